@@ -0,0 +1,217 @@
+use crate::vm::Device;
+use std::time::{Duration, Instant};
+
+/// The base address of the CLINT in the `virt` machine layout
+pub const CLINT_BASE: usize = 0x200_0000;
+
+/// The size of the CLINT region
+pub const CLINT_SIZE: usize = 0x1_0000;
+
+/// The frequency of mtime, the same 10 MHz as the `virt` machine
+pub const TIMEBASE_FREQUENCY: u64 = 10_000_000;
+
+/// The register offsets of the single hart
+const MSIP: usize = 0x0;
+const MTIMECMP: usize = 0x4000;
+const MTIME: usize = 0xbff8;
+
+/// The longest wait for the timer with the host clock, so the other devices
+/// still get polled while the hart is idle
+const MAX_WAIT: Duration = Duration::from_millis(10);
+
+/// What drives mtime
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Clock {
+    /// one tick per executed instruction, for deterministic runs
+    Instructions,
+    /// the host wall clock at `TIMEBASE_FREQUENCY`
+    Host,
+}
+
+/// The core local interruptor, with the software interrupt and the timer of a hart
+#[derive(Debug)]
+pub struct Clint {
+    msip: bool,
+    mtimecmp: u64,
+    clock: Clock,
+    /// the instructions counted, or the offset of mtime from the host clock
+    ticks: u64,
+    start: Instant,
+}
+
+impl Clint {
+    /// A CLINT with mtime at 0, and the timer disabled by the highest mtimecmp
+    pub fn new(clock: Clock) -> Self {
+        Self {
+            msip: false,
+            mtimecmp: u64::MAX,
+            clock,
+            ticks: 0,
+            start: Instant::now(),
+        }
+    }
+
+    /// The ticks of the host clock since the CLINT was created
+    fn host_ticks(&self) -> u64 {
+        let nanos = self.start.elapsed().as_nanos();
+        (nanos * TIMEBASE_FREQUENCY as u128 / 1_000_000_000) as u64
+    }
+
+    pub fn mtime(&self) -> u64 {
+        match self.clock {
+            Clock::Instructions => self.ticks,
+            Clock::Host => self.host_ticks().wrapping_add(self.ticks),
+        }
+    }
+
+    pub fn set_mtime(&mut self, value: u64) {
+        self.ticks = match self.clock {
+            Clock::Instructions => value,
+            Clock::Host => value.wrapping_sub(self.host_ticks()),
+        };
+    }
+
+    pub fn mtimecmp(&self) -> u64 {
+        self.mtimecmp
+    }
+
+    pub fn set_mtimecmp(&mut self, value: u64) {
+        self.mtimecmp = value;
+    }
+
+    /// Count an executed instruction
+    pub fn tick(&mut self) {
+        if self.clock == Clock::Instructions {
+            self.ticks = self.ticks.wrapping_add(1);
+        }
+    }
+
+    /// The machine timer interrupt is pending while mtime >= mtimecmp
+    pub fn timer_pending(&self) -> bool {
+        self.mtime() >= self.mtimecmp
+    }
+
+    /// The machine software interrupt is pending while msip is set
+    pub fn software_pending(&self) -> bool {
+        self.msip
+    }
+
+    /// Let the time pass until the timer interrupt for an idle hart. Counted time
+    /// jumps to mtimecmp, and the host clock sleeps for at most `MAX_WAIT`
+    pub fn wait(&mut self) {
+        if self.timer_pending() || self.mtimecmp == u64::MAX {
+            return;
+        }
+
+        match self.clock {
+            Clock::Instructions => self.ticks = self.mtimecmp,
+            Clock::Host => {
+                let ticks = self.mtimecmp - self.mtime();
+                let nanos = ticks as u128 * 1_000_000_000 / TIMEBASE_FREQUENCY as u128;
+                let wait = Duration::from_nanos(nanos.min(u64::MAX as u128) as u64);
+                std::thread::sleep(wait.min(MAX_WAIT));
+            }
+        }
+    }
+}
+
+/// Read a 64 bit register as a whole, or as one of its 32 bit halves
+fn read_register(register: u64, offset: usize, size: usize) -> Option<u64> {
+    match (offset, size) {
+        (0, 8) => Some(register),
+        (0, 4) => Some(register & 0xffff_ffff),
+        (4, 4) => Some(register >> 32),
+        _ => None,
+    }
+}
+
+/// Write a 64 bit register as a whole, or one of its 32 bit halves
+fn write_register(register: u64, offset: usize, size: usize, value: u64) -> Option<u64> {
+    match (offset, size) {
+        (0, 8) => Some(value),
+        (0, 4) => Some(register & !0xffff_ffff | value & 0xffff_ffff),
+        (4, 4) => Some(register & 0xffff_ffff | value << 32),
+        _ => None,
+    }
+}
+
+impl Device for Clint {
+    fn read(&mut self, offset: usize, size: usize) -> Option<u64> {
+        match offset {
+            MSIP if size == 4 => Some(self.msip.into()),
+            MTIMECMP..=0x4007 => read_register(self.mtimecmp, offset - MTIMECMP, size),
+            MTIME..=0xbfff => read_register(self.mtime(), offset - MTIME, size),
+            _ => None,
+        }
+    }
+
+    fn write(&mut self, offset: usize, size: usize, value: u64) -> Option<()> {
+        match offset {
+            MSIP if size == 4 => self.msip = value & 1 != 0,
+            MTIMECMP..=0x4007 => {
+                self.mtimecmp = write_register(self.mtimecmp, offset - MTIMECMP, size, value)?;
+            }
+            MTIME..=0xbfff => {
+                let mtime = write_register(self.mtime(), offset - MTIME, size, value)?;
+                self.set_mtime(mtime);
+            }
+            _ => return None,
+        }
+
+        Some(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timer() {
+        let mut clint = Clint::new(Clock::Instructions);
+
+        clint.write(MTIMECMP, 4, 3).unwrap();
+        clint.write(MTIMECMP + 4, 4, 0).unwrap();
+        assert_eq!(clint.read(MTIMECMP, 8), Some(3));
+
+        clint.tick();
+        clint.tick();
+        assert!(!clint.timer_pending());
+        clint.tick();
+        assert!(clint.timer_pending());
+        assert_eq!(clint.read(MTIME, 8), Some(3));
+
+        // an idle hart skips to the timer
+        clint.write(MTIMECMP, 8, 0x1_0000_0000).unwrap();
+        clint.wait();
+        assert_eq!(clint.read(MTIME + 4, 4), Some(1));
+        assert!(clint.timer_pending());
+    }
+
+    #[test]
+    fn software() {
+        let mut clint = Clint::new(Clock::Instructions);
+
+        clint.write(MSIP, 4, 0xff).unwrap();
+        assert!(clint.software_pending());
+        assert_eq!(clint.read(MSIP, 4), Some(1));
+
+        clint.write(MSIP, 4, 0).unwrap();
+        assert!(!clint.software_pending());
+
+        assert_eq!(clint.read(MSIP, 8), None);
+        assert_eq!(clint.read(0x100, 4), None);
+    }
+
+    #[test]
+    fn host_clock() {
+        let mut clint = Clint::new(Clock::Host);
+
+        clint.set_mtime(1 << 40);
+        assert!(clint.mtime() >= 1 << 40);
+
+        // the time passes without instructions
+        std::thread::sleep(Duration::from_millis(1));
+        assert!(clint.mtime() >= (1 << 40) + TIMEBASE_FREQUENCY / 1000);
+    }
+}
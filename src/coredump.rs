@@ -0,0 +1,260 @@
+use crate::elf::Endianness;
+use crate::Machine;
+use std::io::{self, Write};
+
+const EHDR_SIZE: u64 = 64;
+const PHDR_SIZE: u64 = 56;
+
+const ET_CORE: u16 = 4;
+const EM_RISCV: u16 = 243;
+const PT_LOAD: u32 = 1;
+const PT_NOTE: u32 = 4;
+const NT_PRSTATUS: u32 = 1;
+
+/// Size of `struct elf_prstatus` on riscv64 linux
+const PRSTATUS_SIZE: usize = 376;
+/// Offset of `pr_reg` in `struct elf_prstatus`
+const PRSTATUS_REG_OFFSET: usize = 112;
+
+/// Segment data is page aligned in the file, like the cores written by linux
+const SEGMENT_ALIGN: u64 = 4096;
+
+/// Writes integers in the endianness of the core file
+struct Writer<W> {
+    inner: W,
+    endianness: Endianness,
+    position: u64,
+}
+
+macro_rules! write_type {
+    ($name:ident, $type:ty) => {
+        fn $name(&mut self, val: $type) -> io::Result<()> {
+            match self.endianness {
+                Endianness::Little => self.bytes(&val.to_le_bytes()),
+                Endianness::Big => self.bytes(&val.to_be_bytes()),
+            }
+        }
+    };
+}
+
+impl<W: Write> Writer<W> {
+    fn bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.position += bytes.len() as u64;
+        self.inner.write_all(bytes)
+    }
+
+    write_type!(u16, u16);
+    write_type!(u32, u32);
+    write_type!(u64, u64);
+
+    /// Write zeros until the position is aligned
+    fn pad(&mut self, align: u64) -> io::Result<()> {
+        let padding = self.position.next_multiple_of(align) - self.position;
+        self.bytes(&vec![0; padding as usize])
+    }
+
+    fn program_header(
+        &mut self,
+        kind: u32,
+        flags: u32,
+        offset: u64,
+        address: u64,
+        size: u64,
+        align: u64,
+    ) -> io::Result<()> {
+        self.u32(kind)?;
+        self.u32(flags)?;
+        self.u64(offset)?;
+        self.u64(address)?;
+        // physical address
+        self.u64(0)?;
+        // size in the file and in memory
+        self.u64(size)?;
+        self.u64(size)?;
+        self.u64(align)
+    }
+}
+
+/// Build the `struct elf_prstatus` with the registers of the machine
+fn prstatus(machine: &Machine, signal: u16) -> Vec<u8> {
+    let mut desc = Vec::with_capacity(PRSTATUS_SIZE);
+    let mut w = Writer {
+        inner: &mut desc,
+        endianness: machine.endianness(),
+        position: 0,
+    };
+
+    // writing to a vector can't fail
+    let mut write = || -> io::Result<()> {
+        // pr_info.si_signo, si_code, si_errno
+        w.u32(signal as u32)?;
+        w.u32(0)?;
+        w.u32(0)?;
+
+        // pr_cursig
+        w.u16(signal)?;
+
+        // the signal masks, ids and times are not tracked
+        w.pad(PRSTATUS_REG_OFFSET as u64)?;
+
+        // pr_reg is the pc followed by x1 to x31, like `struct user_regs_struct`
+        w.u64(machine.pc())?;
+        for &register in &machine.registers()[1..] {
+            w.u64(register)?;
+        }
+
+        // pr_fpvalid and padding
+        w.pad(PRSTATUS_SIZE as u64)
+    };
+    write().unwrap();
+
+    desc
+}
+
+/// Write an ELF core file of the machine, that gdb can open along with the guest
+/// binary. Every segment of the memory becomes a PT_LOAD, and the registers are
+/// saved in a NT_PRSTATUS note as if the process was killed by `signal`
+pub fn write_core<W: Write>(writer: W, machine: &Machine, signal: u16) -> io::Result<()> {
+    let segments = machine.memory().segments();
+
+    let mut w = Writer {
+        inner: writer,
+        endianness: machine.endianness(),
+        position: 0,
+    };
+
+    let name = b"CORE\0";
+    let desc = prstatus(machine, signal);
+    let note_size = (12 + name.len().next_multiple_of(4) + desc.len()) as u64;
+
+    let program_headers = 1 + segments.len() as u64;
+    let note_offset = EHDR_SIZE + program_headers * PHDR_SIZE;
+
+    // ELF header
+    w.bytes(b"\x7fELF")?;
+    w.bytes(&[
+        // 64 bit
+        2,
+        match w.endianness {
+            Endianness::Little => 1,
+            Endianness::Big => 2,
+        },
+        // version
+        1,
+        // System V ABI and version
+        0,
+        0,
+    ])?;
+    w.bytes(&[0; 7])?;
+    w.u16(ET_CORE)?;
+    w.u16(EM_RISCV)?;
+    w.u32(1)?;
+    // entry
+    w.u64(0)?;
+    // program header offset
+    w.u64(EHDR_SIZE)?;
+    // no section headers
+    w.u64(0)?;
+    // flags
+    w.u32(0)?;
+    w.u16(EHDR_SIZE as u16)?;
+    w.u16(PHDR_SIZE as u16)?;
+    w.u16(program_headers as u16)?;
+    w.u16(0)?;
+    w.u16(0)?;
+    w.u16(0)?;
+
+    // program headers
+    w.program_header(PT_NOTE, 0, note_offset, 0, note_size, 4)?;
+
+    let mut offset = (note_offset + note_size).next_multiple_of(SEGMENT_ALIGN);
+    for segment in &segments {
+        w.program_header(
+            PT_LOAD,
+            segment.protection.into(),
+            offset,
+            segment.start as u64,
+            segment.data.len() as u64,
+            SEGMENT_ALIGN,
+        )?;
+
+        offset = (offset + segment.data.len() as u64).next_multiple_of(SEGMENT_ALIGN);
+    }
+
+    // notes
+    w.u32(name.len() as u32)?;
+    w.u32(desc.len() as u32)?;
+    w.u32(NT_PRSTATUS)?;
+    w.bytes(name)?;
+    w.pad(4)?;
+    w.bytes(&desc)?;
+
+    // segment data
+    for segment in &segments {
+        w.pad(SEGMENT_ALIGN)?;
+        w.bytes(&segment.data)?;
+    }
+
+    w.inner.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::elf::{Elf, Segment, Usize};
+
+    fn read_u16(data: &[u8], offset: usize) -> u16 {
+        u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+    }
+
+    fn read_u64(data: &[u8], offset: usize) -> u64 {
+        u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+    }
+
+    #[test]
+    fn core() {
+        let elf = Elf {
+            endianness: Endianness::Little,
+            entry: Usize::U64(0x1000),
+            segments: vec![Segment {
+                start: 0x1000,
+                protection: 0b101.into(),
+                data: vec![0x13, 0, 0, 0],
+            }],
+            stack_protection: 0b110.into(),
+            relro: None,
+            notes: Vec::new(),
+            attributes: None,
+            line_table: None,
+            symbols: Vec::new(),
+        };
+        let machine = Machine::new(elf).unwrap();
+
+        let mut core = Vec::new();
+        write_core(&mut core, &machine, 11).unwrap();
+
+        assert_eq!(&core[0..4], b"\x7fELF");
+        assert_eq!(read_u16(&core, 16), ET_CORE);
+        assert_eq!(read_u16(&core, 18), EM_RISCV);
+
+        // a note and the code and stack segments
+        assert_eq!(read_u16(&core, 56), 3);
+
+        let note = (EHDR_SIZE + 3 * PHDR_SIZE) as usize;
+        assert_eq!(&core[note + 12..note + 17], b"CORE\0");
+
+        let prstatus = note + 20;
+        assert_eq!(read_u16(&core, prstatus), 11);
+        assert_eq!(read_u64(&core, prstatus + PRSTATUS_REG_OFFSET), 0x1000);
+        assert_eq!(
+            read_u64(&core, prstatus + PRSTATUS_REG_OFFSET + 2 * 8),
+            machine.registers()[2]
+        );
+
+        // the first load segment is the code
+        let phdr = (EHDR_SIZE + PHDR_SIZE) as usize;
+        let offset = read_u64(&core, phdr + 8) as usize;
+        assert_eq!(read_u64(&core, phdr + 16), 0x1000);
+        assert_eq!(&core[offset..offset + 4], &[0x13, 0, 0, 0]);
+    }
+}
@@ -0,0 +1,303 @@
+use crate::mmu::Mode;
+use crate::trap::Privilege;
+
+pub const SSTATUS: u16 = 0x100;
+pub const SIE: u16 = 0x104;
+pub const STVEC: u16 = 0x105;
+pub const SSCRATCH: u16 = 0x140;
+pub const SEPC: u16 = 0x141;
+pub const SCAUSE: u16 = 0x142;
+pub const STVAL: u16 = 0x143;
+pub const SIP: u16 = 0x144;
+pub const SATP: u16 = 0x180;
+pub const MSTATUS: u16 = 0x300;
+pub const MISA: u16 = 0x301;
+pub const MEDELEG: u16 = 0x302;
+pub const MIDELEG: u16 = 0x303;
+pub const MIE: u16 = 0x304;
+pub const MTVEC: u16 = 0x305;
+pub const MSCRATCH: u16 = 0x340;
+pub const MEPC: u16 = 0x341;
+pub const MCAUSE: u16 = 0x342;
+pub const MTVAL: u16 = 0x343;
+pub const MIP: u16 = 0x344;
+pub const TIME: u16 = 0xc01;
+pub const MVENDORID: u16 = 0xf11;
+pub const MARCHID: u16 = 0xf12;
+pub const MIMPID: u16 = 0xf13;
+pub const MHARTID: u16 = 0xf14;
+
+pub const MSTATUS_SIE: u64 = 1 << 1;
+pub const MSTATUS_MIE: u64 = 1 << 3;
+pub const MSTATUS_SPIE: u64 = 1 << 5;
+pub const MSTATUS_MPIE: u64 = 1 << 7;
+pub const MSTATUS_SPP: u64 = 1 << 8;
+pub const MSTATUS_MPP_SHIFT: u64 = 11;
+pub const MSTATUS_MPP: u64 = 0b11 << MSTATUS_MPP_SHIFT;
+pub const MSTATUS_MPRV: u64 = 1 << 17;
+pub const MSTATUS_SUM: u64 = 1 << 18;
+pub const MSTATUS_MXR: u64 = 1 << 19;
+pub const MSTATUS_TVM: u64 = 1 << 20;
+pub const MSTATUS_TW: u64 = 1 << 21;
+pub const MSTATUS_TSR: u64 = 1 << 22;
+
+/// The fields of mstatus that can be written. UBE, SBE and MBE are read-only
+/// zero, the endianness of the data is a setting of the machine
+const MSTATUS_MASK: u64 = MSTATUS_SIE
+    | MSTATUS_MIE
+    | MSTATUS_SPIE
+    | MSTATUS_MPIE
+    | MSTATUS_SPP
+    | MSTATUS_MPP
+    | MSTATUS_MPRV
+    | MSTATUS_SUM
+    | MSTATUS_MXR
+    | MSTATUS_TVM
+    | MSTATUS_TW
+    | MSTATUS_TSR;
+
+/// The fields of mstatus that are visible in sstatus
+const SSTATUS_MASK: u64 = MSTATUS_SIE | MSTATUS_SPIE | MSTATUS_SPP | MSTATUS_SUM | MSTATUS_MXR;
+
+/// The interrupt bits of mie and mip
+pub const SSI: u64 = 1 << 1;
+pub const MSI: u64 = 1 << 3;
+pub const STI: u64 = 1 << 5;
+pub const MTI: u64 = 1 << 7;
+pub const SEI: u64 = 1 << 9;
+pub const MEI: u64 = 1 << 11;
+
+const SUPERVISOR_INTERRUPTS: u64 = SSI | STI | SEI;
+
+/// The interrupts from the highest to the lowest priority, with their cause codes
+pub const INTERRUPT_PRIORITY: [(u64, u64); 6] =
+    [(MEI, 11), (MSI, 3), (MTI, 7), (SEI, 9), (SSI, 1), (STI, 5)];
+const ALL_INTERRUPTS: u64 = SUPERVISOR_INTERRUPTS | MSI | MTI | MEI;
+
+/// The exceptions that can be delegated, all but the ECALL from machine mode
+const MEDELEG_MASK: u64 = 0xffff & !(1 << 11);
+
+/// RV64 with the I, S and U extensions
+const MISA_VALUE: u64 = 2 << 62 | 1 << (b'i' - b'a') | 1 << (b's' - b'a') | 1 << (b'u' - b'a');
+
+/// The control and status registers
+#[derive(Debug, Default, Clone)]
+pub struct Csrs {
+    pub mstatus: u64,
+    pub satp: u64,
+    pub mtvec: u64,
+    pub mepc: u64,
+    pub mcause: u64,
+    pub mtval: u64,
+    pub mscratch: u64,
+    pub medeleg: u64,
+    pub mideleg: u64,
+    pub mie: u64,
+    /// the pending interrupts, the machine level bits are set by devices
+    pub mip: u64,
+    pub stvec: u64,
+    pub sepc: u64,
+    pub scause: u64,
+    pub stval: u64,
+    pub sscratch: u64,
+}
+
+impl Csrs {
+    /// The privilege of loads and stores, which is mstatus.MPP when mstatus.MPRV
+    /// is set in machine mode
+    pub fn data_privilege(&self, privilege: Privilege) -> Privilege {
+        if privilege == Privilege::Machine && self.mstatus & MSTATUS_MPRV != 0 {
+            Privilege::from_bits((self.mstatus & MSTATUS_MPP) >> MSTATUS_MPP_SHIFT)
+                .unwrap_or(Privilege::User)
+        } else {
+            privilege
+        }
+    }
+
+    /// Check if a CSR can be accessed with the privilege, the lowest privilege
+    /// that can access it is encoded in the address
+    fn accessible(csr: u16, privilege: Privilege) -> bool {
+        (csr >> 8) & 0b11 <= privilege as u16
+    }
+
+    /// Read a CSR, `None` if it doesn't exist or can't be accessed
+    pub fn read(&self, csr: u16, privilege: Privilege) -> Option<u64> {
+        if !Self::accessible(csr, privilege) {
+            return None;
+        }
+
+        let value = match csr {
+            SSTATUS => self.mstatus & SSTATUS_MASK,
+            SIE => self.mie & self.mideleg,
+            STVEC => self.stvec,
+            SSCRATCH => self.sscratch,
+            SEPC => self.sepc,
+            SCAUSE => self.scause,
+            STVAL => self.stval,
+            SIP => self.mip & self.mideleg,
+            SATP if privilege == Privilege::Supervisor && self.mstatus & MSTATUS_TVM != 0 => {
+                return None
+            }
+            SATP => self.satp,
+            MSTATUS => self.mstatus,
+            MISA => MISA_VALUE,
+            MEDELEG => self.medeleg,
+            MIDELEG => self.mideleg,
+            MIE => self.mie,
+            MTVEC => self.mtvec,
+            MSCRATCH => self.mscratch,
+            MEPC => self.mepc,
+            MCAUSE => self.mcause,
+            MTVAL => self.mtval,
+            MIP => self.mip,
+            MVENDORID | MARCHID | MIMPID | MHARTID => 0,
+            _ => return None,
+        };
+
+        Some(value)
+    }
+
+    /// Write a CSR, `None` if it doesn't exist, is read-only or can't be accessed.
+    /// Fields that can't hold the value keep a legal one
+    pub fn write(&mut self, csr: u16, value: u64, privilege: Privilege) -> Option<()> {
+        // the two top bits are 0b11 for read-only registers
+        if !Self::accessible(csr, privilege) || csr >> 10 == 0b11 {
+            return None;
+        }
+
+        // the modes of the trap vectors are direct and vectored
+        let tvec = |old: u64| if value & 0b11 < 2 { value } else { old };
+        // instructions are aligned to 4 bytes
+        let epc = value & !0b11;
+
+        match csr {
+            SSTATUS => {
+                self.mstatus = self.mstatus & !SSTATUS_MASK | value & SSTATUS_MASK;
+            }
+            SIE => self.mie = self.mie & !self.mideleg | value & self.mideleg,
+            STVEC => self.stvec = tvec(self.stvec),
+            SSCRATCH => self.sscratch = value,
+            SEPC => self.sepc = epc,
+            SCAUSE => self.scause = value,
+            STVAL => self.stval = value,
+            SIP => {
+                // only the software interrupt can be raised or cleared by supervisor mode
+                let mask = self.mideleg & SSI;
+                self.mip = self.mip & !mask | value & mask;
+            }
+            SATP if privilege == Privilege::Supervisor && self.mstatus & MSTATUS_TVM != 0 => {
+                return None
+            }
+            SATP => {
+                // writes with an unsupported mode have no effect
+                if Mode::from_satp(value).is_some() {
+                    self.satp = value;
+                }
+            }
+            MSTATUS => {
+                let mut value = value & MSTATUS_MASK;
+
+                // the reserved privilege 2 is not a legal MPP
+                if Privilege::from_bits((value & MSTATUS_MPP) >> MSTATUS_MPP_SHIFT).is_none() {
+                    value &= !MSTATUS_MPP;
+                }
+
+                self.mstatus = self.mstatus & !MSTATUS_MASK | value;
+            }
+            // misa can't be changed, the writes are ignored
+            MISA => {}
+            MEDELEG => self.medeleg = value & MEDELEG_MASK,
+            MIDELEG => self.mideleg = value & SUPERVISOR_INTERRUPTS,
+            MIE => self.mie = value & ALL_INTERRUPTS,
+            MTVEC => self.mtvec = tvec(self.mtvec),
+            MSCRATCH => self.mscratch = value,
+            MEPC => self.mepc = epc,
+            MCAUSE => self.mcause = value,
+            MTVAL => self.mtval = value,
+            MIP => {
+                // the machine level interrupts are only raised by devices
+                self.mip = self.mip & !SUPERVISOR_INTERRUPTS | value & SUPERVISOR_INTERRUPTS;
+            }
+            _ => return None,
+        }
+
+        Some(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sstatus() {
+        let mut csrs = Csrs::default();
+
+        csrs.write(MSTATUS, MSTATUS_MIE | MSTATUS_SUM, Privilege::Machine)
+            .unwrap();
+
+        assert_eq!(csrs.read(SSTATUS, Privilege::Supervisor), Some(MSTATUS_SUM));
+
+        csrs.write(SSTATUS, MSTATUS_MXR | MSTATUS_MIE, Privilege::Supervisor)
+            .unwrap();
+        assert_eq!(csrs.mstatus, MSTATUS_MIE | MSTATUS_MXR);
+    }
+
+    #[test]
+    fn privilege() {
+        let mut csrs = Csrs::default();
+
+        assert_eq!(csrs.read(SATP, Privilege::User), None);
+        assert_eq!(csrs.read(MSTATUS, Privilege::Supervisor), None);
+        assert!(csrs.write(SATP, 8 << 60, Privilege::Supervisor).is_some());
+        assert!(csrs.write(MHARTID, 1, Privilege::Machine).is_none());
+
+        // mstatus.TVM traps satp accesses in supervisor mode
+        csrs.mstatus |= MSTATUS_TVM;
+        assert_eq!(csrs.read(SATP, Privilege::Supervisor), None);
+        assert_eq!(csrs.read(SATP, Privilege::Machine), Some(8 << 60));
+    }
+
+    #[test]
+    fn warl() {
+        let mut csrs = Csrs::default();
+
+        csrs.write(SATP, 8 << 60 | 5, Privilege::Machine).unwrap();
+        csrs.write(SATP, 5 << 60, Privilege::Machine).unwrap();
+        assert_eq!(csrs.satp, 8 << 60 | 5);
+
+        csrs.write(MSTATUS, 2 << MSTATUS_MPP_SHIFT, Privilege::Machine)
+            .unwrap();
+        assert_eq!(csrs.mstatus & MSTATUS_MPP, 0);
+
+        csrs.write(MTVEC, 0x8000_0001, Privilege::Machine).unwrap();
+        csrs.write(MTVEC, 0x9000_0002, Privilege::Machine).unwrap();
+        assert_eq!(csrs.mtvec, 0x8000_0001);
+
+        csrs.write(MEPC, 0x8000_0003, Privilege::Machine).unwrap();
+        assert_eq!(csrs.mepc, 0x8000_0000);
+
+        csrs.write(MEDELEG, u64::MAX, Privilege::Machine).unwrap();
+        assert_eq!(csrs.medeleg & 1 << 11, 0);
+    }
+
+    #[test]
+    fn delegated_interrupts() {
+        let mut csrs = Csrs::default();
+
+        csrs.write(MIDELEG, u64::MAX, Privilege::Machine).unwrap();
+        assert_eq!(csrs.mideleg, SSI | STI | SEI);
+
+        csrs.write(MIE, MTI | STI, Privilege::Machine).unwrap();
+        assert_eq!(csrs.read(SIE, Privilege::Supervisor), Some(STI));
+
+        csrs.write(SIE, SEI, Privilege::Supervisor).unwrap();
+        assert_eq!(csrs.mie, MTI | SEI);
+
+        // the machine timer is pending, but not visible to supervisor mode
+        csrs.mip = MTI;
+        csrs.write(SIP, SSI, Privilege::Supervisor).unwrap();
+        assert_eq!(csrs.mip, MTI | SSI);
+        assert_eq!(csrs.read(SIP, Privilege::Supervisor), Some(SSI));
+    }
+}
@@ -0,0 +1,565 @@
+use crate::elf::{read_byte, read_cstr, read_sleb128, read_type, read_uleb128, Endianness, Error};
+use std::fmt::Display;
+
+/// The contents of the debug sections needed to build the line table
+#[derive(Debug, Default)]
+pub(crate) struct DebugSections {
+    pub line: Vec<u8>,
+    pub line_str: Vec<u8>,
+    pub str: Vec<u8>,
+}
+
+/// A source location of an address
+#[derive(Debug, PartialEq)]
+pub struct Location<'a> {
+    pub file: &'a str,
+    pub line: u32,
+    /// 0 if the column is unknown
+    pub column: u32,
+}
+
+impl Display for Location<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.file, self.line)?;
+
+        if self.column != 0 {
+            write!(f, ":{}", self.column)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Row {
+    address: u64,
+    /// index into `LineTable::files`
+    file: usize,
+    line: u32,
+    column: u32,
+}
+
+/// A contiguous range of addresses, from the rows of a line program ending with
+/// DW_LNE_end_sequence
+#[derive(Debug)]
+struct Sequence {
+    start: u64,
+    end: u64,
+    rows: Vec<Row>,
+}
+
+/// Maps addresses to source locations, built from the `.debug_line` section
+#[derive(Debug, Default)]
+pub struct LineTable {
+    files: Vec<String>,
+    sequences: Vec<Sequence>,
+}
+
+// standard opcodes
+const DW_LNS_COPY: u8 = 1;
+const DW_LNS_ADVANCE_PC: u8 = 2;
+const DW_LNS_ADVANCE_LINE: u8 = 3;
+const DW_LNS_SET_FILE: u8 = 4;
+const DW_LNS_SET_COLUMN: u8 = 5;
+const DW_LNS_NEGATE_STMT: u8 = 6;
+const DW_LNS_SET_BASIC_BLOCK: u8 = 7;
+const DW_LNS_CONST_ADD_PC: u8 = 8;
+const DW_LNS_FIXED_ADVANCE_PC: u8 = 9;
+
+// extended opcodes
+const DW_LNE_END_SEQUENCE: u8 = 1;
+const DW_LNE_SET_ADDRESS: u8 = 2;
+const DW_LNE_DEFINE_FILE: u8 = 3;
+
+// entry formats of DWARF 5
+const DW_LNCT_PATH: u64 = 1;
+const DW_LNCT_DIRECTORY_INDEX: u64 = 2;
+
+const DW_FORM_BLOCK: u64 = 0x09;
+const DW_FORM_BLOCK1: u64 = 0x0a;
+const DW_FORM_DATA1: u64 = 0x0b;
+const DW_FORM_DATA2: u64 = 0x05;
+const DW_FORM_DATA4: u64 = 0x06;
+const DW_FORM_DATA8: u64 = 0x07;
+const DW_FORM_DATA16: u64 = 0x1e;
+const DW_FORM_STRING: u64 = 0x08;
+const DW_FORM_STRP: u64 = 0x0e;
+const DW_FORM_UDATA: u64 = 0x0f;
+const DW_FORM_LINE_STRP: u64 = 0x1f;
+
+/// A value of an attribute in a directory or file entry
+enum Value {
+    String(String),
+    Number(u64),
+    Other,
+}
+
+/// Read an offset, 4 bytes in 32 bit DWARF and 8 bytes in 64 bit DWARF
+fn read_offset(data: &mut &[u8], offset_size: usize, endianness: Endianness) -> Result<u64, Error> {
+    if offset_size == 8 {
+        read_type!(data, u64, endianness, "dwarf offset")
+    } else {
+        read_type!(data, u32, endianness, "dwarf offset").map(u64::from)
+    }
+}
+
+/// Read a string from a string section at offset
+fn read_str_at(section: &[u8], offset: u64) -> Result<String, Error> {
+    let mut data = section
+        .get(offset as usize..)
+        .ok_or(Error::Malformed("dwarf string offset"))?;
+
+    read_cstr(&mut data, "dwarf string").map(str::to_owned)
+}
+
+fn skip(data: &mut &[u8], len: usize) -> Result<(), Error> {
+    *data = data.get(len..).ok_or(Error::Malformed("dwarf form"))?;
+    Ok(())
+}
+
+fn read_form(
+    data: &mut &[u8],
+    form: u64,
+    offset_size: usize,
+    sections: &DebugSections,
+    endianness: Endianness,
+) -> Result<Value, Error> {
+    Ok(match form {
+        DW_FORM_STRING => Value::String(read_cstr(data, "dwarf string")?.to_owned()),
+        DW_FORM_LINE_STRP => Value::String(read_str_at(
+            &sections.line_str,
+            read_offset(data, offset_size, endianness)?,
+        )?),
+        DW_FORM_STRP => Value::String(read_str_at(
+            &sections.str,
+            read_offset(data, offset_size, endianness)?,
+        )?),
+        DW_FORM_UDATA => Value::Number(read_uleb128(data, "dwarf udata")?),
+        DW_FORM_DATA1 => Value::Number(read_byte(data, "dwarf data1")?.into()),
+        DW_FORM_DATA2 => Value::Number(read_type!(data, u16, endianness, "dwarf data2")?.into()),
+        DW_FORM_DATA4 => Value::Number(read_type!(data, u32, endianness, "dwarf data4")?.into()),
+        DW_FORM_DATA8 => Value::Number(read_type!(data, u64, endianness, "dwarf data8")?),
+        DW_FORM_DATA16 => {
+            skip(data, 16)?;
+            Value::Other
+        }
+        DW_FORM_BLOCK => {
+            let len = read_uleb128(data, "dwarf block length")?;
+            skip(data, len as usize)?;
+            Value::Other
+        }
+        DW_FORM_BLOCK1 => {
+            let len = read_byte(data, "dwarf block length")?;
+            skip(data, len as usize)?;
+            Value::Other
+        }
+        _ => return Err(Error::Malformed("unsupported dwarf form")),
+    })
+}
+
+/// Read the DWARF 5 directory or file name entries as (path, directory index) pairs
+fn read_entries(
+    data: &mut &[u8],
+    offset_size: usize,
+    sections: &DebugSections,
+    endianness: Endianness,
+) -> Result<Vec<(String, usize)>, Error> {
+    let format_count = read_byte(data, "entry format count")?;
+    let mut formats = Vec::with_capacity(format_count as usize);
+    for _ in 0..format_count {
+        let content = read_uleb128(data, "entry content type")?;
+        let form = read_uleb128(data, "entry form")?;
+        formats.push((content, form));
+    }
+
+    let count = read_uleb128(data, "entry count")?;
+    let mut entries = Vec::new();
+
+    for _ in 0..count {
+        let mut path = String::new();
+        let mut directory = 0;
+
+        for &(content, form) in &formats {
+            match (
+                content,
+                read_form(data, form, offset_size, sections, endianness)?,
+            ) {
+                (DW_LNCT_PATH, Value::String(value)) => path = value,
+                (DW_LNCT_DIRECTORY_INDEX, Value::Number(value)) => directory = value as usize,
+                _ => {}
+            }
+        }
+
+        entries.push((path, directory));
+    }
+
+    Ok(entries)
+}
+
+fn join(directory: Option<&String>, name: String) -> String {
+    match directory {
+        Some(directory) if !directory.is_empty() && !name.starts_with('/') => {
+            format!("{}/{}", directory, name)
+        }
+        _ => name,
+    }
+}
+
+impl LineTable {
+    pub(crate) fn parse(sections: &DebugSections, endianness: Endianness) -> Result<Self, Error> {
+        let mut table = Self::default();
+        let mut rest = sections.line.as_slice();
+
+        while !rest.is_empty() {
+            // 0xffffffff marks the 64 bit DWARF format
+            let (len, offset_size) = match read_type!(&mut rest, u32, endianness, "unit length")? {
+                0xffff_ffff => (read_type!(&mut rest, u64, endianness, "unit length")?, 8),
+                len => (len as u64, 4),
+            };
+
+            let len = len as usize;
+            if len > rest.len() {
+                return Err(Error::Malformed("line table unit length"));
+            }
+
+            table.parse_unit(&rest[..len], offset_size, sections, endianness)?;
+            rest = &rest[len..];
+        }
+
+        table.sequences.sort_by_key(|sequence| sequence.start);
+
+        Ok(table)
+    }
+
+    fn parse_unit(
+        &mut self,
+        mut unit: &[u8],
+        offset_size: usize,
+        sections: &DebugSections,
+        endianness: Endianness,
+    ) -> Result<(), Error> {
+        let version = read_type!(&mut unit, u16, endianness, "line table version")?;
+        if !(2..=5).contains(&version) {
+            return Err(Error::Malformed("line table version"));
+        }
+
+        if version >= 5 {
+            let _ = read_byte(&mut unit, "address size")?;
+            let _ = read_byte(&mut unit, "segment selector size")?;
+        }
+
+        let header_length = read_offset(&mut unit, offset_size, endianness)? as usize;
+        let mut program = unit
+            .get(header_length..)
+            .ok_or(Error::Malformed("line table header length"))?;
+
+        let minimum_instruction_length = read_byte(&mut unit, "minimum instruction length")?;
+        if version >= 4 {
+            let _ = read_byte(&mut unit, "maximum operations per instruction")?;
+        }
+        let _ = read_byte(&mut unit, "default is_stmt")?;
+        let line_base = read_byte(&mut unit, "line base")? as i8;
+        let line_range = read_byte(&mut unit, "line range")?;
+        let opcode_base = read_byte(&mut unit, "opcode base")?;
+
+        if line_range == 0 {
+            return Err(Error::Malformed("line range"));
+        }
+
+        let mut standard_opcode_lengths = Vec::new();
+        for _ in 1..opcode_base {
+            standard_opcode_lengths.push(read_byte(&mut unit, "standard opcode length")?);
+        }
+
+        // the file indices of the program are relative to this
+        let first_file = self.files.len();
+
+        if version >= 5 {
+            // both directories and files are indexed from 0
+            let directories = read_entries(&mut unit, offset_size, sections, endianness)?;
+            let directories: Vec<_> = directories.into_iter().map(|(path, _)| path).collect();
+
+            for (name, directory) in read_entries(&mut unit, offset_size, sections, endianness)? {
+                self.files.push(join(directories.get(directory), name));
+            }
+        } else {
+            // directories and files are indexed from 1, directory 0 is the
+            // compilation directory which is not part of the line table
+            let mut directories = vec![String::new()];
+            loop {
+                let directory = read_cstr(&mut unit, "include directory")?;
+                if directory.is_empty() {
+                    break;
+                }
+                directories.push(directory.to_owned());
+            }
+
+            // placeholder for file 0
+            self.files.push(String::from("??"));
+
+            loop {
+                let name = read_cstr(&mut unit, "file name")?;
+                if name.is_empty() {
+                    break;
+                }
+                let directory = read_uleb128(&mut unit, "file directory")? as usize;
+                let _ = read_uleb128(&mut unit, "file modification time")?;
+                let _ = read_uleb128(&mut unit, "file length")?;
+
+                self.files
+                    .push(join(directories.get(directory), name.to_owned()));
+            }
+        }
+
+        // the state machine registers
+        let mut address = 0u64;
+        let mut file = 1usize;
+        let mut line = 1i64;
+        let mut column = 0u32;
+        let mut rows: Vec<Row> = Vec::new();
+
+        let advance =
+            |operation_advance: u64| operation_advance * minimum_instruction_length as u64;
+
+        while !program.is_empty() {
+            let opcode = read_byte(&mut program, "line program opcode")?;
+            let mut emit = false;
+
+            if opcode >= opcode_base {
+                // special opcode
+                let adjusted = opcode - opcode_base;
+                address = address.wrapping_add(advance((adjusted / line_range) as u64));
+                line += line_base as i64 + (adjusted % line_range) as i64;
+                emit = true;
+            } else if opcode == 0 {
+                // extended opcode
+                let len = read_uleb128(&mut program, "extended opcode length")? as usize;
+                if len == 0 || len > program.len() {
+                    return Err(Error::Malformed("extended opcode length"));
+                }
+                let (mut arguments, next) = program.split_at(len);
+                program = next;
+
+                match read_byte(&mut arguments, "extended opcode")? {
+                    DW_LNE_END_SEQUENCE => {
+                        if let Some(first) = rows.first() {
+                            self.sequences.push(Sequence {
+                                start: first.address,
+                                end: address,
+                                rows: std::mem::take(&mut rows),
+                            });
+                        }
+
+                        address = 0;
+                        file = 1;
+                        line = 1;
+                        column = 0;
+                    }
+                    DW_LNE_SET_ADDRESS => {
+                        address = match arguments.len() {
+                            4 => read_type!(&mut arguments, u32, endianness, "address")?.into(),
+                            8 => read_type!(&mut arguments, u64, endianness, "address")?,
+                            _ => return Err(Error::Malformed("address size")),
+                        };
+                    }
+                    DW_LNE_DEFINE_FILE => {
+                        let name = read_cstr(&mut arguments, "file name")?;
+                        self.files.push(name.to_owned());
+                    }
+                    _ => {}
+                }
+            } else {
+                match opcode {
+                    DW_LNS_COPY => emit = true,
+                    DW_LNS_ADVANCE_PC => {
+                        let operation_advance = read_uleb128(&mut program, "advance pc")?;
+                        address = address.wrapping_add(advance(operation_advance));
+                    }
+                    DW_LNS_ADVANCE_LINE => line += read_sleb128(&mut program, "advance line")?,
+                    DW_LNS_SET_FILE => file = read_uleb128(&mut program, "set file")? as usize,
+                    DW_LNS_SET_COLUMN => column = read_uleb128(&mut program, "set column")? as u32,
+                    DW_LNS_NEGATE_STMT | DW_LNS_SET_BASIC_BLOCK => {}
+                    DW_LNS_CONST_ADD_PC => {
+                        let adjusted = 255 - opcode_base;
+                        address = address.wrapping_add(advance((adjusted / line_range) as u64));
+                    }
+                    DW_LNS_FIXED_ADVANCE_PC => {
+                        let delta = read_type!(&mut program, u16, endianness, "fixed advance pc")?;
+                        address = address.wrapping_add(delta as u64);
+                    }
+                    _ => {
+                        // skip the uleb128 arguments of opcodes that don't matter here
+                        for _ in 0..standard_opcode_lengths[opcode as usize - 1] {
+                            let _ = read_uleb128(&mut program, "standard opcode argument")?;
+                        }
+                    }
+                }
+            }
+
+            if emit {
+                rows.push(Row {
+                    address,
+                    file: first_file + file,
+                    line: line as u32,
+                    column,
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Get the source location of an address
+    pub fn lookup(&self, address: u64) -> Option<Location<'_>> {
+        let i = self
+            .sequences
+            .partition_point(|sequence| sequence.start <= address);
+        let sequence = self.sequences[..i]
+            .iter()
+            .rev()
+            .find(|sequence| address < sequence.end)?;
+
+        let i = sequence.rows.partition_point(|row| row.address <= address);
+        let row = sequence.rows[i.checked_sub(1)?];
+
+        Some(Location {
+            file: self.files.get(row.file).map_or("??", String::as_str),
+            line: row.line,
+            column: row.column,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STANDARD_OPCODE_LENGTHS: [u8; 12] = [0, 1, 1, 1, 1, 0, 0, 0, 1, 0, 0, 1];
+
+    /// Wrap a unit in a 32 bit DWARF length
+    fn unit(version: &[u8], header: Vec<u8>, program: &[u8]) -> Vec<u8> {
+        let mut unit = version.to_vec();
+        unit.extend((header.len() as u32).to_le_bytes());
+        unit.extend(header);
+        unit.extend(program);
+
+        let mut data = (unit.len() as u32).to_le_bytes().to_vec();
+        data.extend(unit);
+        data
+    }
+
+    /// Build a DWARF 4 line table with src/a.c and /abs/b.c
+    fn line_table_v4(program: &[u8]) -> Vec<u8> {
+        // instruction length, operations per instruction, is_stmt, line base,
+        // line range, opcode base
+        let mut header = vec![1, 1, 1, -5i8 as u8, 14, 13];
+        header.extend(STANDARD_OPCODE_LENGTHS);
+        header.extend(b"src\0\0");
+        header.extend(b"a.c\0\x01\0\0");
+        header.extend(b"/abs/b.c\0\x01\0\0");
+        header.push(0);
+
+        unit(&[4, 0], header, program)
+    }
+
+    #[test]
+    fn version_4() {
+        #[rustfmt::skip]
+        let program = [
+            // set address 0x1000
+            0, 9, DW_LNE_SET_ADDRESS, 0, 0x10, 0, 0, 0, 0, 0, 0,
+            // 0x1000 line 10
+            DW_LNS_ADVANCE_LINE, 9, DW_LNS_COPY,
+            // special opcode: address += 4, line += 1
+            13 + 4 * 14 + 6,
+            // 0x100c file 2 column 7
+            DW_LNS_SET_FILE, 2, DW_LNS_SET_COLUMN, 7, DW_LNS_ADVANCE_PC, 8, DW_LNS_COPY,
+            // end the sequence at 0x1010
+            DW_LNS_ADVANCE_PC, 4, 0, 1, DW_LNE_END_SEQUENCE,
+        ];
+
+        let sections = DebugSections {
+            line: line_table_v4(&program),
+            ..Default::default()
+        };
+        let table = LineTable::parse(&sections, Endianness::Little).unwrap();
+
+        assert_eq!(table.lookup(0xfff), None);
+        assert_eq!(
+            table.lookup(0x1000),
+            Some(Location {
+                file: "src/a.c",
+                line: 10,
+                column: 0,
+            })
+        );
+        assert_eq!(table.lookup(0x1003).unwrap().line, 10);
+        assert_eq!(table.lookup(0x1004).unwrap().line, 11);
+        assert_eq!(
+            table.lookup(0x100c),
+            Some(Location {
+                file: "/abs/b.c",
+                line: 11,
+                column: 7,
+            })
+        );
+        assert_eq!(table.lookup(0x1010), None);
+    }
+
+    #[test]
+    fn version_5() {
+        let line_str = b"/dir\0main.rs\0".to_vec();
+
+        let mut header = vec![1, 1, 1, -5i8 as u8, 14, 13];
+        header.extend(STANDARD_OPCODE_LENGTHS);
+
+        #[rustfmt::skip]
+        header.extend([
+            // directories: the path is a line_strp
+            1, DW_LNCT_PATH as u8, DW_FORM_LINE_STRP as u8,
+            1, 0, 0, 0, 0,
+            // files: the path is a line_strp, the directory index is udata
+            2, DW_LNCT_PATH as u8, DW_FORM_LINE_STRP as u8,
+            DW_LNCT_DIRECTORY_INDEX as u8, DW_FORM_UDATA as u8,
+            1, 5, 0, 0, 0, 0,
+        ]);
+
+        #[rustfmt::skip]
+        let program = [
+            // files are indexed from 0
+            DW_LNS_SET_FILE, 0,
+            // 0x2000 line 3
+            0, 5, DW_LNE_SET_ADDRESS, 0, 0x20, 0, 0, DW_LNS_ADVANCE_LINE, 2, DW_LNS_COPY,
+            // end the sequence at 0x2002
+            DW_LNS_ADVANCE_PC, 2, 0, 1, DW_LNE_END_SEQUENCE,
+        ];
+
+        // version 5, 4 byte addresses, no segment selector
+        let sections = DebugSections {
+            line: unit(&[5, 0, 4, 0], header, &program),
+            line_str,
+            ..Default::default()
+        };
+        let table = LineTable::parse(&sections, Endianness::Little).unwrap();
+
+        let location = table.lookup(0x2001).unwrap();
+        assert_eq!(location.to_string(), "/dir/main.rs:3");
+        assert_eq!(table.lookup(0x2002), None);
+    }
+
+    #[test]
+    fn truncated() {
+        let mut line = line_table_v4(&[]);
+        line[0] += 1;
+
+        let sections = DebugSections {
+            line,
+            ..Default::default()
+        };
+
+        assert!(matches!(
+            LineTable::parse(&sections, Endianness::Little),
+            Err(Error::Malformed(..))
+        ));
+    }
+}
@@ -1,3 +1,4 @@
+use crate::dwarf::{DebugSections, LineTable};
 use std::fmt::Debug;
 use std::fs::File;
 use std::io::{BufReader, Read, Seek, SeekFrom};
@@ -10,6 +11,7 @@ pub enum Error {
     FieldRead(&'static str, std::io::Error),
     Bitness(u8),
     Endianness(u8),
+    Malformed(&'static str),
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -51,7 +53,7 @@ macro_rules! usize_into {
 
 usize_into!(u64, usize);
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Endianness {
     Little,
     Big,
@@ -70,7 +72,7 @@ impl TryFrom<u8> for Endianness {
 }
 
 /// Read a single byte from reader
-fn read_byte<R: Read>(reader: &mut R, field: &'static str) -> Result<u8, Error> {
+pub(crate) fn read_byte<R: Read>(reader: &mut R, field: &'static str) -> Result<u8, Error> {
     let mut tmp = [0u8; 1];
 
     reader
@@ -80,7 +82,7 @@ fn read_byte<R: Read>(reader: &mut R, field: &'static str) -> Result<u8, Error>
 }
 
 /// Read a field of N bytes from a reader
-fn read_bytes<R: Read, const N: usize>(
+pub(crate) fn read_bytes<R: Read, const N: usize>(
     reader: &mut R,
     field: &'static str,
 ) -> Result<[u8; N], Error> {
@@ -95,15 +97,17 @@ fn read_bytes<R: Read, const N: usize>(
 /// Read a type from a reader with a given endianness
 macro_rules! read_type {
     ($reader:expr, $type:ty, $endianness:expr, $field:expr) => {
-        read_bytes::<_, { std::mem::size_of::<$type>() }>($reader, $field).map(|bytes| {
-            match $endianness {
-                Endianness::Little => <$type>::from_le_bytes(bytes),
-                Endianness::Big => <$type>::from_be_bytes(bytes),
-            }
-        })
+        $crate::elf::read_bytes::<_, { std::mem::size_of::<$type>() }>($reader, $field).map(
+            |bytes| match $endianness {
+                $crate::elf::Endianness::Little => <$type>::from_le_bytes(bytes),
+                $crate::elf::Endianness::Big => <$type>::from_be_bytes(bytes),
+            },
+        )
     };
 }
 
+pub(crate) use read_type;
+
 /// Read an appropriate address type for the platform
 fn read_usize<R: Read>(
     reader: &mut R,
@@ -117,6 +121,81 @@ fn read_usize<R: Read>(
     })
 }
 
+/// Read len bytes from offset, then return to the current position in the reader
+fn read_at<R: Read + Seek>(reader: &mut R, offset: u64, len: usize) -> Result<Vec<u8>, Error> {
+    // save current position in file
+    let stream_position = reader.stream_position().map_err(Error::Io)?;
+
+    // seek to the data
+    reader.seek(SeekFrom::Start(offset)).map_err(Error::Io)?;
+
+    // read the data
+    let mut data = vec![0; len];
+    reader.read_exact(&mut data).map_err(Error::Io)?;
+
+    // reset position in file
+    reader
+        .seek(SeekFrom::Start(stream_position))
+        .map_err(Error::Io)?;
+
+    Ok(data)
+}
+
+/// Read an unsigned LEB128 encoded number
+pub(crate) fn read_uleb128(data: &mut &[u8], field: &'static str) -> Result<u64, Error> {
+    let mut result = 0u64;
+    let mut shift = 0;
+
+    loop {
+        let byte = read_byte(data, field)?;
+
+        if shift < 64 {
+            result |= ((byte & 0x7f) as u64) << shift;
+        }
+        shift += 7;
+
+        if byte & 0x80 == 0 {
+            return Ok(result);
+        }
+    }
+}
+
+/// Read a signed LEB128 encoded number
+pub(crate) fn read_sleb128(data: &mut &[u8], field: &'static str) -> Result<i64, Error> {
+    let mut result = 0i64;
+    let mut shift = 0;
+
+    loop {
+        let byte = read_byte(data, field)?;
+
+        if shift < 64 {
+            result |= ((byte & 0x7f) as i64) << shift;
+        }
+        shift += 7;
+
+        if byte & 0x80 == 0 {
+            // sign extend
+            if shift < 64 && byte & 0x40 != 0 {
+                result |= -1 << shift;
+            }
+            return Ok(result);
+        }
+    }
+}
+
+/// Read a null terminated string
+pub(crate) fn read_cstr<'a>(data: &mut &'a [u8], field: &'static str) -> Result<&'a str, Error> {
+    let len = data
+        .iter()
+        .position(|&b| b == 0)
+        .ok_or(Error::Malformed(field))?;
+
+    let string = std::str::from_utf8(&data[..len]).map_err(|_| Error::Malformed(field))?;
+    *data = &data[len + 1..];
+
+    Ok(string)
+}
+
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub struct Protection {
     pub r: bool,
@@ -134,6 +213,12 @@ impl From<u32> for Protection {
     }
 }
 
+impl From<Protection> for u32 {
+    fn from(value: Protection) -> Self {
+        (value.r as u32) << 2 | (value.w as u32) << 1 | value.x as u32
+    }
+}
+
 impl std::ops::BitAnd for Protection {
     type Output = Self;
 
@@ -146,6 +231,18 @@ impl std::ops::BitAnd for Protection {
     }
 }
 
+impl std::ops::BitOr for Protection {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self::Output {
+        Self {
+            r: self.r | rhs.r,
+            w: self.w | rhs.w,
+            x: self.x | rhs.x,
+        }
+    }
+}
+
 pub struct Segment {
     pub start: usize,
     pub protection: Protection,
@@ -162,10 +259,338 @@ impl Debug for Segment {
     }
 }
 
+/// An entry of a PT_NOTE segment or a SHT_NOTE section
+#[derive(Debug, Clone, PartialEq)]
+pub struct Note {
+    pub name: String,
+    pub kind: u32,
+    pub desc: Vec<u8>,
+}
+
+const NT_GNU_ABI_TAG: u32 = 1;
+const NT_GNU_BUILD_ID: u32 = 3;
+
+/// Parse the notes from the contents of a note segment or section
+fn parse_notes(data: &[u8], align: usize, endianness: Endianness) -> Result<Vec<Note>, Error> {
+    // notes are 4 byte aligned, unless the segment asks for 8
+    // the padding is relative to the start of the notes, which is already aligned
+    let align = if align == 8 { 8 } else { 4 };
+    let padded = |offset: usize| ((offset + align - 1) & !(align - 1)).min(data.len());
+
+    let mut notes = Vec::new();
+    let mut offset = 0;
+
+    while offset < data.len() {
+        let mut header = &data[offset..];
+        let name_size = read_type!(&mut header, u32, endianness, "note name size")? as usize;
+        let desc_size = read_type!(&mut header, u32, endianness, "note descriptor size")? as usize;
+        let kind = read_type!(&mut header, u32, endianness, "note type")?;
+
+        let name_start = offset + 12;
+        let name = data
+            .get(name_start..name_start + name_size)
+            .ok_or(Error::Malformed("note name"))?;
+        let name = name.strip_suffix(&[0]).unwrap_or(name);
+        let name = String::from_utf8_lossy(name).into_owned();
+
+        let desc_start = padded(name_start + name_size);
+        let desc = data
+            .get(desc_start..desc_start + desc_size)
+            .ok_or(Error::Malformed("note descriptor"))?
+            .to_vec();
+
+        offset = padded(desc_start + desc_size);
+
+        notes.push(Note { name, kind, desc });
+    }
+
+    Ok(notes)
+}
+
+/// The contents of a NT_GNU_ABI_TAG note
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AbiTag {
+    /// 0 for linux
+    pub os: u32,
+    /// the earliest compatible kernel version
+    pub version: (u32, u32, u32),
+}
+
+/// The contents of the `.riscv.attributes` section that matter for emulation
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RiscvAttributes {
+    /// the ISA string, like `rv64i2p1_m2p0_a2p1_f2p2_d2p2_c2p0`
+    pub arch: Option<String>,
+    pub stack_align: Option<u64>,
+    pub unaligned_access: Option<bool>,
+}
+
+const TAG_FILE: u64 = 1;
+const TAG_RISCV_STACK_ALIGN: u64 = 4;
+const TAG_RISCV_ARCH: u64 = 5;
+const TAG_RISCV_UNALIGNED_ACCESS: u64 = 6;
+
+/// Parse the build attributes section, only the file scoped "riscv" attributes are used
+fn parse_riscv_attributes(data: &[u8], endianness: Endianness) -> Result<RiscvAttributes, Error> {
+    let mut attributes = RiscvAttributes::default();
+
+    let mut rest = data;
+    if read_byte(&mut rest, "attributes version")? != b'A' {
+        return Err(Error::Malformed("attributes version"));
+    }
+
+    while !rest.is_empty() {
+        // the length of a subsection includes the length field
+        let mut subsection = rest;
+        let len = read_type!(
+            &mut subsection,
+            u32,
+            endianness,
+            "attributes subsection length"
+        )?;
+        let len = len as usize;
+        if len < 4 || len > rest.len() {
+            return Err(Error::Malformed("attributes subsection length"));
+        }
+        subsection = &rest[4..len];
+        rest = &rest[len..];
+
+        if read_cstr(&mut subsection, "attributes vendor")? != "riscv" {
+            continue;
+        }
+
+        while !subsection.is_empty() {
+            // the size of a sub-subsection includes the tag and the size field
+            let start = subsection;
+            let tag = read_uleb128(&mut subsection, "attributes tag")?;
+            let size = read_type!(&mut subsection, u32, endianness, "attributes size")? as usize;
+            let header = start.len() - subsection.len();
+            if size < header || size > start.len() {
+                return Err(Error::Malformed("attributes size"));
+            }
+            let mut body = &start[header..size];
+            subsection = &start[size..];
+
+            if tag != TAG_FILE {
+                continue;
+            }
+
+            while !body.is_empty() {
+                // even tags have integer values, odd tags have string values
+                let tag = read_uleb128(&mut body, "attribute tag")?;
+
+                if tag % 2 == 0 {
+                    let value = read_uleb128(&mut body, "attribute value")?;
+
+                    match tag {
+                        TAG_RISCV_STACK_ALIGN => attributes.stack_align = Some(value),
+                        TAG_RISCV_UNALIGNED_ACCESS => {
+                            attributes.unaligned_access = Some(value != 0)
+                        }
+                        _ => {}
+                    }
+                } else {
+                    let value = read_cstr(&mut body, "attribute value")?;
+
+                    if tag == TAG_RISCV_ARCH {
+                        attributes.arch = Some(value.to_owned());
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(attributes)
+}
+
+/// A named entry of the symbol table
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbol {
+    pub name: String,
+    pub value: u64,
+    pub size: u64,
+}
+
+/// Parse the entries of a SHT_SYMTAB section, with the names in its string table.
+/// The unnamed entries, like the ones of sections, are skipped
+fn parse_symbols(
+    data: &[u8],
+    names: &[u8],
+    bitness: Bitness,
+    endianness: Endianness,
+) -> Result<Vec<Symbol>, Error> {
+    let entry_size = match bitness {
+        Bitness::Bits32 => 16,
+        Bitness::Bits64 => 24,
+    };
+
+    let mut symbols = Vec::new();
+
+    for mut entry in data.chunks_exact(entry_size) {
+        let name = read_type!(&mut entry, u32, endianness, "symbol name")? as usize;
+
+        // the value and size come after the info, other and section index in 64 bit
+        let (value, size) = match bitness {
+            Bitness::Bits32 => (
+                read_type!(&mut entry, u32, endianness, "symbol value")?.into(),
+                read_type!(&mut entry, u32, endianness, "symbol size")?.into(),
+            ),
+            Bitness::Bits64 => {
+                let _ = read_bytes::<_, 4>(&mut entry, "symbol info")?;
+                (
+                    read_type!(&mut entry, u64, endianness, "symbol value")?,
+                    read_type!(&mut entry, u64, endianness, "symbol size")?,
+                )
+            }
+        };
+
+        let name = names
+            .get(name..)
+            .and_then(|mut names| read_cstr(&mut names, "symbol name").ok())
+            .ok_or(Error::Malformed("symbol name"))?;
+
+        if !name.is_empty() {
+            symbols.push(Symbol {
+                name: name.to_owned(),
+                value,
+                size,
+            });
+        }
+    }
+
+    Ok(symbols)
+}
+
 #[derive(Debug)]
 pub struct Elf {
+    pub endianness: Endianness,
     pub entry: Usize,
     pub segments: Vec<Segment>,
+    /// protection of the stack, from PT_GNU_STACK
+    pub stack_protection: Protection,
+    /// the (start, len) region that is made read-only after relocation, from PT_GNU_RELRO
+    pub relro: Option<(usize, usize)>,
+    /// the notes from PT_NOTE segments, or SHT_NOTE sections if there are none
+    pub notes: Vec<Note>,
+    /// the contents of `.riscv.attributes`
+    pub attributes: Option<RiscvAttributes>,
+    /// the source locations of addresses, from `.debug_line`
+    pub line_table: Option<LineTable>,
+    /// the named symbols of `.symtab`
+    pub symbols: Vec<Symbol>,
+}
+
+impl Elf {
+    fn gnu_note(&self, kind: u32) -> Option<&Note> {
+        self.notes
+            .iter()
+            .find(|note| note.name == "GNU" && note.kind == kind)
+    }
+
+    /// The unique identifier of the build, from NT_GNU_BUILD_ID
+    pub fn build_id(&self) -> Option<&[u8]> {
+        self.gnu_note(NT_GNU_BUILD_ID)
+            .map(|note| note.desc.as_slice())
+    }
+
+    /// Find a symbol by its name
+    pub fn symbol(&self, name: &str) -> Option<&Symbol> {
+        self.symbols.iter().find(|symbol| symbol.name == name)
+    }
+
+    /// The target os and kernel version, from NT_GNU_ABI_TAG
+    pub fn abi_tag(&self) -> Option<AbiTag> {
+        let mut desc = self.gnu_note(NT_GNU_ABI_TAG)?.desc.as_slice();
+
+        let mut word = || read_type!(&mut desc, u32, self.endianness, "abi tag").ok();
+
+        Some(AbiTag {
+            os: word()?,
+            version: (word()?, word()?, word()?),
+        })
+    }
+}
+
+const NOTE_SEGMENT: u32 = 4;
+const SYMTAB_SECTION: u32 = 2;
+const NOTE_SECTION: u32 = 7;
+const RISCV_ATTRIBUTES_SECTION: u32 = 0x7000_0003;
+
+const LOADABLE_SEGMENT: u32 = 1;
+const GNU_STACK_SEGMENT: u32 = 0x6474_e551;
+const GNU_RELRO_SEGMENT: u32 = 0x6474_e552;
+
+/// The protection of the stack if there is no PT_GNU_STACK header, riscv
+/// doesn't imply an executable stack
+const DEFAULT_STACK_PROTECTION: Protection = Protection {
+    r: true,
+    w: true,
+    x: false,
+};
+
+/// Page size used for rounding the RELRO region, the same as the dynamic loader does
+const PAGE_SIZE: usize = 4096;
+
+/// Make the RELRO region read-only by splitting the segments overlapping it.
+/// The start is rounded down and the end is rounded down to page boundaries, so
+/// the partial page at the end stays writable, just like in ld.so
+fn apply_relro(segments: Vec<Segment>, start: usize, len: usize) -> Vec<Segment> {
+    let relro_start = start & !(PAGE_SIZE - 1);
+    let relro_end = (start + len) & !(PAGE_SIZE - 1);
+
+    if relro_start >= relro_end {
+        return segments;
+    }
+
+    let mut result = Vec::with_capacity(segments.len() + 2);
+
+    for mut segment in segments {
+        let segment_end = segment.start + segment.data.len();
+
+        // no overlap with the relro region
+        if segment_end <= relro_start || segment.start >= relro_end {
+            result.push(segment);
+            continue;
+        }
+
+        // | head |  relro  | tail |
+        let tail_start = relro_end.min(segment_end);
+        let tail = segment.data.split_off(tail_start - segment.start);
+
+        let head_len = relro_start.saturating_sub(segment.start);
+        let relro = segment.data.split_off(head_len);
+
+        let protection = segment.protection;
+        let no_write = Protection {
+            r: true,
+            w: false,
+            x: true,
+        };
+
+        for (start, protection, data) in [
+            (segment.start, protection, segment.data),
+            (segment.start + head_len, protection & no_write, relro),
+            (tail_start, protection, tail),
+        ] {
+            if !data.is_empty() {
+                result.push(Segment {
+                    start,
+                    protection,
+                    data,
+                });
+            }
+        }
+    }
+
+    result
+}
+
+/// The notes, attributes, symbols and debug info are optional, the program
+/// still loads without them. A malformed one is reported and skipped
+fn optional<T, E: std::fmt::Debug>(what: &str, result: Result<T, E>) -> Option<T> {
+    result
+        .map_err(|err| eprintln!("warning: can't parse {}: {:?}", what, err))
+        .ok()
 }
 
 pub fn read_elf(path: impl AsRef<Path>) -> Result<Elf, Error> {
@@ -194,7 +619,8 @@ pub fn read_elf(path: impl AsRef<Path>) -> Result<Elf, Error> {
     let program_header_offset =
         read_usize(&mut reader, bitness, endianness, "program header offset")?;
 
-    let _ = read_usize(&mut reader, bitness, endianness, "section header offset")?;
+    let section_header_offset =
+        read_usize(&mut reader, bitness, endianness, "section header offset")?;
     let _ = read_type!(&mut reader, u32, endianness, "flags")?;
     let _ = read_type!(&mut reader, u16, endianness, "ELF header size")?;
     let _ = read_type!(&mut reader, u16, endianness, "program header entry size")?;
@@ -202,11 +628,24 @@ pub fn read_elf(path: impl AsRef<Path>) -> Result<Elf, Error> {
     let program_header_entries =
         read_type!(&mut reader, u16, endianness, "program header entries")?;
 
+    let _ = read_type!(&mut reader, u16, endianness, "section header entry size")?;
+    let section_header_entries =
+        read_type!(&mut reader, u16, endianness, "section header entries")?;
+    let section_names_index = read_type!(
+        &mut reader,
+        u16,
+        endianness,
+        "section name string table index"
+    )?;
+
     reader
         .seek(SeekFrom::Start(program_header_offset.into()))
         .map_err(Error::Io)?;
 
     let mut load = Vec::new();
+    let mut stack_protection = DEFAULT_STACK_PROTECTION;
+    let mut relro = None;
+    let mut notes = Vec::new();
 
     for _ in 0..program_header_entries {
         let segment_type = read_type!(&mut reader, u32, endianness, "segment type")?;
@@ -223,7 +662,7 @@ pub fn read_elf(path: impl AsRef<Path>) -> Result<Elf, Error> {
         let _ = read_usize(&mut reader, bitness, endianness, "segment physcal address")?;
         let file_size =
             read_usize(&mut reader, bitness, endianness, "segment size in file")?.into();
-        let memory_size =
+        let memory_size: usize =
             read_usize(&mut reader, bitness, endianness, "segment size in memory")?.into();
 
         let flags = if matches!(bitness, Bitness::Bits32) {
@@ -232,30 +671,37 @@ pub fn read_elf(path: impl AsRef<Path>) -> Result<Elf, Error> {
             flags
         };
 
-        let _ = read_usize(&mut reader, bitness, endianness, "segment alignment")?;
+        let alignment = read_usize(&mut reader, bitness, endianness, "segment alignment")?;
 
-        const LOADABLE_SEGMENT: u32 = 1;
+        match segment_type {
+            LOADABLE_SEGMENT => {}
+            GNU_STACK_SEGMENT => {
+                stack_protection = Protection::from(flags);
+                continue;
+            }
+            GNU_RELRO_SEGMENT => {
+                relro = Some((virtual_address.into(), memory_size));
+                continue;
+            }
+            NOTE_SEGMENT => {
+                let segment_notes = read_at(&mut reader, offset, file_size)
+                    .and_then(|data| parse_notes(&data, alignment.into(), endianness));
+                notes.extend(optional("a note segment", segment_notes).unwrap_or_default());
+                continue;
+            }
+            _ => continue,
+        }
 
         // only care about non zero sized loadable segments
-        if segment_type != LOADABLE_SEGMENT || memory_size == 0 {
+        if memory_size == 0 {
             continue;
         }
 
         let data = if file_size > 0 {
-            // save current position in file
-            let stream_position = reader.stream_position().map_err(Error::Io)?;
+            let mut data = read_at(&mut reader, offset, file_size)?;
 
-            // seek to segment data
-            reader.seek(SeekFrom::Start(offset)).map_err(Error::Io)?;
-
-            // read segment data
-            let mut data = vec![0; file_size];
-            reader.read_exact(&mut data).map_err(Error::Io)?;
-
-            // reset position in file
-            reader
-                .seek(SeekFrom::Start(stream_position))
-                .map_err(Error::Io)?;
+            // the rest of the segment (.bss) is zero filled
+            data.resize(memory_size.max(file_size), 0);
 
             data
         } else {
@@ -273,8 +719,300 @@ pub fn read_elf(path: impl AsRef<Path>) -> Result<Elf, Error> {
         });
     }
 
+    reader
+        .seek(SeekFrom::Start(section_header_offset.into()))
+        .map_err(Error::Io)?;
+
+    let mut sections = Vec::new();
+
+    for _ in 0..section_header_entries {
+        let name = read_type!(&mut reader, u32, endianness, "section name")?;
+        let kind = read_type!(&mut reader, u32, endianness, "section type")?;
+        let _ = read_usize(&mut reader, bitness, endianness, "section flags")?;
+        let _ = read_usize(&mut reader, bitness, endianness, "section address")?;
+        let offset = read_usize(&mut reader, bitness, endianness, "section offset")?.into();
+        let size = read_usize(&mut reader, bitness, endianness, "section size")?.into();
+        let link = read_type!(&mut reader, u32, endianness, "section link")?;
+        let _ = read_type!(&mut reader, u32, endianness, "section info")?;
+        let alignment = read_usize(&mut reader, bitness, endianness, "section alignment")?;
+        let _ = read_usize(&mut reader, bitness, endianness, "section entry size")?;
+
+        sections.push((name, kind, offset, size, alignment.into(), link));
+    }
+
+    let section_names = match sections.get(section_names_index as usize) {
+        Some(&(_, _, offset, size, _, _)) => read_at(&mut reader, offset, size)?,
+        None => Vec::new(),
+    };
+
+    let mut section_notes = Vec::new();
+    let mut attributes = None;
+    let mut debug_sections = DebugSections::default();
+    let mut symbols = Vec::new();
+
+    for &(name, kind, offset, size, alignment, link) in &sections {
+        let name = section_names
+            .get(name as usize..)
+            .and_then(|mut names| read_cstr(&mut names, "section name").ok())
+            .unwrap_or_default();
+
+        match (kind, name) {
+            (NOTE_SECTION, _) => {
+                let notes = read_at(&mut reader, offset, size)
+                    .and_then(|data| parse_notes(&data, alignment, endianness));
+                section_notes.extend(optional(name, notes).unwrap_or_default());
+            }
+            (SYMTAB_SECTION, _) => {
+                // the names are in the string table the section links to
+                let table = sections
+                    .get(link as usize)
+                    .ok_or(Error::Malformed("symbol table link"))
+                    .and_then(|&(_, _, names_offset, names_size, _, _)| {
+                        let names = read_at(&mut reader, names_offset, names_size)?;
+                        let data = read_at(&mut reader, offset, size)?;
+                        parse_symbols(&data, &names, bitness, endianness)
+                    });
+                symbols.extend(optional(name, table).unwrap_or_default());
+            }
+            (RISCV_ATTRIBUTES_SECTION, _) => {
+                let parsed = read_at(&mut reader, offset, size)
+                    .and_then(|data| parse_riscv_attributes(&data, endianness));
+                attributes = optional(name, parsed);
+            }
+            (_, ".debug_line") => debug_sections.line = read_at(&mut reader, offset, size)?,
+            (_, ".debug_line_str") => debug_sections.line_str = read_at(&mut reader, offset, size)?,
+            (_, ".debug_str") => debug_sections.str = read_at(&mut reader, offset, size)?,
+            _ => {}
+        }
+    }
+
+    let line_table = if debug_sections.line.is_empty() {
+        None
+    } else {
+        optional(".debug_line", LineTable::parse(&debug_sections, endianness))
+    };
+
+    // the note sections are the same as the note segments, so they are only
+    // needed when there are no program headers for them
+    if notes.is_empty() {
+        notes = section_notes;
+    }
+
+    // there is no relocation done by the loader, so the RELRO region can be
+    // made read-only right away
+    if let Some((start, len)) = relro {
+        load = apply_relro(load, start, len);
+    }
+
     Ok(Elf {
+        endianness,
         entry,
         segments: load,
+        stack_protection,
+        relro,
+        notes,
+        attributes,
+        line_table,
+        symbols,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn segment(start: usize, len: usize) -> Segment {
+        Segment {
+            start,
+            protection: 0b110.into(),
+            data: (0..len).map(|x| x as u8).collect(),
+        }
+    }
+
+    #[test]
+    fn notes() {
+        let data = [
+            4, 0, 0, 0, // name size
+            3, 0, 0, 0, // descriptor size
+            3, 0, 0, 0, // type
+            b'G', b'N', b'U', 0, // name
+            1, 2, 3, 0, // descriptor and padding
+            5, 0, 0, 0, // name size
+            0, 0, 0, 0, // descriptor size
+            7, 0, 0, 0, // type
+            b'a', b'b', b'c', b'd', 0, 0, 0, 0, // name and padding
+        ];
+
+        let notes = parse_notes(&data, 4, Endianness::Little).unwrap();
+
+        assert_eq!(
+            notes,
+            [
+                Note {
+                    name: "GNU".into(),
+                    kind: 3,
+                    desc: vec![1, 2, 3],
+                },
+                Note {
+                    name: "abcd".into(),
+                    kind: 7,
+                    desc: vec![],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn notes_truncated() {
+        let data = [4, 0, 0, 0, 8, 0, 0, 0, 3, 0, 0, 0, b'G', b'N', b'U', 0, 1];
+
+        assert!(matches!(
+            parse_notes(&data, 4, Endianness::Little),
+            Err(Error::Malformed(..))
+        ));
+    }
+
+    #[test]
+    fn riscv_attributes() {
+        let arch = b"rv64i2p1_m2p0_c2p0\0";
+
+        let mut file = vec![TAG_FILE as u8];
+        file.extend((1 + 4 + 2 + 2 + 2 + 1 + arch.len() as u32).to_le_bytes());
+        file.extend([TAG_RISCV_STACK_ALIGN as u8, 16]);
+        file.extend([TAG_RISCV_UNALIGNED_ACCESS as u8, 0]);
+        // unknown tag with an integer value
+        file.extend([8, 1]);
+        file.push(TAG_RISCV_ARCH as u8);
+        file.extend(arch);
+
+        let mut data = vec![b'A'];
+        data.extend((4 + 6 + file.len() as u32).to_le_bytes());
+        data.extend(b"riscv\0");
+        data.extend(file);
+
+        let attributes = parse_riscv_attributes(&data, Endianness::Little).unwrap();
+
+        assert_eq!(
+            attributes,
+            RiscvAttributes {
+                arch: Some("rv64i2p1_m2p0_c2p0".into()),
+                stack_align: Some(16),
+                unaligned_access: Some(false),
+            }
+        );
+    }
+
+    #[test]
+    fn symbols() {
+        let names = b"\0tohost\0fromhost\0";
+
+        let mut data = vec![0; 24];
+        for (name, value) in [(1u32, 0x8000_1000u64), (8, 0x8000_1040)] {
+            data.extend(name.to_le_bytes());
+            data.extend([0x11, 0, 3, 0]);
+            data.extend(value.to_le_bytes());
+            data.extend(8u64.to_le_bytes());
+        }
+
+        let symbols = parse_symbols(&data, names, Bitness::Bits64, Endianness::Little).unwrap();
+        assert_eq!(
+            symbols,
+            [
+                Symbol {
+                    name: "tohost".into(),
+                    value: 0x8000_1000,
+                    size: 8
+                },
+                Symbol {
+                    name: "fromhost".into(),
+                    value: 0x8000_1040,
+                    size: 8
+                },
+            ]
+        );
+
+        // 32 bit entries have the value and size before the info
+        let mut data = vec![0; 16];
+        data.extend(8u32.to_be_bytes());
+        data.extend(0x1000u32.to_be_bytes());
+        data.extend(4u32.to_be_bytes());
+        data.extend([0x11, 0, 0, 3]);
+        let symbols = parse_symbols(&data, names, Bitness::Bits32, Endianness::Big).unwrap();
+        assert_eq!(symbols[0].name, "fromhost");
+        assert_eq!((symbols[0].value, symbols[0].size), (0x1000, 4));
+
+        assert!(parse_symbols(&data, b"", Bitness::Bits32, Endianness::Big).is_err());
+    }
+
+    #[test]
+    fn uleb128() {
+        let mut data: &[u8] = &[0xe5, 0x8e, 0x26, 0x01];
+
+        assert_eq!(read_uleb128(&mut data, "").unwrap(), 624485);
+        assert_eq!(data, &[1]);
+    }
+
+    #[test]
+    fn sleb128() {
+        let mut data: &[u8] = &[0xc0, 0xbb, 0x78, 0x7f];
+
+        assert_eq!(read_sleb128(&mut data, "").unwrap(), -123456);
+        assert_eq!(read_sleb128(&mut data, "").unwrap(), -1);
+        assert!(data.is_empty());
+    }
+
+    #[test]
+    fn relro_whole_segment() {
+        let segments = apply_relro(vec![segment(0x1000, 0x1000)], 0x1000, 0x1000);
+
+        assert_eq!(segments.len(), 1);
+        assert_eq!(segments[0].start, 0x1000);
+        assert_eq!(segments[0].data.len(), 0x1000);
+        assert_eq!(segments[0].protection, 0b100.into());
+    }
+
+    #[test]
+    fn relro_split() {
+        let segments = apply_relro(vec![segment(0x1800, 0x3000)], 0x1800, 0x1900);
+
+        // the start is rounded down and the end is rounded down
+        assert_eq!(segments.len(), 2);
+
+        assert_eq!(segments[0].start, 0x1800);
+        assert_eq!(segments[0].data.len(), 0x1800);
+        assert_eq!(segments[0].protection, 0b100.into());
+        assert_eq!(segments[0].data[0], 0);
+
+        assert_eq!(segments[1].start, 0x3000);
+        assert_eq!(segments[1].data.len(), 0x1800);
+        assert_eq!(segments[1].protection, 0b110.into());
+        assert_eq!(segments[1].data[0], (0x1800 % 256) as u8);
+    }
+
+    #[test]
+    fn relro_inside() {
+        let segments = apply_relro(vec![segment(0, 0x4000)], 0x1000, 0x2000);
+
+        assert_eq!(segments.len(), 3);
+        assert_eq!((segments[0].start, segments[0].data.len()), (0, 0x1000));
+        assert_eq!(
+            (segments[1].start, segments[1].data.len()),
+            (0x1000, 0x2000)
+        );
+        assert_eq!(
+            (segments[2].start, segments[2].data.len()),
+            (0x3000, 0x1000)
+        );
+        assert_eq!(segments[0].protection, 0b110.into());
+        assert_eq!(segments[1].protection, 0b100.into());
+        assert_eq!(segments[2].protection, 0b110.into());
+    }
+
+    #[test]
+    fn relro_less_than_a_page() {
+        let segments = apply_relro(vec![segment(0x1000, 0x1000)], 0x1000, 0x800);
+
+        assert_eq!(segments.len(), 1);
+        assert_eq!(segments[0].protection, 0b110.into());
+    }
+}
@@ -0,0 +1,227 @@
+use std::collections::HashMap;
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_END: u32 = 9;
+
+const VERSION: u32 = 17;
+const LAST_COMPATIBLE_VERSION: u32 = 16;
+
+/// The size of the header, followed by the memory reservation block
+const HEADER_SIZE: usize = 40;
+
+/// A writer of flattened device tree blobs. Nodes are opened and closed in
+/// order, with their properties before their subnodes
+#[derive(Debug, Default)]
+pub struct Fdt {
+    structure: Vec<u8>,
+    strings: Vec<u8>,
+    /// the offsets of the property names in the strings block
+    names: HashMap<String, u32>,
+    depth: usize,
+}
+
+impl Fdt {
+    fn token(&mut self, token: u32) {
+        self.structure.extend(token.to_be_bytes());
+    }
+
+    /// Pad the structure block to the next 4 byte boundary
+    fn align(&mut self) {
+        let len = self.structure.len().next_multiple_of(4);
+        self.structure.resize(len, 0);
+    }
+
+    /// Open a node, the root node has an empty name
+    pub fn begin_node(&mut self, name: &str) {
+        self.token(FDT_BEGIN_NODE);
+        self.structure.extend(name.as_bytes());
+        self.structure.push(0);
+        self.align();
+        self.depth += 1;
+    }
+
+    pub fn end_node(&mut self) {
+        assert!(self.depth > 0, "no node to end");
+        self.token(FDT_END_NODE);
+        self.depth -= 1;
+    }
+
+    /// The offset of a property name, which is stored once
+    fn name_offset(&mut self, name: &str) -> u32 {
+        if let Some(&offset) = self.names.get(name) {
+            return offset;
+        }
+
+        let offset = self.strings.len() as u32;
+        self.strings.extend(name.as_bytes());
+        self.strings.push(0);
+        self.names.insert(name.to_owned(), offset);
+
+        offset
+    }
+
+    pub fn property(&mut self, name: &str, value: &[u8]) {
+        let offset = self.name_offset(name);
+
+        self.token(FDT_PROP);
+        self.structure.extend((value.len() as u32).to_be_bytes());
+        self.structure.extend(offset.to_be_bytes());
+        self.structure.extend(value);
+        self.align();
+    }
+
+    /// A property without a value, like `interrupt-controller`
+    pub fn property_empty(&mut self, name: &str) {
+        self.property(name, &[]);
+    }
+
+    pub fn property_u32(&mut self, name: &str, value: u32) {
+        self.property(name, &value.to_be_bytes());
+    }
+
+    pub fn property_u64(&mut self, name: &str, value: u64) {
+        self.property(name, &value.to_be_bytes());
+    }
+
+    pub fn property_cells(&mut self, name: &str, cells: &[u32]) {
+        let value: Vec<u8> = cells.iter().flat_map(|cell| cell.to_be_bytes()).collect();
+        self.property(name, &value);
+    }
+
+    /// A property with 64 bit values, as two cells each
+    pub fn property_u64s(&mut self, name: &str, values: &[u64]) {
+        let value: Vec<u8> = values
+            .iter()
+            .flat_map(|value| value.to_be_bytes())
+            .collect();
+        self.property(name, &value);
+    }
+
+    pub fn property_string(&mut self, name: &str, value: &str) {
+        self.property_strings(name, &[value]);
+    }
+
+    /// A string list, like `compatible`
+    pub fn property_strings(&mut self, name: &str, values: &[&str]) {
+        let mut value = Vec::new();
+        for string in values {
+            value.extend(string.as_bytes());
+            value.push(0);
+        }
+
+        self.property(name, &value);
+    }
+
+    /// Build the blob, all the nodes have to be ended
+    pub fn finish(mut self) -> Vec<u8> {
+        assert_eq!(self.depth, 0, "unended nodes");
+        self.token(FDT_END);
+
+        // the memory reservation block only has the terminating entry
+        let reservations = HEADER_SIZE;
+        let structure = reservations + 16;
+        let strings = structure + self.structure.len();
+        let total = strings + self.strings.len();
+
+        let header = [
+            FDT_MAGIC,
+            total as u32,
+            structure as u32,
+            strings as u32,
+            reservations as u32,
+            VERSION,
+            LAST_COMPATIBLE_VERSION,
+            // the boot cpu
+            0,
+            self.strings.len() as u32,
+            self.structure.len() as u32,
+        ];
+
+        let mut blob: Vec<u8> = header.iter().flat_map(|x| x.to_be_bytes()).collect();
+        blob.resize(structure, 0);
+        blob.extend(&self.structure);
+        blob.extend(&self.strings);
+
+        blob
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn be32(blob: &[u8], offset: usize) -> u32 {
+        u32::from_be_bytes(blob[offset..offset + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn blob() {
+        let mut fdt = Fdt::default();
+        fdt.begin_node("");
+        fdt.property_u32("#size-cells", 2);
+        fdt.begin_node("cpus");
+        fdt.property_u32("#size-cells", 0);
+        fdt.property_empty("a");
+        fdt.end_node();
+        fdt.end_node();
+        let blob = fdt.finish();
+
+        assert_eq!(be32(&blob, 0), FDT_MAGIC);
+        assert_eq!(be32(&blob, 4) as usize, blob.len());
+        assert_eq!(be32(&blob, 20), 17);
+
+        // the names are stored once
+        let strings = be32(&blob, 12) as usize;
+        assert_eq!(&blob[strings..], b"#size-cells\0a\0");
+        assert_eq!(be32(&blob, 32) as usize, 14);
+
+        let structure = be32(&blob, 8) as usize;
+        let expected: Vec<u32> = vec![
+            FDT_BEGIN_NODE,
+            0,
+            FDT_PROP,
+            4,
+            0,
+            2,
+            FDT_BEGIN_NODE,
+            u32::from_be_bytes(*b"cpus"),
+            0,
+            FDT_PROP,
+            4,
+            0,
+            0,
+            FDT_PROP,
+            0,
+            12,
+            FDT_END_NODE,
+            FDT_END_NODE,
+            FDT_END,
+        ];
+        let expected: Vec<u8> = expected.iter().flat_map(|x| x.to_be_bytes()).collect();
+        assert_eq!(&blob[structure..strings], expected);
+    }
+
+    #[test]
+    fn values() {
+        let mut fdt = Fdt::default();
+        fdt.begin_node("");
+        fdt.property_strings("compatible", &["a", "bc"]);
+        fdt.property_u64s("reg", &[0x8000_0000, 0x100]);
+        fdt.end_node();
+        let blob = fdt.finish();
+
+        let structure = be32(&blob, 8) as usize;
+        // begin node and the padded empty name, then the property
+        let compatible = structure + 8 + 12;
+        assert_eq!(&blob[compatible..compatible + 5], b"a\0bc\0");
+        assert_eq!(be32(&blob, compatible - 8), 5);
+
+        let reg = compatible + 8 + 12;
+        assert_eq!(be32(&blob, reg - 8), 16);
+        assert_eq!(be32(&blob, reg + 4), 0x8000_0000);
+        assert_eq!(be32(&blob, reg + 12), 0x100);
+    }
+}
@@ -0,0 +1,289 @@
+//! The host-target interface of spike, used by riscv-tests and other bare metal
+//! programs through the `tohost` and `fromhost` symbols
+use crate::vm::VirtualMemory;
+use std::collections::VecDeque;
+use std::io::Write;
+use std::sync::mpsc::Receiver;
+
+/// The devices, in bits 56 to 63 of a command
+const DEVICE_SYSCALL: u64 = 0;
+const DEVICE_CONSOLE: u64 = 1;
+
+/// The commands of the console, in bits 48 to 55
+const CONSOLE_GETCHAR: u64 = 0;
+const CONSOLE_PUTCHAR: u64 = 1;
+
+/// The proxied system calls, with the numbers of linux
+const SYS_WRITE: u64 = 64;
+const SYS_EXIT: u64 = 93;
+
+const EBADF: i64 = 9;
+const EFAULT: i64 = 14;
+const ENOSYS: i64 = 38;
+
+/// The most bytes written by a system call, the rest is left to the next call
+const MAX_WRITE: u64 = 1 << 20;
+
+/// A command written to `tohost`, or a response written to `fromhost`
+fn command(device: u64, command: u64, payload: u64) -> u64 {
+    device << 56 | command << 48 | payload & 0xffff_ffff_ffff
+}
+
+/// The host side of the interface. The program writes commands to `tohost`,
+/// which are cleared when handled, and gets the responses in `fromhost`
+pub struct Htif {
+    tohost: usize,
+    fromhost: Option<usize>,
+    input: Option<Receiver<u8>>,
+    output: Box<dyn Write>,
+    /// the responses waiting for `fromhost` to be cleared
+    responses: VecDeque<u64>,
+    /// a getchar waiting for a byte of input
+    reading: bool,
+}
+
+impl std::fmt::Debug for Htif {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Htif")
+            .field("tohost", &self.tohost)
+            .field("fromhost", &self.fromhost)
+            .field("responses", &self.responses)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Htif {
+    /// Serve the program through the `tohost` and `fromhost` addresses, there are
+    /// no responses without `fromhost`
+    pub fn new(
+        tohost: usize,
+        fromhost: Option<usize>,
+        input: Option<Receiver<u8>>,
+        output: Box<dyn Write>,
+    ) -> Self {
+        Self {
+            tohost,
+            fromhost,
+            input,
+            output,
+            responses: VecDeque::new(),
+            reading: false,
+        }
+    }
+
+    /// Handle the command in `tohost` and deliver the pending responses. Returns
+    /// the exit status when the program exits
+    pub fn poll(&mut self, memory: &mut VirtualMemory) -> Option<i32> {
+        if self.reading {
+            if let Some(byte) = self.input.as_ref().and_then(|input| input.try_recv().ok()) {
+                self.reading = false;
+                self.respond(command(DEVICE_CONSOLE, CONSOLE_GETCHAR, byte.into()));
+            }
+        }
+
+        let status = match memory.read_u64(self.tohost) {
+            Ok(0) | Err(_) => None,
+            Ok(tohost) => {
+                memory.write_u64(self.tohost, 0).ok()?;
+                self.handle(memory, tohost)
+            }
+        };
+
+        // a response is written once the previous one was taken
+        if let Some(fromhost) = self.fromhost {
+            if let Some(&response) = self.responses.front() {
+                if memory.read_u64(fromhost).ok()? == 0 {
+                    memory.write_u64(fromhost, response).ok()?;
+                    self.responses.pop_front();
+                }
+            }
+        }
+
+        status
+    }
+
+    fn respond(&mut self, response: u64) {
+        if self.fromhost.is_some() {
+            self.responses.push_back(response);
+        }
+    }
+
+    /// Write to the console, the output of the program is best effort
+    fn print(&mut self, data: &[u8]) {
+        let _ = self
+            .output
+            .write_all(data)
+            .and_then(|()| self.output.flush());
+    }
+
+    fn handle(&mut self, memory: &mut VirtualMemory, tohost: u64) -> Option<i32> {
+        let device = tohost >> 56;
+        let cmd = (tohost >> 48) & 0xff;
+        let payload = tohost & 0xffff_ffff_ffff;
+
+        match (device, cmd) {
+            // an odd payload is the exit status shifted left, riscv-tests report
+            // the number of the failed test this way
+            (DEVICE_SYSCALL, 0) if payload & 1 != 0 => return Some((payload >> 1) as i32),
+            // an even payload is the address of the system call arguments
+            (DEVICE_SYSCALL, 0) => {
+                let status = self.syscall(memory, payload as usize);
+                self.respond(command(DEVICE_SYSCALL, 0, 1));
+                return status;
+            }
+            (DEVICE_CONSOLE, CONSOLE_PUTCHAR) => self.print(&[payload as u8]),
+            (DEVICE_CONSOLE, CONSOLE_GETCHAR) => self.reading = true,
+            _ => {}
+        }
+
+        None
+    }
+
+    /// Run a system call from the block of the number and its arguments, the
+    /// result replaces the number
+    fn syscall(&mut self, memory: &mut VirtualMemory, address: usize) -> Option<i32> {
+        let mut args = [0; 4];
+        for (i, arg) in args.iter_mut().enumerate() {
+            *arg = memory.read_u64(address + 8 * i).ok()?;
+        }
+
+        let result = match args {
+            [SYS_EXIT, status, ..] => return Some(status as i32),
+            [SYS_WRITE, 1 | 2, buffer, len] => {
+                let mut data = vec![0; len.min(MAX_WRITE) as usize];
+                match memory.read_slice(buffer as usize, &mut data) {
+                    Ok(()) => {
+                        self.print(&data);
+                        data.len() as i64
+                    }
+                    Err(_) => -EFAULT,
+                }
+            }
+            [SYS_WRITE, ..] => -EBADF,
+            _ => -ENOSYS,
+        };
+
+        memory.write_u64(address, result as u64).ok()?;
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::elf::Segment;
+    use std::sync::{mpsc, Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    const TOHOST: usize = 0x1000;
+    const FROMHOST: usize = 0x1040;
+
+    fn memory() -> VirtualMemory {
+        VirtualMemory::try_from_iter([Segment {
+            start: TOHOST,
+            protection: 0b110.into(),
+            data: vec![0; 0x1000],
+        }])
+        .unwrap()
+    }
+
+    #[test]
+    fn exit() {
+        let mut memory = memory();
+        let mut htif = Htif::new(TOHOST, Some(FROMHOST), None, Box::new(Shared::default()));
+
+        assert_eq!(htif.poll(&mut memory), None);
+
+        // test 3 failed
+        memory.write_u64(TOHOST, 3 << 1 | 1).unwrap();
+        assert_eq!(htif.poll(&mut memory), Some(3));
+        assert_eq!(memory.read_u64(TOHOST).unwrap(), 0);
+
+        memory.write_u64(TOHOST, 1).unwrap();
+        assert_eq!(htif.poll(&mut memory), Some(0));
+    }
+
+    #[test]
+    fn syscalls() {
+        let mut memory = memory();
+        let output = Shared::default();
+        let mut htif = Htif::new(TOHOST, Some(FROMHOST), None, Box::new(output.clone()));
+
+        // write(1, "hi", 2)
+        let args = TOHOST + 0x100;
+        memory.write_slice(TOHOST + 0x200, b"hi").unwrap();
+        for (i, arg) in [SYS_WRITE, 1, TOHOST as u64 + 0x200, 2].iter().enumerate() {
+            memory.write_u64(args + 8 * i, *arg).unwrap();
+        }
+        memory.write_u64(TOHOST, args as u64).unwrap();
+
+        assert_eq!(htif.poll(&mut memory), None);
+        assert_eq!(*output.0.lock().unwrap(), b"hi");
+        assert_eq!(memory.read_u64(args).unwrap(), 2);
+        assert_eq!(memory.read_u64(FROMHOST).unwrap(), 1);
+
+        // an unknown system call
+        memory.write_u64(FROMHOST, 0).unwrap();
+        memory.write_u64(args, 1234).unwrap();
+        memory.write_u64(TOHOST, args as u64).unwrap();
+        htif.poll(&mut memory);
+        assert_eq!(memory.read_u64(args).unwrap() as i64, -ENOSYS);
+
+        // exit(7)
+        memory.write_u64(args, SYS_EXIT).unwrap();
+        memory.write_u64(args + 8, 7).unwrap();
+        memory.write_u64(TOHOST, args as u64).unwrap();
+        assert_eq!(htif.poll(&mut memory), Some(7));
+    }
+
+    #[test]
+    fn console() {
+        let mut memory = memory();
+        let output = Shared::default();
+        let (sender, receiver) = mpsc::channel();
+        let mut htif = Htif::new(
+            TOHOST,
+            Some(FROMHOST),
+            Some(receiver),
+            Box::new(output.clone()),
+        );
+
+        memory
+            .write_u64(
+                TOHOST,
+                command(DEVICE_CONSOLE, CONSOLE_PUTCHAR, b'x'.into()),
+            )
+            .unwrap();
+        htif.poll(&mut memory);
+        assert_eq!(*output.0.lock().unwrap(), b"x");
+        assert_eq!(memory.read_u64(FROMHOST).unwrap(), 0);
+
+        // the getchar is answered once there is input
+        memory
+            .write_u64(TOHOST, command(DEVICE_CONSOLE, CONSOLE_GETCHAR, 0))
+            .unwrap();
+        htif.poll(&mut memory);
+        assert_eq!(memory.read_u64(FROMHOST).unwrap(), 0);
+
+        sender.send(b'y').unwrap();
+        htif.poll(&mut memory);
+        assert_eq!(
+            memory.read_u64(FROMHOST).unwrap(),
+            command(DEVICE_CONSOLE, CONSOLE_GETCHAR, b'y'.into())
+        );
+    }
+}
@@ -0,0 +1,188 @@
+use std::str::FromStr;
+
+#[derive(Debug)]
+pub enum Error {
+    Base(String),
+    Extension(String),
+}
+
+/// A single extension with an optional (major, minor) version
+#[derive(Debug, Clone, PartialEq)]
+pub struct Extension {
+    pub name: String,
+    pub version: Option<(u32, u32)>,
+}
+
+/// A parsed ISA string like `rv64i2p1_m2p0_a2p1_f2p2_d2p2_c2p0`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Isa {
+    pub xlen: u32,
+    pub extensions: Vec<Extension>,
+}
+
+impl Isa {
+    pub fn has(&self, name: &str) -> bool {
+        self.extensions
+            .iter()
+            .any(|extension| extension.name == name)
+    }
+}
+
+/// Split a version like `2p1` from the end of the input
+fn split_version(input: &str) -> (&str, Option<(u32, u32)>) {
+    let without_minor = input.trim_end_matches(|c: char| c.is_ascii_digit());
+
+    let (rest, minor) = match without_minor.strip_suffix('p') {
+        Some(rest) if without_minor.len() != input.len() => {
+            (rest, input[without_minor.len()..].parse().ok())
+        }
+        _ => (input, None),
+    };
+
+    let name = rest.trim_end_matches(|c: char| c.is_ascii_digit());
+
+    match rest[name.len()..].parse() {
+        Ok(major) => (name, Some((major, minor.unwrap_or(0)))),
+        Err(_) => (input, None),
+    }
+}
+
+impl FromStr for Isa {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let lower = s.to_ascii_lowercase();
+
+        let (xlen, rest) = ["32", "64", "128"]
+            .iter()
+            .find_map(|xlen| {
+                lower
+                    .strip_prefix("rv")
+                    .and_then(|rest| rest.strip_prefix(xlen))
+                    .map(|rest| (xlen.parse().unwrap(), rest))
+            })
+            .ok_or_else(|| Error::Base(s.to_owned()))?;
+
+        let mut extensions = Vec::new();
+
+        for part in rest.split('_').filter(|part| !part.is_empty()) {
+            if part.starts_with(['z', 's', 'x']) {
+                // multi letter extensions are separated by underscores
+                let (name, version) = split_version(part);
+                extensions.push(Extension {
+                    name: name.to_owned(),
+                    version,
+                });
+                continue;
+            }
+
+            // single letter extensions can follow each other, each with an optional version
+            let mut rest = part;
+            while let Some(letter) = rest.chars().next() {
+                if !letter.is_ascii_alphabetic() {
+                    return Err(Error::Extension(part.to_owned()));
+                }
+
+                // the first 'p' between digits separates the major and minor
+                // versions, anywhere else it is the P extension
+                let bytes = rest.as_bytes();
+                let mut minor = false;
+                let end = (1..bytes.len())
+                    .find(|&i| {
+                        let separator = !minor
+                            && bytes[i] == b'p'
+                            && bytes[i - 1].is_ascii_digit()
+                            && bytes.get(i + 1).is_some_and(u8::is_ascii_digit);
+                        minor |= separator;
+                        bytes[i].is_ascii_alphabetic() && !separator
+                    })
+                    .unwrap_or(bytes.len());
+                let (_, version) = split_version(&rest[..end]);
+                rest = &rest[end..];
+
+                if letter == 'g' {
+                    for name in ["i", "m", "a", "f", "d", "zicsr", "zifencei"] {
+                        extensions.push(Extension {
+                            name: name.to_owned(),
+                            version: None,
+                        });
+                    }
+                } else {
+                    extensions.push(Extension {
+                        name: letter.to_string(),
+                        version,
+                    });
+                }
+            }
+        }
+
+        Ok(Self { xlen, extensions })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(isa: &Isa) -> Vec<&str> {
+        isa.extensions.iter().map(|x| x.name.as_str()).collect()
+    }
+
+    #[test]
+    fn attributes_arch() {
+        let isa: Isa = "rv64i2p1_m2p0_a2p1_f2p2_d2p2_c2p0_zicsr2p0_zifencei2p0"
+            .parse()
+            .unwrap();
+
+        assert_eq!(isa.xlen, 64);
+        assert_eq!(
+            names(&isa),
+            ["i", "m", "a", "f", "d", "c", "zicsr", "zifencei"]
+        );
+        assert_eq!(isa.extensions[0].version, Some((2, 1)));
+        assert_eq!(isa.extensions[7].version, Some((2, 0)));
+    }
+
+    #[test]
+    fn short_form() {
+        let isa: Isa = "RV32IMAC".parse().unwrap();
+
+        assert_eq!(isa.xlen, 32);
+        assert_eq!(names(&isa), ["i", "m", "a", "c"]);
+        assert!(isa.extensions.iter().all(|x| x.version.is_none()));
+    }
+
+    #[test]
+    fn general() {
+        let isa: Isa = "rv64gc".parse().unwrap();
+
+        assert!(isa.has("zicsr"));
+        assert!(isa.has("c"));
+        assert!(!isa.has("v"));
+    }
+
+    #[test]
+    fn digits_in_name() {
+        let isa: Isa = "rv64i_zve32x1p0_zba".parse().unwrap();
+
+        assert_eq!(names(&isa), ["i", "zve32x", "zba"]);
+        assert_eq!(isa.extensions[1].version, Some((1, 0)));
+        assert_eq!(isa.extensions[2].version, None);
+    }
+
+    #[test]
+    fn p_extension() {
+        let isa: Isa = "rv64ip".parse().unwrap();
+        assert_eq!(names(&isa), ["i", "p"]);
+
+        let isa: Isa = "rv64i2p1p0p2m".parse().unwrap();
+        assert_eq!(names(&isa), ["i", "p", "m"]);
+        assert_eq!(isa.extensions[0].version, Some((2, 1)));
+        assert_eq!(isa.extensions[1].version, Some((0, 2)));
+    }
+
+    #[test]
+    fn bad_base() {
+        assert!(matches!("rv48i".parse::<Isa>(), Err(Error::Base(..))));
+    }
+}
@@ -0,0 +1,19 @@
+pub mod clint;
+pub mod coredump;
+pub mod csr;
+pub mod dwarf;
+pub mod elf;
+pub mod fdt;
+pub mod htif;
+pub mod isa;
+pub mod machine;
+pub mod mmu;
+pub mod plic;
+pub mod sbi;
+pub mod system;
+pub mod trap;
+pub mod uart;
+pub mod virtio;
+pub mod vm;
+
+pub use machine::Machine;
@@ -0,0 +1,1924 @@
+use crate::clint::{Clint, Clock};
+use crate::csr::{
+    Csrs, INTERRUPT_PRIORITY, MEI, MSI, MSTATUS_MIE, MSTATUS_MPIE, MSTATUS_MPP, MSTATUS_MPP_SHIFT,
+    MSTATUS_MPRV, MSTATUS_MXR, MSTATUS_SIE, MSTATUS_SPIE, MSTATUS_SPP, MSTATUS_SUM, MSTATUS_TSR,
+    MSTATUS_TVM, MSTATUS_TW, MTI, SEI, SSI, STI, TIME,
+};
+use crate::dwarf::{LineTable, Location};
+use crate::elf::{Elf, Endianness, Segment};
+use crate::htif::Htif;
+use crate::isa::Isa;
+use crate::mmu::{Access, Context, Mmu};
+use crate::plic::Plic;
+use crate::sbi::{self, Call};
+use crate::trap::{Exception, Privilege};
+use crate::vm::{self, VirtualMemory, WatchKind, PAGE_SIZE};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io::Write;
+use std::rc::Rc;
+
+#[derive(Debug)]
+pub enum Error {
+    Memory(vm::Error),
+    /// an exception with the value of mtval
+    Exception {
+        exception: Exception,
+        tval: u64,
+    },
+    /// the binary was built with extensions that can't be emulated
+    UnsupportedExtensions(Vec<String>),
+}
+
+type Instruction = u32;
+
+/// The highest address of the initial stack, the same as `TASK_SIZE` for sv39 linux
+pub const STACK_TOP: usize = 0x40_0000_0000;
+
+/// Size of the initial stack mapping
+pub const STACK_SIZE: usize = 8 * 1024 * 1024;
+
+/// Anonymous mappings are placed below this address, leaving room for the stack
+/// to grow like the minimum stack gap of linux
+pub const MAP_BASE: usize = STACK_TOP - 128 * 1024 * 1024;
+
+/// The extensions that can be emulated
+pub const SUPPORTED_EXTENSIONS: &[&str] = &["i", "zicsr"];
+
+/// The exceptions delegated to a supervisor mode guest, all but its own ECALL
+const SBI_MEDELEG: u64 = 0xb1ff;
+
+/// The error of the system calls that are not emulated
+const ENOSYS: i64 = 38;
+
+/// Why the machine stopped running
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stop {
+    /// the instruction at `pc` accessed the watched address `addr`. The access is
+    /// done, and the machine stops before the next instruction. When an instruction
+    /// hits several watchpoints, the machine stops for each of them
+    Watchpoint {
+        addr: usize,
+        kind: WatchKind,
+        pc: u64,
+    },
+    /// the program made the exit system call, or the guest asked the SBI or HTIF
+    /// to power off or reset the system, with the exit status of the host
+    Exit(i32),
+}
+
+/// The state of a `Machine` saved by `snapshot`
+pub struct Snapshot {
+    memory: vm::Snapshot,
+    registers: [u64; 32],
+    pc: u64,
+    endianness: Endianness,
+    privilege: Privilege,
+    csrs: Csrs,
+}
+
+#[derive(Debug)]
+pub struct Machine {
+    memory: VirtualMemory,
+    registers: [u64; 32],
+    pc: u64,
+    extensions: Vec<String>,
+    /// the endianness of data accesses in every privilege, set from the ELF.
+    /// mstatus.UBE, SBE and MBE are hard-wired to zero and don't change it, and
+    /// instructions are always little endian
+    endianness: Endianness,
+    /// stops of the last instruction that were not reported yet
+    pending_stops: VecDeque<Stop>,
+    privilege: Privilege,
+    /// the privilege the guest was started in, the emulator handles the traps
+    /// to the privileges above it
+    environment: Privilege,
+    csrs: Csrs,
+    mmu: Mmu,
+    clint: Option<Rc<RefCell<Clint>>>,
+    plic: Option<Rc<RefCell<Plic>>>,
+    /// set by WFI until an interrupt is pending
+    waiting: bool,
+    /// the base of the UART used by the console calls of the SBI
+    console: Option<usize>,
+    /// print the fields of every executed instruction to stderr
+    trace: bool,
+    /// the source locations of the program, for the trace and fault reports
+    line_table: Option<LineTable>,
+    htif: Option<Htif>,
+}
+
+/// The exception of an instruction that can't be executed
+fn illegal_instruction(instruction: Instruction) -> Error {
+    Error::Exception {
+        exception: Exception::IllegalInstruction,
+        tval: instruction.into(),
+    }
+}
+
+impl Machine {
+    /// Map the loaded segments and a stack, and prepare to start from the entry point
+    pub fn new(elf: Elf) -> Result<Self, Error> {
+        let extensions = Self::select_extensions(elf.attributes.and_then(|x| x.arch))?;
+
+        let mut memory = VirtualMemory::default();
+        memory.set_map_base(MAP_BASE);
+
+        // the heap starts after the highest segment, including its bss
+        let end = elf
+            .segments
+            .iter()
+            .map(|segment| segment.start + segment.data.len())
+            .max()
+            .unwrap_or(0);
+        memory.set_brk_start(end);
+
+        for segment in elf.segments {
+            memory.load(segment).map_err(Error::Memory)?;
+        }
+
+        // the protection of the stack comes from PT_GNU_STACK
+        memory
+            .insert(Segment {
+                start: STACK_TOP - STACK_SIZE,
+                protection: elf.stack_protection,
+                data: vec![0; STACK_SIZE],
+            })
+            .map_err(Error::Memory)?;
+
+        // the stack pointer points to argc, followed by the argv, envp and auxv arrays
+        // they are all empty, so the zeroed stack already contains
+        // argc = 0, argv = { NULL }, envp = { NULL }, auxv = { AT_NULL, 0 }
+        let mut registers = [0; 32];
+        registers[2] = (STACK_TOP - 48) as u64;
+
+        // programs run in user mode without address translation, the memory
+        // is their address space, and the emulator handles their system calls
+        Ok(Self {
+            registers,
+            line_table: elf.line_table,
+            ..Self::with_memory(
+                memory,
+                elf.entry.into(),
+                elf.endianness,
+                extensions,
+                Privilege::User,
+            )
+        })
+    }
+
+    /// Load the segments into physical memory, and start from the entry point in
+    /// machine mode like bare metal firmware. Every trap is delivered to the guest
+    pub fn new_system(elf: Elf) -> Result<Self, Error> {
+        let extensions = Self::select_extensions(elf.attributes.and_then(|x| x.arch))?;
+
+        let mut memory = VirtualMemory::default();
+        for segment in elf.segments {
+            memory.load(segment).map_err(Error::Memory)?;
+        }
+
+        Ok(Self {
+            line_table: elf.line_table,
+            ..Self::with_memory(
+                memory,
+                elf.entry.into(),
+                elf.endianness,
+                extensions,
+                Privilege::Machine,
+            )
+        })
+    }
+
+    /// Start from address 0 in machine mode with empty memory, for raw images
+    /// written with `write_memory` and started with `set_pc`
+    pub fn new_bare() -> Self {
+        Self::with_memory(
+            VirtualMemory::default(),
+            0,
+            Endianness::Little,
+            SUPPORTED_EXTENSIONS.iter().map(|&x| x.to_owned()).collect(),
+            Privilege::Machine,
+        )
+    }
+
+    fn with_memory(
+        memory: VirtualMemory,
+        entry: u64,
+        endianness: Endianness,
+        extensions: Vec<String>,
+        privilege: Privilege,
+    ) -> Self {
+        Self {
+            memory,
+            registers: [0; 32],
+            pc: entry,
+            extensions,
+            // a big endian image has big endian data, but still little endian instructions
+            endianness,
+            pending_stops: VecDeque::new(),
+            privilege,
+            environment: privilege,
+            csrs: Csrs::default(),
+            mmu: Mmu::default(),
+            clint: None,
+            plic: None,
+            waiting: false,
+            console: None,
+            trace: false,
+            htif: None,
+            line_table: None,
+        }
+    }
+
+    /// Enable the extensions the binary was built for, it can't be run when some
+    /// of them can't be emulated. Without an arch string every supported extension
+    /// is enabled
+    fn select_extensions(arch: Option<String>) -> Result<Vec<String>, Error> {
+        let all = || SUPPORTED_EXTENSIONS.iter().map(|&x| x.to_owned()).collect();
+
+        let Some(arch) = arch else {
+            return Ok(all());
+        };
+
+        let isa = match arch.parse::<Isa>() {
+            Ok(isa) => isa,
+            Err(err) => {
+                eprintln!("warning: can't parse the arch string {:?}: {:?}", arch, err);
+                return Ok(all());
+            }
+        };
+
+        let (supported, unsupported): (Vec<_>, Vec<_>) = isa
+            .extensions
+            .into_iter()
+            .map(|extension| extension.name)
+            .partition(|name| SUPPORTED_EXTENSIONS.contains(&name.as_str()));
+
+        if !unsupported.is_empty() {
+            return Err(Error::UnsupportedExtensions(unsupported));
+        }
+
+        Ok(supported)
+    }
+
+    /// Check if an extension is enabled, the instructions of the disabled ones are
+    /// illegal
+    pub fn has_extension(&self, name: &str) -> bool {
+        self.extensions.iter().any(|x| x == name)
+    }
+
+    /// The ISA string of the enabled extensions, like `rv64i_zicsr`
+    pub fn isa(&self) -> String {
+        let (single, multi): (Vec<_>, Vec<_>) =
+            self.extensions.iter().partition(|name| name.len() == 1);
+
+        let mut isa = String::from("rv64");
+        isa.extend(single.into_iter().map(String::as_str));
+        for name in multi {
+            isa.push('_');
+            isa.push_str(name);
+        }
+
+        isa
+    }
+
+    pub fn memory(&self) -> &VirtualMemory {
+        &self.memory
+    }
+
+    /// Write to physical memory, for loading images like the device tree
+    pub fn write_memory(&mut self, address: usize, data: &[u8]) -> Result<(), Error> {
+        self.memory
+            .write_slice(address, data)
+            .map_err(Error::Memory)
+    }
+
+    /// Limit the size of the heap the guest can allocate with brk, `None` is
+    /// unlimited. The default is `vm::DEFAULT_HEAP_LIMIT`
+    pub fn set_heap_limit(&mut self, limit: Option<usize>) {
+        self.memory.set_heap_limit(limit);
+    }
+
+    pub fn registers(&self) -> &[u64; 32] {
+        &self.registers
+    }
+
+    pub fn pc(&self) -> u64 {
+        self.pc
+    }
+
+    /// The source location of an address, from the line table of the program
+    pub fn locate(&self, address: u64) -> Option<Location<'_>> {
+        self.line_table.as_ref()?.lookup(address)
+    }
+
+    pub fn set_pc(&mut self, pc: u64) {
+        self.pc = pc;
+    }
+
+    /// Print the fields of every executed instruction to stderr, which is off by
+    /// default
+    pub fn set_trace(&mut self, trace: bool) {
+        self.trace = trace;
+    }
+
+    /// The endianness of loads and stores
+    pub fn endianness(&self) -> Endianness {
+        self.endianness
+    }
+
+    /// The current privilege mode
+    pub fn privilege(&self) -> Privilege {
+        self.privilege
+    }
+
+    pub fn set_privilege(&mut self, privilege: Privilege) {
+        self.privilege = privilege;
+    }
+
+    pub fn csrs(&self) -> &Csrs {
+        &self.csrs
+    }
+
+    /// Switch between little and big endian data accesses of every privilege,
+    /// mstatus.UBE, SBE and MBE read as zero whatever the endianness
+    pub fn set_endianness(&mut self, endianness: Endianness) {
+        self.endianness = endianness;
+    }
+
+    /// Stop when the range (start, len) is accessed by `kind`, see `Stop::Watchpoint`
+    pub fn add_watchpoint(&mut self, start: usize, len: usize, kind: WatchKind) {
+        self.memory.add_watchpoint(start, len, kind);
+    }
+
+    /// Remove a watchpoint added with the same arguments, false if there is none
+    pub fn remove_watchpoint(&mut self, start: usize, len: usize, kind: WatchKind) -> bool {
+        self.memory.remove_watchpoint(start, len, kind)
+    }
+
+    /// Map zeroed RAM at (base, size) of physical memory, with the segments
+    /// already loaded there
+    pub fn add_ram(&mut self, base: usize, size: usize) -> Result<(), Error> {
+        self.memory
+            .fill(base, size, 0b111.into())
+            .map_err(Error::Memory)
+    }
+
+    /// Map a CLINT at `base`, to raise the machine timer and software interrupts
+    pub fn add_clint(&mut self, base: usize, clock: Clock) -> Result<(), Error> {
+        let clint = Rc::new(RefCell::new(Clint::new(clock)));
+
+        self.memory
+            .insert_device(base, crate::clint::CLINT_SIZE, clint.clone())
+            .map_err(Error::Memory)?;
+        self.clint = Some(clint);
+
+        Ok(())
+    }
+
+    /// Map a PLIC at `base`, to raise the external interrupts. Devices are connected
+    /// to its sources with `plic::Interrupt`
+    pub fn add_plic(&mut self, base: usize) -> Result<Rc<RefCell<Plic>>, Error> {
+        let plic = Rc::new(RefCell::new(Plic::default()));
+
+        self.memory
+            .insert_device(base, crate::plic::PLIC_SIZE, plic.clone())
+            .map_err(Error::Memory)?;
+        self.plic = Some(plic.clone());
+
+        Ok(plic)
+    }
+
+    /// Start the guest in supervisor mode on the built-in SBI, like firmware that
+    /// jumped to a kernel. The exceptions and the supervisor interrupts are
+    /// delegated, and the ECALLs from supervisor mode are handled by the emulator
+    pub fn start_supervisor(&mut self) {
+        self.privilege = Privilege::Supervisor;
+        self.environment = Privilege::Supervisor;
+        self.csrs.medeleg = SBI_MEDELEG;
+        self.csrs.mideleg = SSI | STI | SEI;
+    }
+
+    /// Use the UART at `base` for the console calls of the SBI, instead of the
+    /// standard output
+    pub fn set_console(&mut self, base: usize) {
+        self.console = Some(base);
+    }
+
+    /// Serve the `tohost` and `fromhost` of the program after every instruction,
+    /// its exit stops the machine
+    pub fn set_htif(&mut self, htif: Htif) {
+        self.htif = Some(htif);
+    }
+
+    /// Map a device to the region (start, len) of physical memory
+    pub fn add_device(
+        &mut self,
+        start: usize,
+        len: usize,
+        device: vm::SharedDevice,
+    ) -> Result<(), Error> {
+        self.memory
+            .insert_device(start, len, device)
+            .map_err(Error::Memory)
+    }
+
+    /// Save the registers and the memory, to reset the machine to this state
+    /// many times, like after the initialization of a fuzzing target
+    pub fn snapshot(&mut self) -> Snapshot {
+        Snapshot {
+            memory: self.memory.snapshot(),
+            registers: self.registers,
+            pc: self.pc,
+            endianness: self.endianness,
+            privilege: self.privilege,
+            csrs: self.csrs.clone(),
+        }
+    }
+
+    /// Reset the machine to a snapshot, only the pages written since the last
+    /// snapshot or restore are copied
+    pub fn restore(&mut self, snapshot: &Snapshot) {
+        self.memory.restore(&snapshot.memory);
+        self.registers = snapshot.registers;
+        self.pc = snapshot.pc;
+        self.endianness = snapshot.endianness;
+        self.privilege = snapshot.privilege;
+        self.csrs = snapshot.csrs.clone();
+        self.pending_stops.clear();
+        self.waiting = false;
+
+        // the page tables may have changed
+        self.mmu.flush(None, None);
+    }
+
+    /// Write a register, writes to x0 are ignored
+    pub fn set_register(&mut self, register: usize, value: u64) {
+        if register != 0 {
+            self.registers[register] = value;
+        }
+    }
+
+    /// The translation context of an access by the current privilege
+    fn context(&self, access: Access) -> Context {
+        let privilege = match access {
+            Access::Fetch => self.privilege,
+            Access::Load | Access::Store => self.csrs.data_privilege(self.privilege),
+        };
+
+        Context {
+            satp: self.csrs.satp,
+            privilege,
+            sum: self.csrs.mstatus & MSTATUS_SUM != 0,
+            mxr: self.csrs.mstatus & MSTATUS_MXR != 0,
+        }
+    }
+
+    /// Translate a virtual address for an access by the current privilege
+    fn translate(&mut self, address: u64, access: Access) -> Result<usize, Error> {
+        let context = self.context(access);
+
+        self.mmu
+            .translate(&mut self.memory, context, address, access)
+            .map(|address| address as usize)
+            .map_err(|exception| Error::Exception {
+                exception,
+                tval: address,
+            })
+    }
+
+    /// Check if an access crosses into a page that may be anywhere in physical
+    /// memory, it's then split in bytes that are translated separately
+    fn crosses_translated_page(&self, address: u64, size: usize) -> bool {
+        // loads and stores have the same context
+        self.context(Access::Load).is_translated()
+            && (address as usize % vm::PAGE_SIZE) + size > vm::PAGE_SIZE
+    }
+
+    /// Turn a fault of physical memory into the exception of the access, when the
+    /// guest handles its own traps. Programs in user mode keep the details of the fault
+    fn memory_fault(&self, err: vm::Error, access: Access, address: u64) -> Error {
+        if self.environment == Privilege::User {
+            return Error::Memory(err);
+        }
+
+        let exception = match (err, access) {
+            (vm::Error::Misaligned { .. }, Access::Load) => Exception::LoadAddressMisaligned,
+            (vm::Error::Misaligned { .. }, Access::Store) => Exception::StoreAddressMisaligned,
+            (_, Access::Fetch) => Exception::InstructionAccessFault,
+            (_, Access::Load) => Exception::LoadAccessFault,
+            (_, Access::Store) => Exception::StoreAccessFault,
+        };
+
+        Error::Exception {
+            exception,
+            tval: address,
+        }
+    }
+
+    /// Check the misaligned access policy for an access split in bytes
+    fn check_split_access(&self, address: u64, size: usize, access: Access) -> Result<(), Error> {
+        match self.memory.misaligned_policy() {
+            vm::MisalignedPolicy::Trap => Err(self.memory_fault(
+                vm::Error::Misaligned {
+                    addr: address as usize,
+                    size,
+                },
+                access,
+                address,
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Load a zero extended value of `size` bytes in the data endianness
+    fn load(&mut self, address: u64, size: usize) -> Result<u64, Error> {
+        let fault = |machine: &Self, err| machine.memory_fault(err, Access::Load, address);
+
+        let value = if self.crosses_translated_page(address, size) {
+            self.check_split_access(address, size, Access::Load)?;
+
+            let mut bytes = [0; 8];
+            for (i, byte) in bytes[..size].iter_mut().enumerate() {
+                let physical = self.translate(address.wrapping_add(i as u64), Access::Load)?;
+                *byte = self
+                    .memory
+                    .read_u8(physical)
+                    .map_err(|err| fault(self, err))?;
+            }
+
+            u64::from_le_bytes(bytes)
+        } else {
+            let physical = self.translate(address, Access::Load)?;
+
+            match size {
+                1 => self.memory.read_u8(physical).map(u64::from),
+                2 => self.memory.read_u16(physical).map(u64::from),
+                4 => self.memory.read_u32(physical).map(u64::from),
+                _ => self.memory.read_u64(physical),
+            }
+            .map_err(|err| fault(self, err))?
+        };
+
+        // the memory is read as little endian, big endian only needs a byte swap
+        Ok(match self.endianness {
+            Endianness::Little => value,
+            Endianness::Big => value.swap_bytes() >> (64 - 8 * size),
+        })
+    }
+
+    /// Store the low `size` bytes of a value in the data endianness
+    fn store(&mut self, address: u64, size: usize, value: u64) -> Result<(), Error> {
+        let fault = |machine: &Self, err| machine.memory_fault(err, Access::Store, address);
+
+        let value = match self.endianness {
+            Endianness::Little => value,
+            Endianness::Big => value.swap_bytes() >> (64 - 8 * size),
+        };
+
+        if self.crosses_translated_page(address, size) {
+            self.check_split_access(address, size, Access::Store)?;
+
+            // translate every byte first, so a page fault doesn't leave a partial store
+            let mut addresses = [0; 8];
+            for (i, physical) in addresses[..size].iter_mut().enumerate() {
+                *physical = self.translate(address.wrapping_add(i as u64), Access::Store)?;
+            }
+
+            for (i, &physical) in addresses[..size].iter().enumerate() {
+                self.memory
+                    .write_u8(physical, (value >> (8 * i)) as u8)
+                    .map_err(|err| fault(self, err))?;
+            }
+
+            return Ok(());
+        }
+
+        let physical = self.translate(address, Access::Store)?;
+
+        match size {
+            1 => self.memory.write_u8(physical, value as u8),
+            2 => self.memory.write_u16(physical, value as u16),
+            4 => self.memory.write_u32(physical, value as u32),
+            _ => self.memory.write_u64(physical, value),
+        }
+        .map_err(|err| fault(self, err))
+    }
+
+    /// fetch an instruction from pc, instructions are always little endian
+    /// only supports the 32 bit instructions for now
+    fn fetch_instruction(&mut self) -> Result<Instruction, Error> {
+        let physical = self.translate(self.pc, Access::Fetch)?;
+
+        self.memory
+            .fetch_u32(physical)
+            .map_err(|err| self.memory_fault(err, Access::Fetch, self.pc))
+    }
+
+    /// Execute a CSR instruction, the old value is written to rd
+    fn csr_instruction(&mut self, instruction: Instruction) -> Result<(), Error> {
+        let funct3 = (instruction >> 12) & 0b111;
+        let rd = ((instruction >> 7) & 0b1_1111) as usize;
+        let rs1 = ((instruction >> 15) & 0b1_1111) as usize;
+        let csr = (instruction >> 20) as u16;
+
+        let illegal = || illegal_instruction(instruction);
+
+        // the immediate forms use the rs1 field as a 5 bit value
+        let operand = if funct3 & 0b100 != 0 {
+            rs1 as u64
+        } else {
+            self.registers[rs1]
+        };
+
+        // CSRRW doesn't read with rd = x0, CSRRS and CSRRC don't write with rs1 = x0
+        let read = funct3 & 0b11 != 1 || rd != 0;
+        let write = funct3 & 0b11 == 1 || rs1 != 0;
+
+        let old = if read {
+            match (csr, &self.clint) {
+                // the time counter is a read-only shadow of mtime
+                (TIME, Some(clint)) => clint.borrow().mtime(),
+                _ => self.csrs.read(csr, self.privilege).ok_or_else(illegal)?,
+            }
+        } else {
+            0
+        };
+
+        if write {
+            let new = match funct3 & 0b11 {
+                1 => operand,
+                2 => old | operand,
+                _ => old & !operand,
+            };
+
+            self.csrs
+                .write(csr, new, self.privilege)
+                .ok_or_else(illegal)?;
+        }
+
+        self.set_register(rd, old);
+
+        Ok(())
+    }
+
+    /// Execute SFENCE.VMA, which can't be used in user mode or when trapped by mstatus.TVM
+    fn sfence_vma(&mut self, instruction: Instruction) -> Result<(), Error> {
+        let rs1 = ((instruction >> 15) & 0b1_1111) as usize;
+        let rs2 = ((instruction >> 20) & 0b1_1111) as usize;
+
+        let trapped =
+            self.privilege == Privilege::Supervisor && self.csrs.mstatus & MSTATUS_TVM != 0;
+
+        if self.privilege == Privilege::User || trapped {
+            return Err(illegal_instruction(instruction));
+        }
+
+        // x0 means all the addresses or address spaces
+        let address = (rs1 != 0).then(|| self.registers[rs1]);
+        let asid = (rs2 != 0).then(|| self.registers[rs2] as u16);
+
+        self.mmu.flush(address, asid);
+
+        Ok(())
+    }
+
+    /// Run until the machine stops or faults
+    pub fn run(&mut self) -> Result<Stop, Error> {
+        loop {
+            if let Some(stop) = self.cycle()? {
+                return Ok(stop);
+            }
+        }
+    }
+
+    /// Return from a machine mode trap handler to the privilege in mstatus.MPP
+    fn mret(&mut self, instruction: Instruction) -> Result<(), Error> {
+        if self.privilege != Privilege::Machine {
+            return Err(illegal_instruction(instruction));
+        }
+
+        let status = self.csrs.mstatus;
+        let previous = Privilege::from_bits((status & MSTATUS_MPP) >> MSTATUS_MPP_SHIFT)
+            .unwrap_or(Privilege::User);
+
+        // MIE is restored from MPIE, and MPP is left at the lowest privilege
+        let mie = if status & MSTATUS_MPIE != 0 {
+            MSTATUS_MIE
+        } else {
+            0
+        };
+        let mut status = status & !(MSTATUS_MIE | MSTATUS_MPP) | mie | MSTATUS_MPIE;
+
+        if previous != Privilege::Machine {
+            status &= !MSTATUS_MPRV;
+        }
+
+        self.csrs.mstatus = status;
+        self.privilege = previous;
+        self.pc = self.csrs.mepc;
+
+        Ok(())
+    }
+
+    /// Return from a supervisor mode trap handler to the privilege in mstatus.SPP,
+    /// which can be trapped by mstatus.TSR
+    fn sret(&mut self, instruction: Instruction) -> Result<(), Error> {
+        let trapped =
+            self.privilege == Privilege::Supervisor && self.csrs.mstatus & MSTATUS_TSR != 0;
+
+        if self.privilege == Privilege::User || trapped {
+            return Err(illegal_instruction(instruction));
+        }
+
+        let status = self.csrs.mstatus;
+        let previous = if status & MSTATUS_SPP != 0 {
+            Privilege::Supervisor
+        } else {
+            Privilege::User
+        };
+
+        let sie = if status & MSTATUS_SPIE != 0 {
+            MSTATUS_SIE
+        } else {
+            0
+        };
+
+        self.csrs.mstatus =
+            status & !(MSTATUS_SIE | MSTATUS_SPP | MSTATUS_MPRV) | sie | MSTATUS_SPIE;
+        self.privilege = previous;
+        self.pc = self.csrs.sepc;
+
+        Ok(())
+    }
+
+    /// Take the trap of an exception. The traps to a privilege above the one the
+    /// guest was started in are handled by the emulator, the others are delivered
+    /// to the trap handler of the guest, in supervisor mode when delegated by medeleg
+    fn exception(&mut self, exception: Exception, tval: u64) -> Result<(), Error> {
+        let cause = exception as u64;
+
+        let delegated =
+            self.privilege <= Privilege::Supervisor && (self.csrs.medeleg >> cause) & 1 != 0;
+        let target = if delegated {
+            Privilege::Supervisor
+        } else {
+            Privilege::Machine
+        };
+
+        if target > self.environment {
+            return self.emulate_trap(exception, tval);
+        }
+
+        self.enter_trap(target, cause, tval);
+
+        Ok(())
+    }
+
+    /// Enter the trap handler of the target privilege, `cause` has the interrupt bit
+    /// set for interrupts, which are the only traps using the vectored mode
+    fn enter_trap(&mut self, target: Privilege, cause: u64, tval: u64) {
+        let status = self.csrs.mstatus;
+
+        let tvec = if target == Privilege::Supervisor {
+            let spie = if status & MSTATUS_SIE != 0 {
+                MSTATUS_SPIE
+            } else {
+                0
+            };
+            let spp = if self.privilege == Privilege::Supervisor {
+                MSTATUS_SPP
+            } else {
+                0
+            };
+
+            self.csrs.sepc = self.pc;
+            self.csrs.scause = cause;
+            self.csrs.stval = tval;
+            self.csrs.mstatus = status & !(MSTATUS_SIE | MSTATUS_SPIE | MSTATUS_SPP) | spie | spp;
+
+            self.csrs.stvec
+        } else {
+            let mpie = if status & MSTATUS_MIE != 0 {
+                MSTATUS_MPIE
+            } else {
+                0
+            };
+            let mpp = (self.privilege as u64) << MSTATUS_MPP_SHIFT;
+
+            self.csrs.mepc = self.pc;
+            self.csrs.mcause = cause;
+            self.csrs.mtval = tval;
+            self.csrs.mstatus = status & !(MSTATUS_MIE | MSTATUS_MPIE | MSTATUS_MPP) | mpie | mpp;
+
+            self.csrs.mtvec
+        };
+
+        let interrupt = cause >> 63 != 0;
+        let base = tvec & !0b11;
+
+        self.privilege = target;
+        self.pc = if interrupt && tvec & 1 != 0 {
+            base.wrapping_add(4 * (cause & !(1 << 63)))
+        } else {
+            base
+        };
+    }
+
+    /// Handle a trap like the kernel of a user program or the firmware of a
+    /// supervisor, only the system calls and the SBI calls are handled and the
+    /// other exceptions stop the machine
+    fn emulate_trap(&mut self, exception: Exception, tval: u64) -> Result<(), Error> {
+        match exception {
+            Exception::EcallFromUser if self.environment == Privilege::User => {
+                self.syscall();
+                self.pc += 4;
+                Ok(())
+            }
+            Exception::EcallFromSupervisor if self.environment == Privilege::Supervisor => {
+                self.sbi_call();
+                self.pc += 4;
+                Ok(())
+            }
+            _ => Err(Error::Exception { exception, tval }),
+        }
+    }
+
+    /// Handle a linux system call
+    fn syscall(&mut self) {
+        match self.registers[17] {
+            93 => {
+                // exit
+                let status = self.registers[10] as i32;
+                self.pending_stops.push_back(Stop::Exit(status));
+            }
+            214 => {
+                // brk
+                let brk = self.memory.brk(self.registers[10] as usize);
+                self.set_register(10, brk as u64);
+            }
+            _ => self.set_register(10, -ENOSYS as u64),
+        }
+    }
+
+    /// Handle a call to the SBI, with the extension in a7, the function in a6 and
+    /// the arguments in a0 to a5
+    fn sbi_call(&mut self) {
+        let args: [u64; 6] = self.registers[10..16].try_into().unwrap();
+        let request = sbi::decode(self.registers[17], self.registers[16], &args);
+
+        let result = match request.call {
+            Call::Return(result) => result,
+            Call::SetTimer(time) => match &self.clint {
+                // the supervisor timer interrupt follows the comparator
+                Some(clint) => {
+                    clint.borrow_mut().set_mtimecmp(time);
+                    Ok(0)
+                }
+                None => Err(sbi::ERR_NOT_SUPPORTED),
+            },
+            Call::ConsolePutchar(byte) => self.console_putchar(byte),
+            Call::ConsoleGetchar => Ok(self.console_getchar().map_or(u64::MAX, u64::from)),
+            Call::SendIpi(harts) => {
+                if harts.contains(0) {
+                    self.csrs.mip |= SSI;
+                }
+                Ok(0)
+            }
+            Call::ClearIpi => {
+                self.csrs.mip &= !SSI;
+                Ok(0)
+            }
+            // the instructions are always fetched from memory
+            Call::RemoteFenceI(_) => Ok(0),
+            Call::RemoteSfenceVma {
+                harts,
+                start,
+                size,
+                asid,
+            } => {
+                if harts.contains(0) {
+                    self.remote_sfence_vma(start, size, asid);
+                }
+                Ok(0)
+            }
+            // there is only one hart, which is always started
+            Call::HartStart { hart: 0, .. } => Err(sbi::ERR_ALREADY_AVAILABLE),
+            Call::HartStart { .. } => Err(sbi::ERR_INVALID_PARAM),
+            Call::HartStop => Err(sbi::ERR_FAILED),
+            Call::HartStatus(0) => Ok(sbi::HART_STARTED),
+            Call::HartStatus(_) => Err(sbi::ERR_INVALID_PARAM),
+            Call::HartSuspend { kind, .. } if kind == sbi::SUSPEND_RETENTIVE => {
+                self.waiting = true;
+                Ok(0)
+            }
+            Call::HartSuspend { .. } => Err(sbi::ERR_NOT_SUPPORTED),
+            Call::Reset { kind, reason } => match kind {
+                sbi::RESET_SHUTDOWN | sbi::RESET_COLD_REBOOT | sbi::RESET_WARM_REBOOT => {
+                    let status = i32::from(reason == sbi::REASON_SYSTEM_FAILURE);
+                    self.pending_stops.push_back(Stop::Exit(status));
+                    Ok(0)
+                }
+                _ => Err(sbi::ERR_INVALID_PARAM),
+            },
+        };
+
+        match (result, request.legacy) {
+            (Ok(value), true) => self.set_register(10, value),
+            (Err(err), true) => self.set_register(10, err as u64),
+            (Ok(value), false) => {
+                self.set_register(10, sbi::SUCCESS as u64);
+                self.set_register(11, value);
+            }
+            (Err(err), false) => self.set_register(10, err as u64),
+        }
+    }
+
+    /// Flush the translations of the range (start, size) of virtual memory, the
+    /// empty range at 0 and the size of -1 are the whole address space
+    fn remote_sfence_vma(&mut self, start: u64, size: u64, asid: Option<u16>) {
+        // flushing page by page is only worth it for small ranges
+        const MAX_PAGES: u64 = 64;
+
+        if start == 0 && size == 0 || size == u64::MAX || size > MAX_PAGES * PAGE_SIZE as u64 {
+            self.mmu.flush(None, asid);
+            return;
+        }
+
+        let mut page = start & !(PAGE_SIZE as u64 - 1);
+        while page < start.saturating_add(size) {
+            self.mmu.flush(Some(page), asid);
+            page += PAGE_SIZE as u64;
+        }
+    }
+
+    /// Write a byte to the console of the SBI, the transmitter of the UART has no
+    /// flow control
+    fn console_putchar(&mut self, byte: u8) -> Result<u64, i64> {
+        match self.console {
+            Some(base) => self
+                .memory
+                .write_u8(base, byte)
+                .map_err(|_| sbi::ERR_FAILED)?,
+            None => {
+                let mut stdout = std::io::stdout();
+                stdout
+                    .write_all(&[byte])
+                    .and_then(|()| stdout.flush())
+                    .map_err(|_| sbi::ERR_FAILED)?;
+            }
+        }
+
+        Ok(0)
+    }
+
+    /// Read a byte from the console of the SBI, `None` when no byte was received
+    fn console_getchar(&mut self) -> Option<u8> {
+        // the data ready bit of the line status register
+        let base = self.console?;
+        let status = self.memory.read_u8(base + 5).ok()?;
+
+        if status & 1 == 0 {
+            return None;
+        }
+        self.memory.read_u8(base).ok()
+    }
+
+    /// Execute a single instruction, and tell if the machine stopped. The other
+    /// stops of the previous instruction are reported before executing another
+    pub fn cycle(&mut self) -> Result<Option<Stop>, Error> {
+        if let Some(stop) = self.pending_stops.pop_front() {
+            return Ok(Some(stop));
+        }
+
+        self.update_interrupts();
+
+        if self.waiting {
+            if self.csrs.mip & self.csrs.mie == 0 {
+                if let Some(clint) = &self.clint {
+                    clint.borrow_mut().wait();
+                }
+                return Ok(None);
+            }
+
+            // a pending interrupt wakes the hart up, even when it is disabled
+            self.waiting = false;
+        }
+
+        if let Some((target, code)) = self.pending_interrupt() {
+            self.enter_trap(target, 1 << 63 | code, 0);
+        }
+
+        let pc = self.pc;
+
+        // the hits of a faulting instruction are stale
+        self.memory.take_watchpoint_hits();
+
+        match self.step() {
+            Ok(()) => {}
+            Err(Error::Exception { exception, tval }) => self.exception(exception, tval)?,
+            Err(err) => return Err(err),
+        }
+
+        self.pending_stops.extend(
+            self.memory
+                .take_watchpoint_hits()
+                .into_iter()
+                .map(|(addr, kind)| Stop::Watchpoint { addr, kind, pc }),
+        );
+
+        if let Some(htif) = &mut self.htif {
+            if let Some(status) = htif.poll(&mut self.memory) {
+                self.pending_stops.push_back(Stop::Exit(status));
+            }
+        }
+
+        Ok(self.pending_stops.pop_front())
+    }
+
+    /// Update the pending bits of mip that are driven by devices
+    fn update_interrupts(&mut self) {
+        self.memory.poll_devices();
+
+        if let Some(clint) = &self.clint {
+            let mut clint = clint.borrow_mut();
+            clint.tick();
+
+            // on the SBI the comparator is the one of the supervisor timer
+            let timer = if self.environment == Privilege::Supervisor {
+                STI
+            } else {
+                MTI
+            };
+
+            let mut pending = 0;
+            if clint.timer_pending() {
+                pending |= timer;
+            }
+            if clint.software_pending() {
+                pending |= MSI;
+            }
+
+            self.csrs.mip = self.csrs.mip & !(timer | MSI) | pending;
+        }
+
+        // the contexts 0 and 1 are the machine and supervisor external interrupts,
+        // the PLIC drives SEIP over the value written by software
+        if let Some(plic) = &self.plic {
+            let plic = plic.borrow();
+
+            let mut pending = 0;
+            if plic.interrupting(0) {
+                pending |= MEI;
+            }
+            if plic.interrupting(1) {
+                pending |= SEI;
+            }
+
+            self.csrs.mip = self.csrs.mip & !(MEI | SEI) | pending;
+        }
+    }
+
+    /// The interrupt to take before the next instruction, with its target privilege
+    /// and cause code. Interrupts are taken in a lower privilege than their target,
+    /// or in the target privilege while they are enabled by mstatus
+    fn pending_interrupt(&self) -> Option<(Privilege, u64)> {
+        let pending = self.csrs.mip & self.csrs.mie;
+        if pending == 0 {
+            return None;
+        }
+
+        let status = self.csrs.mstatus;
+        let machine = self.privilege < Privilege::Machine || status & MSTATUS_MIE != 0;
+        let supervisor = self.privilege < Privilege::Supervisor
+            || self.privilege == Privilege::Supervisor && status & MSTATUS_SIE != 0;
+
+        let highest = |target: Privilege, interrupts: u64| {
+            INTERRUPT_PRIORITY
+                .iter()
+                .find(|(bit, _)| interrupts & bit != 0)
+                .map(|&(_, code)| (target, code))
+        };
+
+        let interrupt = machine
+            .then(|| highest(Privilege::Machine, pending & !self.csrs.mideleg))
+            .flatten()
+            .or_else(|| {
+                supervisor
+                    .then(|| highest(Privilege::Supervisor, pending & self.csrs.mideleg))
+                    .flatten()
+            })?;
+
+        // the emulator takes no interrupts
+        (interrupt.0 <= self.environment).then_some(interrupt)
+    }
+
+    /// Fetch and execute an instruction, exceptions are returned as errors
+    fn step(&mut self) -> Result<(), Error> {
+        let instruction = self.fetch_instruction()?;
+
+        let opcode = instruction & 0b111_1111;
+        let funct3 = (instruction >> 12) & 0b111;
+        let imm11_0 = instruction >> 20;
+        let rd = ((instruction >> 7) & 0b1_1111) as usize;
+        let rs1 = ((instruction >> 15) & 0b1_1111) as usize;
+        let rs2 = ((instruction >> 20) & 0b1_1111) as usize;
+
+        // sign extended immediates of the I and S formats
+        let imm_i = (instruction as i32 >> 20) as u64;
+        let imm_s = ((instruction as i32 >> 25) << 5 | (instruction as i32 >> 7) & 0b1_1111) as u64;
+
+        if self.trace {
+            if let Some(location) = self.locate(self.pc) {
+                eprintln!("at    : {}", location);
+            }
+            eprintln!(
+                "instr : {} {:#x} {:#b}",
+                instruction, instruction, instruction
+            );
+            eprintln!("opcode: {} {:#x} {:#b}", opcode, opcode, opcode);
+            eprintln!("funct3: {} {:#x} {:#b}", funct3, funct3, funct3);
+            eprintln!("imm110: {} {:#x} {:#b}", imm11_0, imm11_0, imm11_0);
+            eprintln!("rd    : {} {:#x} {:#b}", rd, rd, rd);
+            eprintln!("rs1   : {} {:#x} {:#b}", rs1, rs1, rs1);
+        }
+
+        match (funct3, opcode) {
+            (0..=6, 0b0000011) => {
+                // LB, LH, LW, LD, LBU, LHU, LWU
+                let address = self.registers[rs1].wrapping_add(imm_i);
+
+                let value = match funct3 {
+                    0 => self.load(address, 1)? as i8 as u64,
+                    1 => self.load(address, 2)? as i16 as u64,
+                    2 => self.load(address, 4)? as i32 as u64,
+                    3 => self.load(address, 8)?,
+                    4 => self.load(address, 1)?,
+                    5 => self.load(address, 2)?,
+                    _ => self.load(address, 4)?,
+                };
+
+                self.set_register(rd, value);
+            }
+            (0..=3, 0b0100011) => {
+                // SB, SH, SW, SD
+                let address = self.registers[rs1].wrapping_add(imm_s);
+                self.store(address, 1 << funct3, self.registers[rs2])?;
+            }
+            (0, 0b0010011) => {
+                // ADDI
+                self.set_register(rd, self.registers[rs1].wrapping_add(imm_i));
+            }
+            (0, 0b1110011) => match imm11_0 {
+                0 => {
+                    // ECALL
+                    return Err(Error::Exception {
+                        exception: Exception::ecall(self.privilege),
+                        tval: 0,
+                    });
+                }
+                1 => {
+                    // EBREAK
+                    return Err(Error::Exception {
+                        exception: Exception::Breakpoint,
+                        tval: self.pc,
+                    });
+                }
+                0x102 => return self.sret(instruction),
+                0x302 => return self.mret(instruction),
+                0x105 => {
+                    // WFI, which can be trapped by mstatus.TW
+                    let trapped =
+                        self.privilege != Privilege::Machine && self.csrs.mstatus & MSTATUS_TW != 0;
+
+                    if self.privilege == Privilege::User || trapped {
+                        return Err(illegal_instruction(instruction));
+                    }
+
+                    self.waiting = true;
+                }
+                _ if instruction >> 25 == 0b000_1001 && rd == 0 => {
+                    // SFENCE.VMA
+                    self.sfence_vma(instruction)?;
+                }
+                _ => return Err(illegal_instruction(instruction)),
+            },
+            (1..=3 | 5..=7, 0b1110011) if self.has_extension("zicsr") => {
+                // CSRRW, CSRRS, CSRRC, CSRRWI, CSRRSI, CSRRCI
+                self.csr_instruction(instruction)?;
+            }
+            _ => return Err(illegal_instruction(instruction)),
+        }
+
+        // TODO: only works for 32 bit instructions
+        self.pc += 4;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::elf::{RiscvAttributes, Usize};
+
+    const ENTRY: usize = 0x1000;
+
+    /// Create a binary with the instructions at the entry point
+    fn elf(code: &[u32], endianness: Endianness, arch: &str) -> Elf {
+        Elf {
+            endianness,
+            entry: Usize::U64(ENTRY as u64),
+            segments: vec![Segment {
+                start: ENTRY,
+                protection: 0b101.into(),
+                data: code.iter().flat_map(|x| x.to_le_bytes()).collect(),
+            }],
+            stack_protection: 0b110.into(),
+            relro: None,
+            notes: Vec::new(),
+            attributes: Some(RiscvAttributes {
+                arch: Some(arch.into()),
+                ..Default::default()
+            }),
+            line_table: None,
+            symbols: Vec::new(),
+        }
+    }
+
+    /// Create a machine with the instructions loaded at the entry point
+    fn machine(code: &[u32], endianness: Endianness) -> Machine {
+        Machine::new(elf(code, endianness, "rv64i2p1_zicsr2p0")).unwrap()
+    }
+
+    // addi a0, zero, 5
+    const ADDI_A0_5: u32 = 0x0050_0513;
+
+    #[test]
+    fn little_endian() {
+        let mut machine = machine(&[ADDI_A0_5], Endianness::Little);
+
+        assert_eq!(machine.endianness(), Endianness::Little);
+
+        machine.cycle().unwrap();
+        assert_eq!(machine.registers()[10], 5);
+        assert_eq!(machine.pc(), ENTRY as u64 + 4);
+    }
+
+    // sd a1, 8(sp); lw a2, 12(sp); lbu a3, 15(sp); lb a4, 15(sp); ld a5, 8(sp)
+    const LOAD_STORE: [u32; 5] = [
+        0x00b1_3423,
+        0x00c1_2603,
+        0x00f1_4683,
+        0x00f1_0703,
+        0x0081_3783,
+    ];
+
+    #[test]
+    fn load_store() {
+        let mut machine = machine(&LOAD_STORE, Endianness::Little);
+        machine.registers[11] = 0x8123_4567_89ab_cdef;
+
+        for _ in LOAD_STORE {
+            machine.cycle().unwrap();
+        }
+
+        assert_eq!(machine.registers()[12], 0xffff_ffff_8123_4567);
+        assert_eq!(machine.registers()[13], 0x81);
+        assert_eq!(machine.registers()[14], 0xffff_ffff_ffff_ff81);
+        assert_eq!(machine.registers()[15], 0x8123_4567_89ab_cdef);
+    }
+
+    #[test]
+    fn load_store_big_endian() {
+        let mut machine = machine(&LOAD_STORE, Endianness::Big);
+        machine.registers[11] = 0x8123_4567_89ab_cdef;
+
+        for _ in LOAD_STORE {
+            machine.cycle().unwrap();
+        }
+
+        // the most significant bytes are stored first
+        let sp = machine.registers()[2] as usize;
+        assert_eq!(machine.memory().read_u8(sp + 8).unwrap(), 0x81);
+
+        assert_eq!(machine.registers()[12], 0xffff_ffff_89ab_cdef);
+        assert_eq!(machine.registers()[13], 0xef);
+        assert_eq!(machine.registers()[14], 0xffff_ffff_ffff_ffef);
+        assert_eq!(machine.registers()[15], 0x8123_4567_89ab_cdef);
+    }
+
+    #[test]
+    fn fetch_not_executable() {
+        let mut machine = machine(&[ADDI_A0_5], Endianness::Little);
+
+        // jump to the stack, which is not executable
+        machine.pc = machine.registers()[2];
+
+        assert!(matches!(
+            machine.cycle(),
+            Err(Error::Memory(vm::Error::InstructionAccessFault(..)))
+        ));
+    }
+
+    #[test]
+    fn brk() {
+        // li a7, 214; li a0, 0; ecall; addi a0, a0, 100; ecall; sb a0, -1(a0)
+        let code = [
+            0x0d60_0893,
+            0x0000_0513,
+            0x0000_0073,
+            0x0645_0513,
+            0x0000_0073,
+            0xfea5_0fa3,
+        ];
+        let mut machine = machine(&code, Endianness::Little);
+
+        for _ in code {
+            machine.cycle().unwrap();
+        }
+
+        // the heap starts at the page after the code
+        assert_eq!(machine.registers()[10], 0x2000 + 100);
+        assert_eq!(machine.memory().read(0x2000 + 99).unwrap(), 100);
+    }
+
+    #[test]
+    fn exit() {
+        // li a7, 93; li a0, 3; ecall
+        let mut machine = machine(&[0x05d0_0893, 0x0030_0513, 0x0000_0073], Endianness::Little);
+
+        assert_eq!(machine.cycle().unwrap(), None);
+        assert_eq!(machine.cycle().unwrap(), None);
+        assert_eq!(machine.cycle().unwrap(), Some(Stop::Exit(3)));
+    }
+
+    #[test]
+    fn unknown_syscall() {
+        // li a7, 1000; ecall
+        let mut machine = machine(&[0x3e80_0893, 0x0000_0073], Endianness::Little);
+
+        machine.cycle().unwrap();
+        machine.cycle().unwrap();
+
+        assert_eq!(machine.registers()[10] as i64, -ENOSYS);
+        assert_eq!(machine.pc(), ENTRY as u64 + 8);
+    }
+
+    #[test]
+    fn snapshot() {
+        let mut machine = machine(&LOAD_STORE, Endianness::Little);
+        machine.registers[11] = 0x1234;
+
+        let snapshot = machine.snapshot();
+
+        for _ in LOAD_STORE {
+            machine.cycle().unwrap();
+        }
+
+        // only the stack page was written
+        assert_eq!(machine.memory().dirty_pages(), 1);
+
+        machine.restore(&snapshot);
+
+        let sp = machine.registers()[2] as usize;
+        assert_eq!(machine.pc(), ENTRY as u64);
+        assert_eq!(machine.registers()[15], 0);
+        assert_eq!(machine.registers()[11], 0x1234);
+        assert_eq!(machine.memory().read_u64(sp + 8).unwrap(), 0);
+    }
+
+    #[test]
+    fn watchpoint() {
+        let mut machine = machine(&LOAD_STORE, Endianness::Little);
+        let sp = machine.registers()[2] as usize;
+
+        machine.add_watchpoint(sp + 12, 4, WatchKind::Read);
+        machine.add_watchpoint(sp + 15, 1, WatchKind::Write);
+        machine.add_watchpoint(ENTRY, 4, WatchKind::Execute);
+
+        assert_eq!(
+            machine.run().unwrap(),
+            Stop::Watchpoint {
+                addr: ENTRY,
+                kind: WatchKind::Execute,
+                pc: ENTRY as u64
+            }
+        );
+        assert_eq!(
+            machine.run().unwrap(),
+            Stop::Watchpoint {
+                addr: sp + 15,
+                kind: WatchKind::Write,
+                pc: ENTRY as u64
+            }
+        );
+        assert_eq!(
+            machine.run().unwrap(),
+            Stop::Watchpoint {
+                addr: sp + 12,
+                kind: WatchKind::Read,
+                pc: ENTRY as u64 + 4
+            }
+        );
+        assert_eq!(machine.pc(), ENTRY as u64 + 8);
+    }
+
+    // csrrw zero, satp, a1; csrrs a0, satp, zero; sfence.vma zero, zero
+    const CSR: [u32; 3] = [0x1805_9073, 0x1800_2573, 0x1200_0073];
+
+    #[test]
+    fn csr() {
+        let mut machine = machine(&CSR, Endianness::Little);
+        machine.set_privilege(Privilege::Machine);
+        machine.registers[11] = 8 << 60 | 0x100;
+
+        for _ in CSR {
+            machine.cycle().unwrap();
+        }
+
+        assert_eq!(machine.csrs().satp, 8 << 60 | 0x100);
+        assert_eq!(machine.registers()[10], 8 << 60 | 0x100);
+    }
+
+    #[test]
+    fn csr_user() {
+        let mut machine = machine(&CSR[1..], Endianness::Little);
+
+        assert!(matches!(
+            machine.cycle(),
+            Err(Error::Exception {
+                exception: Exception::IllegalInstruction,
+                tval: 0x1800_2573
+            })
+        ));
+
+        machine.pc += 4;
+        assert!(matches!(
+            machine.cycle(),
+            Err(Error::Exception {
+                exception: Exception::IllegalInstruction,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn csr_without_zicsr() {
+        let mut machine = Machine::new(elf(&CSR, Endianness::Little, "rv64i2p1")).unwrap();
+
+        assert!(matches!(
+            machine.cycle(),
+            Err(Error::Exception {
+                exception: Exception::IllegalInstruction,
+                tval: 0x1805_9073
+            })
+        ));
+    }
+
+    #[test]
+    fn unsupported_extensions() {
+        let elf = elf(&[ADDI_A0_5], Endianness::Little, "rv64i2p1_m2p0_zicsr2p0");
+
+        assert!(matches!(
+            Machine::new(elf),
+            Err(Error::UnsupportedExtensions(names)) if names == ["m"]
+        ));
+    }
+
+    #[test]
+    fn translated() {
+        // ld a0, 8(a1); sd a0, 0(a1)
+        let mut machine = machine(&[0x0085_b503, 0x00a5_b023], Endianness::Little);
+        machine.set_privilege(Privilege::Supervisor);
+
+        // the page tables at 0x10_0000 map the code at 0x1000 to itself, and
+        // the read-only 2 MiB page at 0x40_0000 to 0x40_0000
+        let mut tables = vec![0u8; 3 * vm::PAGE_SIZE];
+        let mut pte = |table: usize, index: usize, pte: u64| {
+            let offset = table * vm::PAGE_SIZE + index * 8;
+            tables[offset..offset + 8].copy_from_slice(&pte.to_le_bytes());
+        };
+        pte(0, 0, 0x101 << 10 | 0b1);
+        pte(1, 0, 0x102 << 10 | 0b1);
+        pte(1, 2, 0x400 << 10 | 0b11);
+        pte(2, 1, 0x1 << 10 | 0b1001);
+
+        for (start, data) in [(0x10_0000, tables), (0x40_0000, vec![7; 16])] {
+            machine
+                .memory
+                .insert(Segment {
+                    start,
+                    protection: 0b110.into(),
+                    data,
+                })
+                .unwrap();
+        }
+
+        machine.csrs.satp = 8 << 60 | 0x100;
+        machine.registers[11] = 0x40_0000;
+
+        machine.cycle().unwrap();
+        assert_eq!(machine.registers()[10], 0x0707_0707_0707_0707);
+
+        assert!(matches!(
+            machine.cycle(),
+            Err(Error::Exception {
+                exception: Exception::StorePageFault,
+                tval: 0x40_0000
+            })
+        ));
+    }
+
+    #[test]
+    fn write_zero_register() {
+        // addi zero, zero, 5
+        let mut machine = machine(&[0x0050_0013], Endianness::Little);
+
+        machine.cycle().unwrap();
+        assert_eq!(machine.registers()[0], 0);
+    }
+
+    /// Create a machine in machine mode with the instructions loaded at the entry point
+    fn system(code: &[u32]) -> Machine {
+        let elf = Elf {
+            endianness: Endianness::Little,
+            entry: Usize::U64(ENTRY as u64),
+            segments: vec![Segment {
+                start: ENTRY,
+                protection: 0b111.into(),
+                data: code.iter().flat_map(|x| x.to_le_bytes()).collect(),
+            }],
+            stack_protection: 0b110.into(),
+            relro: None,
+            notes: Vec::new(),
+            attributes: None,
+            line_table: None,
+            symbols: Vec::new(),
+        };
+
+        Machine::new_system(elf).unwrap()
+    }
+
+    const ECALL: u32 = 0x0000_0073;
+    const MRET: u32 = 0x3020_0073;
+    const SRET: u32 = 0x1020_0073;
+
+    #[test]
+    fn ecall_machine() {
+        let mut machine = system(&[ECALL]);
+        machine.csrs.mtvec = 0x2000;
+
+        machine.cycle().unwrap();
+
+        assert_eq!(machine.pc(), 0x2000);
+        assert_eq!(machine.privilege(), Privilege::Machine);
+        assert_eq!(machine.csrs().mcause, 11);
+        assert_eq!(machine.csrs().mepc, ENTRY as u64);
+        assert_eq!(machine.csrs().mstatus & MSTATUS_MPP, MSTATUS_MPP);
+    }
+
+    #[test]
+    fn mret() {
+        let mut machine = system(&[MRET]);
+        machine.csrs.mepc = 0x3000;
+        machine.csrs.mstatus = 1 << MSTATUS_MPP_SHIFT | MSTATUS_MPIE | MSTATUS_MPRV;
+
+        machine.cycle().unwrap();
+
+        assert_eq!(machine.pc(), 0x3000);
+        assert_eq!(machine.privilege(), Privilege::Supervisor);
+        assert_eq!(machine.csrs().mstatus, MSTATUS_MIE | MSTATUS_MPIE);
+    }
+
+    #[test]
+    fn delegated_ecall() {
+        let mut machine = system(&[ECALL, SRET]);
+        machine.set_privilege(Privilege::User);
+        machine.csrs.medeleg = 1 << Exception::EcallFromUser as u64;
+        machine.csrs.stvec = ENTRY as u64 + 4;
+        machine.csrs.mstatus = MSTATUS_SIE;
+
+        // the ecall is handled in supervisor mode, with interrupts disabled
+        machine.cycle().unwrap();
+        assert_eq!(machine.pc(), ENTRY as u64 + 4);
+        assert_eq!(machine.privilege(), Privilege::Supervisor);
+        assert_eq!(machine.csrs().scause, 8);
+        assert_eq!(machine.csrs().sepc, ENTRY as u64);
+        assert_eq!(machine.csrs().mstatus, MSTATUS_SPIE);
+
+        machine.csrs.sepc += 4;
+        machine.cycle().unwrap();
+        assert_eq!(machine.pc(), ENTRY as u64 + 4);
+        assert_eq!(machine.privilege(), Privilege::User);
+        assert_eq!(machine.csrs().mstatus, MSTATUS_SIE | MSTATUS_SPIE);
+    }
+
+    #[test]
+    fn illegal_mret() {
+        let mut machine = system(&[MRET]);
+        machine.set_privilege(Privilege::User);
+        machine.csrs.mtvec = 0x2000;
+
+        machine.cycle().unwrap();
+
+        assert_eq!(machine.pc(), 0x2000);
+        assert_eq!(machine.privilege(), Privilege::Machine);
+        assert_eq!(machine.csrs().mcause, 2);
+        assert_eq!(machine.csrs().mtval, MRET as u64);
+        assert_eq!(machine.csrs().mstatus & MSTATUS_MPP, 0);
+    }
+
+    #[test]
+    fn access_fault() {
+        // ld a0, 8(a1)
+        let mut machine = system(&[0x0085_b503]);
+        machine.csrs.mtvec = 0x2000;
+        machine.registers[11] = 0x8000_0000;
+
+        machine.cycle().unwrap();
+
+        assert_eq!(machine.pc(), 0x2000);
+        assert_eq!(machine.csrs().mcause, 5);
+        assert_eq!(machine.csrs().mtval, 0x8000_0008);
+    }
+
+    const WFI: u32 = 0x1050_0073;
+
+    #[test]
+    fn timer_interrupt() {
+        let mut code = vec![WFI];
+        code.extend([ADDI_A0_5; 8]);
+        let mut machine = system(&code);
+        machine
+            .add_clint(crate::clint::CLINT_BASE, Clock::Instructions)
+            .unwrap();
+
+        // the vectored machine timer handler is at the 8th instruction
+        machine.csrs.mtvec = ENTRY as u64 | 1;
+        machine.csrs.mie = MTI;
+        machine.csrs.mstatus = MSTATUS_MIE;
+        machine
+            .memory
+            .write_u64(crate::clint::CLINT_BASE + 0x4000, 10)
+            .unwrap();
+
+        // the hart waits for the timer, without executing instructions
+        machine.cycle().unwrap();
+        machine.cycle().unwrap();
+        assert_eq!(machine.pc(), ENTRY as u64 + 4);
+        assert_eq!(machine.registers()[10], 0);
+
+        machine.cycle().unwrap();
+        assert_eq!(machine.pc(), ENTRY as u64 + 32);
+        assert_eq!(machine.registers()[10], 5);
+        assert_eq!(machine.csrs().mcause, 1 << 63 | 7);
+        assert_eq!(machine.csrs().mepc, ENTRY as u64 + 4);
+        assert_eq!(machine.csrs().mstatus, MSTATUS_MPIE | MSTATUS_MPP);
+
+        // the time csr shadows mtime; csrr a1, time
+        machine.pc = ENTRY as u64;
+        machine.memory.write_u32(ENTRY, 0xc010_25f3).unwrap();
+        machine.cycle().unwrap();
+        assert_eq!(machine.registers()[11], 12);
+    }
+
+    #[test]
+    fn delegated_interrupt() {
+        let mut machine = system(&[ADDI_A0_5; 3]);
+        machine.csrs.stvec = ENTRY as u64 + 8;
+        machine.csrs.mideleg = crate::csr::STI;
+        machine.csrs.mie = crate::csr::STI;
+        machine.csrs.mip = crate::csr::STI;
+
+        // supervisor interrupts are never taken in machine mode
+        machine.cycle().unwrap();
+        assert_eq!(machine.pc(), ENTRY as u64 + 4);
+
+        // and in supervisor mode only when enabled
+        machine.set_privilege(Privilege::Supervisor);
+        machine.cycle().unwrap();
+        assert_eq!(machine.pc(), ENTRY as u64 + 8);
+
+        machine.pc = ENTRY as u64;
+        machine.csrs.mstatus = MSTATUS_SIE;
+        machine.cycle().unwrap();
+        assert_eq!(machine.pc(), ENTRY as u64 + 12);
+        assert_eq!(machine.csrs().scause, 1 << 63 | 5);
+        assert_eq!(machine.csrs().sepc, ENTRY as u64);
+        assert_eq!(machine.csrs().mstatus, MSTATUS_SPIE | MSTATUS_SPP);
+    }
+
+    #[test]
+    fn external_interrupt() {
+        let mut machine = system(&[ADDI_A0_5; 2]);
+        let plic = machine.add_plic(crate::plic::PLIC_BASE).unwrap();
+        let line = crate::plic::Interrupt::new(plic, 10);
+
+        // source 10 is enabled for the machine context
+        let base = crate::plic::PLIC_BASE;
+        machine.memory.write_u32(base + 4 * 10, 1).unwrap();
+        machine.memory.write_u32(base + 0x2000, 1 << 10).unwrap();
+
+        machine.csrs.mtvec = ENTRY as u64 + 4;
+        machine.csrs.mie = MEI;
+        machine.csrs.mstatus = MSTATUS_MIE;
+        machine.set_privilege(Privilege::Supervisor);
+
+        line.set(true);
+        machine.cycle().unwrap();
+        assert_eq!(machine.pc(), ENTRY as u64 + 8);
+        assert_eq!(machine.csrs().mcause, 1 << 63 | 11);
+        assert_eq!(machine.csrs().mip & MEI, MEI);
+
+        // claiming the source lowers the external interrupt
+        assert_eq!(machine.memory.read_u32(base + 0x20_0004).unwrap(), 10);
+        machine.update_interrupts();
+        assert_eq!(machine.csrs().mip & MEI, 0);
+    }
+
+    /// Set the extension, function and first arguments of an SBI call
+    fn sbi_args(machine: &mut Machine, extension: u64, function: u64, args: &[u64]) {
+        machine.registers[17] = extension;
+        machine.registers[16] = function;
+        machine.registers[10..10 + args.len()].copy_from_slice(args);
+    }
+
+    #[test]
+    fn sbi_base() {
+        let mut machine = system(&[ECALL, ECALL]);
+        machine.start_supervisor();
+
+        // get_spec_version
+        sbi_args(&mut machine, 0x10, 0, &[]);
+        machine.cycle().unwrap();
+        assert_eq!(machine.pc(), ENTRY as u64 + 4);
+        assert_eq!(machine.privilege(), Privilege::Supervisor);
+        assert_eq!(machine.registers()[10], 0);
+        assert_eq!(machine.registers()[11], 1 << 24);
+
+        // the hypervisor fences are not supported
+        sbi_args(&mut machine, 0x5246_4e43, 5, &[]);
+        machine.cycle().unwrap();
+        assert_eq!(machine.registers()[10] as i64, sbi::ERR_NOT_SUPPORTED);
+    }
+
+    #[test]
+    fn sbi_timer() {
+        let mut code = vec![ECALL, WFI];
+        code.extend([ADDI_A0_5; 8]);
+        let mut machine = system(&code);
+        machine
+            .add_clint(crate::clint::CLINT_BASE, Clock::Instructions)
+            .unwrap();
+        machine.start_supervisor();
+
+        // the handler is after the WFI
+        machine.csrs.stvec = ENTRY as u64 + 16;
+        machine.csrs.mie = STI;
+        machine.csrs.mstatus = MSTATUS_SIE;
+
+        // set_timer
+        sbi_args(&mut machine, 0x5449_4d45, 0, &[10]);
+        machine.cycle().unwrap();
+        assert_eq!(machine.registers()[10], 0);
+        assert_eq!(machine.clint.as_ref().unwrap().borrow().mtimecmp(), 10);
+
+        // the comparator raises the supervisor timer interrupt
+        while machine.csrs().scause == 0 {
+            machine.cycle().unwrap();
+        }
+        assert_eq!(machine.csrs().scause, 1 << 63 | 5);
+        // the interrupt is taken after the WFI
+        assert_eq!(machine.csrs().sepc, ENTRY as u64 + 8);
+        assert_eq!(machine.pc(), ENTRY as u64 + 20);
+        assert_eq!(machine.csrs().mip & (STI | MTI), STI);
+    }
+
+    #[derive(Debug, Default)]
+    struct Console(Vec<u8>);
+
+    impl vm::Device for Console {
+        fn read(&mut self, offset: usize, _size: usize) -> Option<u64> {
+            // a byte is always ready
+            match offset {
+                0 => Some(b'x'.into()),
+                5 => Some(1),
+                _ => Some(0),
+            }
+        }
+
+        fn write(&mut self, offset: usize, _size: usize, value: u64) -> Option<()> {
+            if offset == 0 {
+                self.0.push(value as u8);
+            }
+            Some(())
+        }
+    }
+
+    #[test]
+    fn sbi_console() {
+        let mut machine = system(&[ECALL, ECALL, ECALL]);
+        machine.start_supervisor();
+
+        // without a console, no byte is received
+        machine.registers[11] = 7;
+        sbi_args(&mut machine, 2, 0, &[]);
+        machine.cycle().unwrap();
+        assert_eq!(machine.registers()[10], u64::MAX);
+        assert_eq!(machine.registers()[11], 7);
+
+        let console = Rc::new(RefCell::new(Console::default()));
+        machine
+            .add_device(0x1000_0000, 0x100, console.clone())
+            .unwrap();
+        machine.set_console(0x1000_0000);
+
+        sbi_args(&mut machine, 1, 0, &[b'o'.into()]);
+        machine.cycle().unwrap();
+        assert_eq!(console.borrow().0, b"o");
+
+        sbi_args(&mut machine, 2, 0, &[]);
+        machine.cycle().unwrap();
+        assert_eq!(machine.registers()[10], b'x'.into());
+    }
+
+    #[test]
+    fn sbi_ipi_hsm_reset() {
+        let mut machine = system(&[ECALL; 5]);
+        machine.start_supervisor();
+
+        // send_ipi to harts 0 and 1
+        sbi_args(&mut machine, 0x73_5049, 0, &[0b11, 0]);
+        machine.cycle().unwrap();
+        assert_eq!(machine.registers()[10], 0);
+        assert_eq!(machine.csrs().mip & SSI, SSI);
+
+        // hart_get_status of hart 0 and 1
+        sbi_args(&mut machine, 0x48_534d, 2, &[0]);
+        machine.cycle().unwrap();
+        assert_eq!(machine.registers()[10..12], [0, sbi::HART_STARTED]);
+
+        sbi_args(&mut machine, 0x48_534d, 2, &[1]);
+        machine.cycle().unwrap();
+        assert_eq!(machine.registers()[10] as i64, sbi::ERR_INVALID_PARAM);
+
+        // hart_start of the running hart
+        sbi_args(&mut machine, 0x48_534d, 0, &[0, ENTRY as u64, 0]);
+        machine.cycle().unwrap();
+        assert_eq!(machine.registers()[10] as i64, sbi::ERR_ALREADY_AVAILABLE);
+
+        // system_reset for a system failure stops the machine
+        sbi_args(&mut machine, 0x5352_5354, 0, &[0, 1]);
+        assert_eq!(machine.cycle().unwrap(), Some(Stop::Exit(1)));
+        assert_eq!(machine.pc(), ENTRY as u64 + 20);
+    }
+
+    #[test]
+    fn htif_exit() {
+        // sd a0, 0x100(zero) with a0 = 2 << 1 | 1, the second test failed
+        let mut machine = system(&[0x0050_0513, 0x10a0_3023]);
+        machine
+            .memory
+            .insert(Segment {
+                start: 0,
+                protection: 0b110.into(),
+                data: vec![0; 0x1000],
+            })
+            .unwrap();
+        machine.set_htif(Htif::new(0x100, None, None, Box::new(std::io::sink())));
+
+        assert_eq!(machine.cycle().unwrap(), None);
+        assert_eq!(machine.cycle().unwrap(), Some(Stop::Exit(2)));
+        assert_eq!(machine.memory.read_u64(0x100).unwrap(), 0);
+    }
+
+    #[test]
+    fn big_endian_data() {
+        let mut machine = machine(&[ADDI_A0_5], Endianness::Big);
+
+        // the data is big endian, but the instructions are still little endian
+        assert_eq!(machine.endianness(), Endianness::Big);
+
+        machine.cycle().unwrap();
+        assert_eq!(machine.registers()[10], 5);
+    }
+
+    #[test]
+    fn big_endian_memory() {
+        // sd a1, 8(sp); lw a2, 8(sp)
+        let mut machine = machine(&[0x00b1_3423, 0x0081_2603], Endianness::Big);
+        machine.registers[11] = 0x8123_4567_89ab_cdef;
+
+        machine.cycle().unwrap();
+        machine.cycle().unwrap();
+
+        let sp = machine.registers()[2] as usize;
+        let mut bytes = [0; 8];
+        machine.memory().read_slice(sp + 8, &mut bytes).unwrap();
+        assert_eq!(bytes, [0x81, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef]);
+
+        // the word at the lowest address holds the most significant half
+        assert_eq!(machine.registers()[12], 0xffff_ffff_8123_4567);
+    }
+}
@@ -1,109 +1,287 @@
-mod elf;
-mod vm;
+use risky::clint::Clock;
+use risky::htif::Htif;
+use risky::machine::{self, Stop};
+use risky::system::{self, Config, Drive};
+use risky::uart::{self, Serial};
+use risky::vm::WatchKind;
+use risky::{coredump, elf, Machine};
+use std::fs::File;
+use std::io::BufWriter;
 
-use vm::VirtualMemory;
+/// SIGSEGV, the signal recorded in core dumps of faulting guests
+const SIGSEGV: u16 = 11;
 
-#[derive(Debug)]
-pub enum Error {
-    Memory(vm::Error),
+fn usage() -> ! {
+    eprintln!(
+        "usage: risky [--trace] [--core <file>] [--watch <addr>[+<len>][:rwx]]... <program>\n\
+         \x20      risky --system [--sbi] [--memory <size>] [--clock instructions|host]\n\
+         \x20             [--serial stdio|pipe:<path>|file:<path>] [--uart <addr>]\n\
+         \x20             [--drive <image>[,ro][,snapshot]]... [--append <bootargs>]\n\
+         \x20             [--dtb <file>] [--watch ...] <program>\n\
+         \x20      risky --kernel <image> [--initrd <file>] [--append <bootargs>]\n\
+         \x20             [--memory, --clock, --serial, --drive ...]\n\
+         \x20      (a bare supervisor image, the emulated instructions are not enough for linux)"
+    );
+    std::process::exit(2);
 }
 
-type Word = u32;
-type Instruction = u32;
+/// Report an error of the system setup and exit
+fn system_error(err: system::Error) -> ! {
+    eprintln!("can't build the system: {:?}", err);
+    std::process::exit(1);
+}
+
+/// Report an error of the program setup and exit
+fn machine_error(err: machine::Error) -> ! {
+    eprintln!("can't run the program: {:?}", err);
+    std::process::exit(1);
+}
 
-#[derive(Debug)]
-pub struct Machine {
-    memory: VirtualMemory,
-    registers: [u64; 32],
-    pc: u64,
+/// Read a host file for the guest, like a raw image, or exit
+fn read_file(path: &str) -> Vec<u8> {
+    std::fs::read(path).unwrap_or_else(|err| {
+        eprintln!("can't read {}: {}", path, err);
+        std::process::exit(1);
+    })
 }
 
-impl Machine {
-    fn read_word(&self, address: u64) -> Result<Word, Error> {
-        let mut buf = [0; 4];
-        self.memory
-            .read_slice(self.pc as _, &mut buf)
-            .map_err(Error::Memory)?;
+/// The source location of an address for the reports, empty without debug info
+fn location(machine: &Machine, address: u64) -> String {
+    machine
+        .locate(address)
+        .map_or(String::new(), |location| format!(" ({})", location))
+}
 
-        Ok(Word::from_le_bytes(buf))
+/// Parse a decimal or 0x prefixed hexadecimal number
+fn parse_number(s: &str) -> Option<usize> {
+    match s.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
     }
+}
+
+/// Parse a size like `256M`, with an optional K, M or G suffix
+fn parse_size(s: &str) -> Option<usize> {
+    let (number, shift) = match s.strip_suffix(['K', 'M', 'G']) {
+        Some(number) => (number, 10 * (1 + "KMG".find(s.chars().last()?)?)),
+        None => (s, 0),
+    };
+
+    parse_number(number)?.checked_mul(1 << shift)
+}
+
+/// Parse a watchpoint like `0x11000+8:rw`, the length defaults to 1 and the
+/// accesses to writes
+fn parse_watch(s: &str) -> Option<(usize, usize, Vec<WatchKind>)> {
+    let (range, kinds) = s.split_once(':').unwrap_or((s, "w"));
+    let (addr, len) = range.split_once('+').unwrap_or((range, "1"));
+
+    let kinds = kinds
+        .chars()
+        .map(|c| match c {
+            'r' => Some(WatchKind::Read),
+            'w' => Some(WatchKind::Write),
+            'x' => Some(WatchKind::Execute),
+            _ => None,
+        })
+        .collect::<Option<Vec<_>>>()?;
+
+    Some((parse_number(addr)?, parse_number(len)?, kinds))
+}
+
+/// Parse a drive like `disk.img,ro,snapshot`, with the read-only and the
+/// copy-on-write overlay options
+fn parse_drive(s: &str) -> Option<Drive> {
+    let mut parts = s.split(',');
+    let path = parts.next().filter(|path| !path.is_empty())?;
 
-    /// fetch an instruction from pc
-    /// only supports the 32 bit instructions for now
-    fn fetch_instruction(&self) -> Result<Instruction, Error> {
-        let word = self.read_word(self.pc)?;
-        Ok(word)
+    let mut drive = Drive {
+        path: path.into(),
+        read_only: false,
+        overlay: false,
+    };
+    for option in parts {
+        match option {
+            "ro" => drive.read_only = true,
+            "snapshot" => drive.overlay = true,
+            _ => return None,
+        }
     }
 
-    pub fn cycle(&mut self) -> Result<(), Error> {
-        let instruction = self.fetch_instruction()?;
-
-        let opcode = instruction & 0b111_1111;
-        let funct3 = (instruction >> 12) & 0b111;
-        let imm11_0 = instruction >> 20;
-        let rd = ((instruction >> 7) & 0b1_1111) as usize;
-        let rs1 = ((instruction >> 15) & 0b1_1111) as usize;
-
-        println!(
-            "instr : {} {:#x} {:#b}",
-            instruction, instruction, instruction
-        );
-        println!("opcode: {} {:#x} {:#b}", opcode, opcode, opcode);
-        println!("funct3: {} {:#x} {:#b}", funct3, funct3, funct3);
-        println!("imm110: {} {:#x} {:#b}", imm11_0, imm11_0, imm11_0);
-        println!("rd    : {} {:#x} {:#b}", rd, rd, rd);
-        println!("rs1   : {} {:#x} {:#b}", rs1, rs1, rs1);
-
-        match (funct3, opcode) {
-            (0, 0b0010011) => {
-                // ADDI
-                self.registers[rd] = self.registers[rs1].wrapping_add(imm11_0 as _);
+    Some(drive)
+}
+
+fn main() {
+    let mut path = None;
+    let mut core_path = None;
+    let mut system = false;
+    let mut sbi = false;
+    let mut config = Config::default();
+    let mut dtb_path = None;
+    let mut kernel_path = None;
+    let mut initrd_path = None;
+    let mut watchpoints = Vec::new();
+    let mut trace = false;
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--system" => system = true,
+            "--sbi" => sbi = true,
+            "--memory" => {
+                let size = args.next().as_deref().and_then(parse_size);
+                config.ram_size = size.unwrap_or_else(|| usage());
             }
-            (0, 0b1110011) => {
-                match imm11_0 {
-                    0 => {
-                        // ECALL
-                        match self.registers[17] {
-                            93 => {
-                                std::process::exit(self.registers[10].try_into().unwrap());
-                            }
-                            _ => unimplemented!(),
-                        }
-                    }
-                    _ => unimplemented!(),
+            "--clock" => {
+                config.clock = match args.next().as_deref() {
+                    Some("instructions") => Clock::Instructions,
+                    Some("host") => Clock::Host,
+                    _ => usage(),
                 }
             }
-            _ => unimplemented!(),
+            "--serial" => {
+                let backend = args.next().and_then(|arg| arg.parse().ok());
+                config.serial = backend.unwrap_or_else(|| usage());
+            }
+            "--uart" => {
+                let base = args.next().as_deref().and_then(parse_number);
+                config.uart_base = base.unwrap_or_else(|| usage());
+            }
+            "--drive" => {
+                let drive = args.next().as_deref().and_then(parse_drive);
+                config.drives.push(drive.unwrap_or_else(|| usage()));
+            }
+            "--append" => config.bootargs = Some(args.next().unwrap_or_else(|| usage())),
+            "--kernel" => kernel_path = Some(args.next().unwrap_or_else(|| usage())),
+            "--initrd" => initrd_path = Some(args.next().unwrap_or_else(|| usage())),
+            "--dtb" => dtb_path = Some(args.next().unwrap_or_else(|| usage())),
+            "--trace" => trace = true,
+            "--core" => core_path = Some(args.next().unwrap_or_else(|| usage())),
+            "--watch" => {
+                let watch = args.next().as_deref().and_then(parse_watch);
+                watchpoints.push(watch.unwrap_or_else(|| usage()));
+            }
+            _ if arg.starts_with('-') => usage(),
+            _ if path.is_none() => path = Some(arg),
+            _ => usage(),
         }
+    }
 
-        // TODO: only works for 32 bit instructions
-        self.pc += 4;
+    // a raw supervisor image is started in system mode on the built-in SBI, instead
+    // of a program
+    if kernel_path.is_some() {
+        if path.is_some() {
+            usage();
+        }
+        system = true;
+        sbi = true;
+    } else if initrd_path.is_some() {
+        usage();
+    }
 
-        Ok(())
+    let elf = match &kernel_path {
+        Some(_) => None,
+        None => Some(elf::read_elf(path.unwrap_or_else(|| usage())).unwrap()),
+    };
+
+    // programs with a `tohost` symbol talk to the host through HTIF, like
+    // riscv-tests; the UART owns the standard input when it is on the terminal
+    let htif = elf.as_ref().and_then(|elf| {
+        let tohost = elf.symbol("tohost")?.value as usize;
+        let fromhost = elf.symbol("fromhost").map(|symbol| symbol.value as usize);
+        let input = (!system || config.serial != Serial::Stdio).then(uart::stdin_reader);
+
+        Some(Htif::new(
+            tohost,
+            fromhost,
+            input,
+            Box::new(std::io::stdout()),
+        ))
+    });
+
+    // in system mode the program runs in machine mode and handles its own traps
+    let mut machine = match elf {
+        Some(elf) if system => Machine::new_system(elf).unwrap_or_else(|err| machine_error(err)),
+        Some(elf) => Machine::new(elf).unwrap_or_else(|err| machine_error(err)),
+        None => Machine::new_bare(),
+    };
+    machine.set_trace(trace);
+
+    if let Some(htif) = htif {
+        machine.set_htif(htif);
     }
-}
 
-fn main() {
-    // let path = std::env::args().nth(1).expect("excpected filename");
-    let path = "samples/nostd/target/riscv64gc-unknown-linux-gnu/release/nostd";
+    if system {
+        system::build(&mut machine, &config).unwrap_or_else(|err| system_error(err));
 
-    let elf = elf::read_elf(path).unwrap();
+        if let Some(kernel_path) = &kernel_path {
+            let image = read_file(kernel_path);
+            let (_, kernel_end) = system::load_kernel(&mut machine, &config, &image)
+                .unwrap_or_else(|err| system_error(err));
 
-    let segments = elf.segments.into_iter();
-    let vm = VirtualMemory::try_from_iter(segments).unwrap();
+            if let Some(initrd_path) = &initrd_path {
+                let initrd = read_file(initrd_path);
+                let range = system::place_initrd(&mut machine, &config, &initrd, kernel_end)
+                    .unwrap_or_else(|err| system_error(err));
+                config.initrd = Some(range);
+            }
+        }
 
-    println!("{:#?}", vm);
+        // the device tree is passed to the program, and can be dumped for inspection
+        let dtb = system::device_tree(&config, &machine.isa());
+        system::place_device_tree(&mut machine, &config, &dtb)
+            .unwrap_or_else(|err| system_error(err));
+        if let Some(dtb_path) = dtb_path {
+            std::fs::write(dtb_path, &dtb).unwrap();
+        }
 
-    let mut machine = Machine {
-        memory: vm,
-        registers: Default::default(),
-        pc: elf.entry.into(),
-    };
+        // a supervisor mode program runs on the built-in SBI, with its console on the UART
+        if sbi {
+            machine.start_supervisor();
+            machine.set_console(config.uart_base);
+        }
 
-    machine.cycle().unwrap();
-    machine.cycle().unwrap();
+        if config.serial == Serial::Stdio {
+            uart::raw_terminal();
+        }
+    }
 
-    println!("{:?}", machine.registers);
+    for (addr, len, kinds) in watchpoints {
+        for kind in kinds {
+            machine.add_watchpoint(addr, len, kind);
+        }
+    }
+
+    loop {
+        match machine.run() {
+            // report the hit and keep running
+            Ok(Stop::Watchpoint { addr, kind, pc }) => {
+                eprintln!(
+                    "watchpoint: {} of {:#x} at {:#x}{}",
+                    kind,
+                    addr,
+                    pc,
+                    location(&machine, pc)
+                );
+            }
+            Ok(Stop::Exit(status)) => {
+                uart::restore_terminal();
+                std::process::exit(status);
+            }
+            Err(err) => {
+                uart::restore_terminal();
 
-    machine.cycle().unwrap();
+                let pc = machine.pc();
+                eprintln!("fault at {:#x}{}: {:?}", pc, location(&machine, pc), err);
+
+                if let Some(core_path) = core_path {
+                    let file = File::create(&core_path).unwrap();
+                    coredump::write_core(BufWriter::new(file), &machine, SIGSEGV).unwrap();
+                    eprintln!("core dumped to {}", core_path);
+                }
+
+                std::process::exit(1);
+            }
+        }
+    }
 }
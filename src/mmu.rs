@@ -0,0 +1,617 @@
+use crate::trap::{Exception, Privilege};
+use crate::vm::VirtualMemory;
+use std::collections::HashMap;
+
+const PTE_V: u64 = 1 << 0;
+const PTE_R: u64 = 1 << 1;
+const PTE_W: u64 = 1 << 2;
+const PTE_X: u64 = 1 << 3;
+const PTE_U: u64 = 1 << 4;
+const PTE_G: u64 = 1 << 5;
+const PTE_A: u64 = 1 << 6;
+const PTE_D: u64 = 1 << 7;
+
+/// The reserved bits, and the bits of Svnapot and Svpbmt which are not supported
+const PTE_RESERVED: u64 = 0x3ff << 54;
+
+const PPN_MASK: u64 = (1 << 44) - 1;
+
+const ASID_MASK: u64 = 0xffff << 44;
+
+/// Translations are cached until SFENCE.VMA, the cache is cleared when it
+/// holds this many pages. They are tagged with the mode and root of satp and
+/// the ASID, so writing satp doesn't flush them
+const TLB_SIZE: usize = 4096;
+
+/// The kind of an access that is translated
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Fetch,
+    Load,
+    Store,
+}
+
+impl Access {
+    fn page_fault(self) -> Exception {
+        match self {
+            Self::Fetch => Exception::InstructionPageFault,
+            Self::Load => Exception::LoadPageFault,
+            Self::Store => Exception::StorePageFault,
+        }
+    }
+
+    fn access_fault(self) -> Exception {
+        match self {
+            Self::Fetch => Exception::InstructionAccessFault,
+            Self::Load => Exception::LoadAccessFault,
+            Self::Store => Exception::StoreAccessFault,
+        }
+    }
+}
+
+/// The translation schemes of satp.MODE
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Bare,
+    Sv39,
+    Sv48,
+    Sv57,
+}
+
+impl Mode {
+    /// Decode satp.MODE, `None` if the scheme is not supported
+    pub fn from_satp(satp: u64) -> Option<Self> {
+        match satp >> 60 {
+            0 => Some(Self::Bare),
+            8 => Some(Self::Sv39),
+            9 => Some(Self::Sv48),
+            10 => Some(Self::Sv57),
+            _ => None,
+        }
+    }
+
+    /// The number of page table levels
+    fn levels(self) -> u32 {
+        match self {
+            Self::Bare => 0,
+            Self::Sv39 => 3,
+            Self::Sv48 => 4,
+            Self::Sv57 => 5,
+        }
+    }
+}
+
+/// The state the translation of an access depends on, besides the page tables
+#[derive(Debug, Clone, Copy)]
+pub struct Context {
+    pub satp: u64,
+    /// the effective privilege of the access, with mstatus.MPRV applied
+    pub privilege: Privilege,
+    /// mstatus.SUM, supervisor loads and stores can access user pages
+    pub sum: bool,
+    /// mstatus.MXR, loads can read executable pages
+    pub mxr: bool,
+}
+
+impl Context {
+    fn mode(&self) -> Mode {
+        // satp only holds supported modes
+        Mode::from_satp(self.satp).unwrap_or(Mode::Bare)
+    }
+
+    fn asid(&self) -> u16 {
+        (self.satp >> 44) as u16
+    }
+
+    /// satp without the ASID, the mode and the root table of the translation
+    fn root(&self) -> u64 {
+        self.satp & !ASID_MASK
+    }
+
+    /// Check if addresses are translated, they are not in machine mode
+    pub fn is_translated(&self) -> bool {
+        self.privilege != Privilege::Machine && self.mode() != Mode::Bare
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct TlbEntry {
+    /// the mode and root table of satp
+    root: u64,
+    asid: u16,
+    /// the physical page number of the 4 KiB page, even in a superpage
+    ppn: u64,
+    /// the leaf PTE, with the A and D bits that were set
+    pte: u64,
+    /// the level of the leaf, superpages have a level above 0
+    level: u32,
+}
+
+/// Check the permissions of a leaf PTE for an access
+fn check_permissions(pte: u64, context: Context, access: Access) -> Result<(), Exception> {
+    let allowed = match access {
+        Access::Fetch => pte & PTE_X != 0,
+        Access::Load => pte & PTE_R != 0 || (context.mxr && pte & PTE_X != 0),
+        Access::Store => pte & PTE_W != 0,
+    };
+
+    let user_page = pte & PTE_U != 0;
+    let privileged = match context.privilege {
+        Privilege::User => user_page,
+        // supervisor code is never executed from user pages
+        Privilege::Supervisor => !user_page || (context.sum && access != Access::Fetch),
+        Privilege::Machine => true,
+    };
+
+    if allowed && privileged {
+        Ok(())
+    } else {
+        Err(access.page_fault())
+    }
+}
+
+/// Translates virtual addresses with the page tables in physical memory
+#[derive(Debug, Default)]
+pub struct Mmu {
+    /// cached translations by virtual page number
+    tlb: HashMap<u64, TlbEntry>,
+}
+
+impl Mmu {
+    /// Translate a virtual address to a physical address. The page faults and the
+    /// access faults of the page table walk are returned as exceptions
+    pub fn translate(
+        &mut self,
+        memory: &mut VirtualMemory,
+        context: Context,
+        vaddr: u64,
+        access: Access,
+    ) -> Result<u64, Exception> {
+        if !context.is_translated() {
+            return Ok(vaddr);
+        }
+
+        let vpn = vaddr >> 12;
+        let offset = vaddr & 0xfff;
+
+        if let Some(entry) = self.tlb.get(&vpn) {
+            let global = entry.pte & PTE_G != 0;
+            let dirty = access != Access::Store || entry.pte & PTE_D != 0;
+
+            // the first store to a page walks again, to set the dirty bit
+            if entry.root == context.root() && (global || entry.asid == context.asid()) && dirty {
+                check_permissions(entry.pte, context, access)?;
+                return Ok(entry.ppn << 12 | offset);
+            }
+        }
+
+        let entry = Self::walk(memory, context, vaddr, access)?;
+
+        if self.tlb.len() >= TLB_SIZE {
+            self.tlb.clear();
+        }
+        self.tlb.insert(vpn, entry);
+
+        Ok(entry.ppn << 12 | offset)
+    }
+
+    /// Walk the page tables, and set the A and D bits of the leaf like hardware
+    /// that updates them
+    fn walk(
+        memory: &mut VirtualMemory,
+        context: Context,
+        vaddr: u64,
+        access: Access,
+    ) -> Result<TlbEntry, Exception> {
+        let levels = context.mode().levels();
+
+        // the bits above the virtual address have to be copies of its highest bit
+        let unused = 64 - (12 + 9 * levels);
+        if ((vaddr << unused) as i64 >> unused) as u64 != vaddr {
+            return Err(access.page_fault());
+        }
+
+        let mut table = (context.satp & PPN_MASK) << 12;
+
+        for level in (0..levels).rev() {
+            let index = (vaddr >> (12 + 9 * level)) & 0x1ff;
+            let pte_addr = (table + index * 8) as usize;
+
+            let pte = memory
+                .read_u64_implicit(pte_addr)
+                .map_err(|_| access.access_fault())?;
+
+            if pte & PTE_V == 0 || (pte & PTE_R == 0 && pte & PTE_W != 0) || pte & PTE_RESERVED != 0
+            {
+                return Err(access.page_fault());
+            }
+
+            let ppn = (pte >> 10) & PPN_MASK;
+
+            if pte & (PTE_R | PTE_X) == 0 {
+                // a pointer to the next level, where A, D and U are reserved
+                if pte & (PTE_A | PTE_D | PTE_U) != 0 {
+                    return Err(access.page_fault());
+                }
+
+                table = ppn << 12;
+                continue;
+            }
+
+            check_permissions(pte, context, access)?;
+
+            // superpages have to be aligned to their size
+            let superpage_mask = (1 << (9 * level)) - 1;
+            if ppn & superpage_mask != 0 {
+                return Err(access.page_fault());
+            }
+
+            let mut updated = pte | PTE_A;
+            if access == Access::Store {
+                updated |= PTE_D;
+            }
+
+            if updated != pte {
+                memory
+                    .write_u64_implicit(pte_addr, updated)
+                    .map_err(|_| access.access_fault())?;
+            }
+
+            return Ok(TlbEntry {
+                root: context.root(),
+                asid: context.asid(),
+                ppn: ppn | (vaddr >> 12) & superpage_mask,
+                pte: updated,
+                level,
+            });
+        }
+
+        // the last level can't point to another table
+        Err(access.page_fault())
+    }
+
+    /// Invalidate cached translations like SFENCE.VMA. Without an address every
+    /// page is invalidated, and without an ASID every address space is. The
+    /// translations of global pages are kept when an ASID is given
+    pub fn flush(&mut self, vaddr: Option<u64>, asid: Option<u16>) {
+        self.tlb.retain(|&vpn, entry| {
+            let address = vaddr.is_none_or(|vaddr| (vpn ^ vaddr >> 12) >> (9 * entry.level) == 0);
+            let space = asid.is_none_or(|asid| entry.pte & PTE_G == 0 && entry.asid == asid);
+
+            !(address && space)
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::elf::Segment;
+    use crate::vm::WatchKind;
+
+    const SV39: u64 = 8 << 60;
+    const SV48: u64 = 9 << 60;
+    const SV57: u64 = 10 << 60;
+
+    /// Physical memory, with the root table at 0x1000
+    fn memory() -> VirtualMemory {
+        VirtualMemory::try_from_iter([Segment {
+            start: 0,
+            protection: 0b111.into(),
+            data: vec![0; 0x10000],
+        }])
+        .unwrap()
+    }
+
+    fn pte(memory: &mut VirtualMemory, table: u64, index: u64, ppn: u64, flags: u64) {
+        memory
+            .write_u64((table + index * 8) as usize, ppn << 10 | flags)
+            .unwrap();
+    }
+
+    fn read_pte(memory: &VirtualMemory, table: u64, index: u64) -> u64 {
+        memory.read_u64((table + index * 8) as usize).unwrap()
+    }
+
+    fn context(satp: u64, privilege: Privilege) -> Context {
+        Context {
+            satp,
+            privilege,
+            sum: false,
+            mxr: false,
+        }
+    }
+
+    /// Map the virtual page 0x40_1000 to the physical page 0x8000, through the
+    /// tables at 0x1000, 0x2000 and 0x3000
+    fn sv39(flags: u64) -> VirtualMemory {
+        let mut memory = memory();
+
+        pte(&mut memory, 0x1000, 0, 0x2, PTE_V);
+        pte(&mut memory, 0x2000, 2, 0x3, PTE_V);
+        pte(&mut memory, 0x3000, 1, 0x8, PTE_V | flags);
+
+        memory
+    }
+
+    #[test]
+    fn bare() {
+        let mut mmu = Mmu::default();
+        let mut memory = memory();
+
+        let context = context(0, Privilege::Supervisor);
+        assert_eq!(
+            mmu.translate(&mut memory, context, 0x1234, Access::Load),
+            Ok(0x1234)
+        );
+
+        // machine mode is not translated
+        let context = self::context(SV39 | 1, Privilege::Machine);
+        assert_eq!(
+            mmu.translate(&mut memory, context, 0x1234, Access::Store),
+            Ok(0x1234)
+        );
+    }
+
+    #[test]
+    fn sv39_page() {
+        let mut mmu = Mmu::default();
+        let mut memory = sv39(PTE_R | PTE_W);
+        let context = context(SV39 | 1, Privilege::Supervisor);
+
+        assert_eq!(
+            mmu.translate(&mut memory, context, 0x40_1234, Access::Load),
+            Ok(0x8234)
+        );
+        assert_eq!(read_pte(&memory, 0x3000, 1) & (PTE_A | PTE_D), PTE_A);
+
+        assert_eq!(
+            mmu.translate(&mut memory, context, 0x40_1238, Access::Store),
+            Ok(0x8238)
+        );
+        assert_eq!(
+            read_pte(&memory, 0x3000, 1) & (PTE_A | PTE_D),
+            PTE_A | PTE_D
+        );
+
+        // the neighbouring pages are not mapped
+        assert_eq!(
+            mmu.translate(&mut memory, context, 0x40_2000, Access::Fetch),
+            Err(Exception::InstructionPageFault)
+        );
+        assert_eq!(
+            mmu.translate(&mut memory, context, 0x40_0000, Access::Load),
+            Err(Exception::LoadPageFault)
+        );
+    }
+
+    #[test]
+    fn walk_unwatched() {
+        let mut mmu = Mmu::default();
+        let mut memory = sv39(PTE_R | PTE_W);
+        let context = context(SV39 | 1, Privilege::Supervisor);
+
+        memory.add_watchpoint(0x1000, 0x3000, WatchKind::Read);
+        memory.add_watchpoint(0x1000, 0x3000, WatchKind::Write);
+
+        assert_eq!(
+            mmu.translate(&mut memory, context, 0x40_1238, Access::Store),
+            Ok(0x8238)
+        );
+        assert_eq!(memory.take_watchpoint_hits(), []);
+    }
+
+    #[test]
+    fn sv39_superpage() {
+        let mut mmu = Mmu::default();
+        let mut memory = memory();
+        let context = context(SV39 | 1, Privilege::Supervisor);
+
+        // a 2 MiB page at 0x20_0000 and a misaligned one at 0x40_0000
+        pte(&mut memory, 0x1000, 0, 0x2, PTE_V);
+        pte(&mut memory, 0x2000, 1, 0x200, PTE_V | PTE_R);
+        pte(&mut memory, 0x2000, 2, 0x201, PTE_V | PTE_R);
+
+        assert_eq!(
+            mmu.translate(&mut memory, context, 0x21_2345, Access::Load),
+            Ok(0x21_2345)
+        );
+        assert_eq!(
+            mmu.translate(&mut memory, context, 0x40_0000, Access::Load),
+            Err(Exception::LoadPageFault)
+        );
+    }
+
+    #[test]
+    fn sv48_sv57() {
+        let mut mmu = Mmu::default();
+        let mut memory = memory();
+
+        // a 512 GiB page at 0 for sv48, and 256 TiB page at 0 for sv57
+        pte(&mut memory, 0x1000, 0, 0, PTE_V | PTE_R);
+
+        let context = context(SV48 | 1, Privilege::Supervisor);
+        assert_eq!(
+            mmu.translate(&mut memory, context, 0x12_3456_789a, Access::Load),
+            Ok(0x12_3456_789a)
+        );
+        assert_eq!(
+            mmu.translate(&mut memory, context, 0x8000_0000_0000, Access::Load),
+            Err(Exception::LoadPageFault)
+        );
+
+        let context = self::context(SV57 | 1, Privilege::Supervisor);
+        assert_eq!(
+            mmu.translate(&mut memory, context, 0x8000_0000_0000, Access::Load),
+            Ok(0x8000_0000_0000)
+        );
+    }
+
+    #[test]
+    fn non_canonical() {
+        let mut mmu = Mmu::default();
+        let mut memory = sv39(PTE_R);
+        let context = context(SV39 | 1, Privilege::Supervisor);
+
+        assert_eq!(
+            mmu.translate(&mut memory, context, 1 << 39 | 0x40_1000, Access::Load),
+            Err(Exception::LoadPageFault)
+        );
+    }
+
+    #[test]
+    fn reserved_encodings() {
+        let mut mmu = Mmu::default();
+        let context = context(SV39 | 1, Privilege::Supervisor);
+
+        for flags in [PTE_W, PTE_R | 1 << 61, 0] {
+            let mut memory = sv39(flags);
+            assert_eq!(
+                mmu.translate(&mut memory, context, 0x40_1000, Access::Store),
+                Err(Exception::StorePageFault)
+            );
+        }
+
+        // the table can't be outside physical memory
+        let mut memory = memory();
+        pte(&mut memory, 0x1000, 0, 0x100_0000, PTE_V);
+        assert_eq!(
+            mmu.translate(&mut memory, context, 0x1000, Access::Fetch),
+            Err(Exception::InstructionAccessFault)
+        );
+    }
+
+    #[test]
+    fn permissions() {
+        let mut mmu = Mmu::default();
+        let supervisor = context(SV39 | 1, Privilege::Supervisor);
+        let user = context(SV39 | 1, Privilege::User);
+
+        let mut memory = sv39(PTE_R);
+        assert!(mmu
+            .translate(&mut memory, supervisor, 0x40_1000, Access::Load)
+            .is_ok());
+        assert_eq!(
+            mmu.translate(&mut memory, supervisor, 0x40_1000, Access::Store),
+            Err(Exception::StorePageFault)
+        );
+        assert_eq!(
+            mmu.translate(&mut memory, user, 0x40_1000, Access::Load),
+            Err(Exception::LoadPageFault)
+        );
+
+        // loads from execute-only pages need MXR
+        let mut memory = sv39(PTE_X);
+        let mut mmu = Mmu::default();
+        assert_eq!(
+            mmu.translate(&mut memory, supervisor, 0x40_1000, Access::Load),
+            Err(Exception::LoadPageFault)
+        );
+        let mxr = Context {
+            mxr: true,
+            ..supervisor
+        };
+        assert!(mmu
+            .translate(&mut memory, mxr, 0x40_1000, Access::Load)
+            .is_ok());
+    }
+
+    #[test]
+    fn user_pages() {
+        let mut mmu = Mmu::default();
+        let mut memory = sv39(PTE_R | PTE_X | PTE_U);
+        let supervisor = context(SV39 | 1, Privilege::Supervisor);
+        let sum = Context {
+            sum: true,
+            ..supervisor
+        };
+        let user = context(SV39 | 1, Privilege::User);
+
+        assert!(mmu
+            .translate(&mut memory, user, 0x40_1000, Access::Fetch)
+            .is_ok());
+        assert_eq!(
+            mmu.translate(&mut memory, supervisor, 0x40_1000, Access::Load),
+            Err(Exception::LoadPageFault)
+        );
+        assert!(mmu
+            .translate(&mut memory, sum, 0x40_1000, Access::Load)
+            .is_ok());
+
+        // supervisor code never runs from user pages
+        assert_eq!(
+            mmu.translate(&mut memory, sum, 0x40_1000, Access::Fetch),
+            Err(Exception::InstructionPageFault)
+        );
+    }
+
+    #[test]
+    fn tlb() {
+        let mut mmu = Mmu::default();
+        let mut memory = sv39(PTE_R);
+        let context = context(SV39 | 1, Privilege::Supervisor);
+        let other = self::context(SV39 | 2 << 44 | 1, Privilege::Supervisor);
+
+        assert_eq!(
+            mmu.translate(&mut memory, context, 0x40_1000, Access::Load),
+            Ok(0x8000)
+        );
+
+        // the cached translation is used until it's invalidated
+        pte(&mut memory, 0x3000, 1, 0x9, PTE_V | PTE_R);
+        assert_eq!(
+            mmu.translate(&mut memory, context, 0x40_1000, Access::Load),
+            Ok(0x8000)
+        );
+
+        // but not by other address spaces
+        assert_eq!(
+            mmu.translate(&mut memory, other, 0x40_1000, Access::Load),
+            Ok(0x9000)
+        );
+
+        // or other page tables with the same ASID
+        let empty = self::context(SV39 | 4, Privilege::Supervisor);
+        assert_eq!(
+            mmu.translate(&mut memory, empty, 0x40_1000, Access::Load),
+            Err(Exception::LoadPageFault)
+        );
+
+        mmu.flush(Some(0x40_1fff), Some(0));
+        assert_eq!(
+            mmu.translate(&mut memory, context, 0x40_1000, Access::Load),
+            Ok(0x9000)
+        );
+    }
+
+    #[test]
+    fn flush() {
+        let mut mmu = Mmu::default();
+        let mut memory = memory();
+        let context = context(SV39 | 1, Privilege::Supervisor);
+
+        // a global 2 MiB page
+        pte(&mut memory, 0x1000, 0, 0x2, PTE_V);
+        pte(&mut memory, 0x2000, 1, 0x200, PTE_V | PTE_R | PTE_G);
+
+        mmu.translate(&mut memory, context, 0x20_0000, Access::Load)
+            .unwrap();
+        mmu.translate(&mut memory, context, 0x30_0000, Access::Load)
+            .unwrap();
+        pte(&mut memory, 0x2000, 1, 0x400, PTE_V | PTE_R | PTE_G);
+
+        // global pages are kept when flushing an address space
+        mmu.flush(None, Some(0));
+        assert_eq!(
+            mmu.translate(&mut memory, context, 0x30_0000, Access::Load),
+            Ok(0x30_0000)
+        );
+
+        // an address in a superpage invalidates all of it
+        mmu.flush(Some(0x20_0000), None);
+        assert_eq!(
+            mmu.translate(&mut memory, context, 0x30_0000, Access::Load),
+            Ok(0x50_0000)
+        );
+    }
+}
@@ -0,0 +1,276 @@
+use crate::vm::Device;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// The base address of the PLIC in the `virt` machine layout
+pub const PLIC_BASE: usize = 0xc00_0000;
+
+/// The size of the PLIC region, with room for the contexts of 15872 harts
+pub const PLIC_SIZE: usize = 0x400_0000;
+
+/// The interrupt sources, source 0 is reserved for "no interrupt"
+pub const NUM_SOURCES: usize = 32;
+
+/// The contexts of the single hart, for machine and supervisor mode
+pub const NUM_CONTEXTS: usize = 2;
+
+/// The register offsets of the SiFive layout
+const PRIORITY: usize = 0x0;
+const PENDING: usize = 0x1000;
+const ENABLE: usize = 0x2000;
+const ENABLE_STRIDE: usize = 0x80;
+const CONTEXT: usize = 0x20_0000;
+const CONTEXT_STRIDE: usize = 0x1000;
+
+/// The priorities are 3 bits, and 0 never interrupts
+const PRIORITY_MASK: u32 = 0b111;
+
+/// The platform level interrupt controller, which routes the level triggered
+/// interrupts of devices to the external interrupts of a hart
+#[derive(Debug, Default)]
+pub struct Plic {
+    priorities: [u32; NUM_SOURCES],
+    /// the levels of the interrupt lines
+    levels: u32,
+    pending: u32,
+    /// the claimed sources, which don't become pending until completed
+    claimed: u32,
+    enables: [u32; NUM_CONTEXTS],
+    thresholds: [u32; NUM_CONTEXTS],
+}
+
+/// The interrupt line of a device source
+#[derive(Debug, Clone)]
+pub struct Interrupt {
+    plic: Rc<RefCell<Plic>>,
+    source: usize,
+}
+
+impl Interrupt {
+    pub fn new(plic: Rc<RefCell<Plic>>, source: usize) -> Self {
+        assert!(
+            (1..NUM_SOURCES).contains(&source),
+            "invalid source {source}"
+        );
+        Self { plic, source }
+    }
+
+    pub fn source(&self) -> usize {
+        self.source
+    }
+
+    /// Drive the level of the line
+    pub fn set(&self, level: bool) {
+        self.plic.borrow_mut().set_level(self.source, level);
+    }
+}
+
+impl Plic {
+    /// Change the level of a source. The gateway makes a raised source pending,
+    /// unless it's claimed and waiting for the completion
+    pub fn set_level(&mut self, source: usize, level: bool) {
+        let bit = 1 << source;
+
+        if level {
+            self.levels |= bit;
+            if self.claimed & bit == 0 {
+                self.pending |= bit;
+            }
+        } else {
+            self.levels &= !bit;
+        }
+    }
+
+    /// The highest priority pending source that interrupts a context, the lowest
+    /// source wins among equal priorities
+    fn best(&self, context: usize) -> Option<usize> {
+        let candidates = self.pending & self.enables[context];
+
+        (1..NUM_SOURCES)
+            .filter(|&source| candidates & 1 << source != 0)
+            .filter(|&source| self.priorities[source] > self.thresholds[context])
+            .max_by_key(|&source| (self.priorities[source], std::cmp::Reverse(source)))
+    }
+
+    /// Tell if the external interrupt of a context is raised
+    pub fn interrupting(&self, context: usize) -> bool {
+        self.best(context).is_some()
+    }
+
+    /// Claim the best source of a context, 0 if there is none
+    fn claim(&mut self, context: usize) -> u32 {
+        let Some(source) = self.best(context) else {
+            return 0;
+        };
+
+        self.pending &= !(1 << source);
+        self.claimed |= 1 << source;
+
+        source as u32
+    }
+
+    /// Complete the handling of a source, a source still raised is pending again
+    fn complete(&mut self, context: usize, source: usize) {
+        // completions of sources that are not enabled for the context are ignored
+        if source >= NUM_SOURCES || self.enables[context] & 1 << source == 0 {
+            return;
+        }
+
+        let bit = 1 << source;
+
+        self.claimed &= !bit;
+        if self.levels & bit != 0 {
+            self.pending |= bit;
+        }
+    }
+}
+
+impl Device for Plic {
+    fn read(&mut self, offset: usize, size: usize) -> Option<u64> {
+        if size != 4 {
+            return None;
+        }
+
+        let value = match offset {
+            PRIORITY..=0xfff => {
+                let source = (offset - PRIORITY) / 4;
+                self.priorities.get(source).copied().unwrap_or(0)
+            }
+            PENDING => self.pending,
+            ENABLE..=0x1f_ffff => {
+                let context = (offset - ENABLE) / ENABLE_STRIDE;
+                let word = (offset - ENABLE) % ENABLE_STRIDE;
+                match (self.enables.get(context), word) {
+                    (Some(&enable), 0) => enable,
+                    _ => 0,
+                }
+            }
+            CONTEXT.. => {
+                let context = (offset - CONTEXT) / CONTEXT_STRIDE;
+                if context >= NUM_CONTEXTS {
+                    return Some(0);
+                }
+
+                match (offset - CONTEXT) % CONTEXT_STRIDE {
+                    0 => self.thresholds[context],
+                    4 => self.claim(context),
+                    _ => 0,
+                }
+            }
+            _ => 0,
+        };
+
+        Some(value.into())
+    }
+
+    fn write(&mut self, offset: usize, size: usize, value: u64) -> Option<()> {
+        if size != 4 {
+            return None;
+        }
+
+        let value = value as u32;
+
+        match offset {
+            PRIORITY..=0xfff => {
+                let source = (offset - PRIORITY) / 4;
+                // source 0 doesn't exist
+                if (1..NUM_SOURCES).contains(&source) {
+                    self.priorities[source] = value & PRIORITY_MASK;
+                }
+            }
+            ENABLE..=0x1f_ffff => {
+                let context = (offset - ENABLE) / ENABLE_STRIDE;
+                let word = (offset - ENABLE) % ENABLE_STRIDE;
+                if let (Some(enable), 0) = (self.enables.get_mut(context), word) {
+                    *enable = value & !1;
+                }
+            }
+            CONTEXT.. => {
+                let context = (offset - CONTEXT) / CONTEXT_STRIDE;
+                if context < NUM_CONTEXTS {
+                    match (offset - CONTEXT) % CONTEXT_STRIDE {
+                        0 => self.thresholds[context] = value & PRIORITY_MASK,
+                        4 => self.complete(context, value as usize),
+                        _ => {}
+                    }
+                }
+            }
+            // the pending bits are read-only
+            _ => {}
+        }
+
+        Some(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plic() -> Plic {
+        let mut plic = Plic::default();
+
+        // sources 1 and 2 enabled for supervisor mode, 3 for machine mode
+        for source in 1..4 {
+            plic.write(4 * source, 4, 1).unwrap();
+        }
+        plic.write(ENABLE, 4, 0b1000).unwrap();
+        plic.write(ENABLE + ENABLE_STRIDE, 4, 0b110).unwrap();
+
+        plic
+    }
+
+    #[test]
+    fn claim_complete() {
+        let mut plic = plic();
+        let claim = CONTEXT + CONTEXT_STRIDE + 4;
+
+        assert!(!plic.interrupting(1));
+        plic.set_level(2, true);
+        plic.set_level(1, true);
+        assert!(plic.interrupting(1));
+        assert!(!plic.interrupting(0));
+        assert_eq!(plic.read(PENDING, 4), Some(0b110));
+
+        // the lowest source wins among the same priority
+        assert_eq!(plic.read(claim, 4), Some(1));
+        assert_eq!(plic.read(claim, 4), Some(2));
+        assert_eq!(plic.read(claim, 4), Some(0));
+        assert!(!plic.interrupting(1));
+
+        // a source still raised is pending again after the completion
+        plic.set_level(1, false);
+        plic.write(claim, 4, 1).unwrap();
+        plic.write(claim, 4, 2).unwrap();
+        assert_eq!(plic.read(PENDING, 4), Some(0b100));
+    }
+
+    #[test]
+    fn priority_threshold() {
+        let mut plic = plic();
+        let context = CONTEXT + CONTEXT_STRIDE;
+
+        plic.write(4 * 2, 4, 0xff).unwrap();
+        assert_eq!(plic.read(4 * 2, 4), Some(7));
+
+        plic.set_level(1, true);
+        plic.set_level(2, true);
+
+        // only the sources above the threshold interrupt
+        plic.write(context, 4, 1).unwrap();
+        assert_eq!(plic.read(context + 4, 4), Some(2));
+        assert_eq!(plic.read(context + 4, 4), Some(0));
+        assert!(plic.pending & 0b10 != 0);
+    }
+
+    #[test]
+    fn interrupt_line() {
+        let plic = Rc::new(RefCell::new(plic()));
+        let line = Interrupt::new(plic.clone(), 3);
+
+        line.set(true);
+        assert!(plic.borrow().interrupting(0));
+        assert_eq!(plic.borrow_mut().read(CONTEXT + 4, 4), Some(3));
+        assert!(!plic.borrow().interrupting(0));
+    }
+}
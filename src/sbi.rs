@@ -0,0 +1,275 @@
+//! The supervisor binary interface, the calls of supervisor mode payloads to the
+//! firmware. Calls are decoded here, and carried out by the machine
+
+/// The errors of the calls, returned in a0
+pub const SUCCESS: i64 = 0;
+pub const ERR_FAILED: i64 = -1;
+pub const ERR_NOT_SUPPORTED: i64 = -2;
+pub const ERR_INVALID_PARAM: i64 = -3;
+pub const ERR_ALREADY_AVAILABLE: i64 = -6;
+
+/// Version 1.0 of the specification, the major version is in bits 24 to 30
+pub const SPEC_VERSION: u64 = 1 << 24;
+
+/// The implementation ID, not one of the registered ones
+pub const IMPL_ID: u64 = 0x7269_736b;
+
+/// The extension IDs
+const EXT_SET_TIMER: u64 = 0x00;
+const EXT_CONSOLE_PUTCHAR: u64 = 0x01;
+const EXT_CONSOLE_GETCHAR: u64 = 0x02;
+const EXT_CLEAR_IPI: u64 = 0x03;
+const EXT_SEND_IPI: u64 = 0x04;
+const EXT_REMOTE_FENCE_I: u64 = 0x05;
+const EXT_REMOTE_SFENCE_VMA: u64 = 0x06;
+const EXT_REMOTE_SFENCE_VMA_ASID: u64 = 0x07;
+const EXT_SHUTDOWN: u64 = 0x08;
+const EXT_BASE: u64 = 0x10;
+const EXT_TIME: u64 = 0x5449_4d45;
+const EXT_IPI: u64 = 0x73_5049;
+const EXT_RFENCE: u64 = 0x5246_4e43;
+const EXT_HSM: u64 = 0x48_534d;
+const EXT_SRST: u64 = 0x5352_5354;
+
+/// The extensions answered by `probe_extension`
+const EXTENSIONS: [u64; 15] = [
+    EXT_SET_TIMER,
+    EXT_CONSOLE_PUTCHAR,
+    EXT_CONSOLE_GETCHAR,
+    EXT_CLEAR_IPI,
+    EXT_SEND_IPI,
+    EXT_REMOTE_FENCE_I,
+    EXT_REMOTE_SFENCE_VMA,
+    EXT_REMOTE_SFENCE_VMA_ASID,
+    EXT_SHUTDOWN,
+    EXT_BASE,
+    EXT_TIME,
+    EXT_IPI,
+    EXT_RFENCE,
+    EXT_HSM,
+    EXT_SRST,
+];
+
+/// The states of `hart_get_status`
+pub const HART_STARTED: u64 = 0;
+
+/// The retentive suspend type of `hart_suspend`, which resumes like WFI
+pub const SUSPEND_RETENTIVE: u64 = 0;
+
+/// The reset types of `system_reset`
+pub const RESET_SHUTDOWN: u64 = 0;
+pub const RESET_COLD_REBOOT: u64 = 1;
+pub const RESET_WARM_REBOOT: u64 = 2;
+
+/// The reset reason of a failure
+pub const REASON_SYSTEM_FAILURE: u64 = 1;
+
+/// A call to the firmware
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Call {
+    /// a call answered without the machine, like the ones of the base extension
+    Return(Result<u64, i64>),
+    SetTimer(u64),
+    ConsolePutchar(u8),
+    ConsoleGetchar,
+    /// raise the supervisor software interrupt of the harts in the mask
+    SendIpi(HartMask),
+    ClearIpi,
+    RemoteFenceI(HartMask),
+    /// flush the translations of the range (start, size), in an address space
+    RemoteSfenceVma {
+        harts: HartMask,
+        start: u64,
+        size: u64,
+        asid: Option<u16>,
+    },
+    HartStart {
+        hart: u64,
+        start: u64,
+        opaque: u64,
+    },
+    HartStop,
+    HartStatus(u64),
+    HartSuspend {
+        kind: u64,
+        resume: u64,
+        opaque: u64,
+    },
+    Reset {
+        kind: u64,
+        reason: u64,
+    },
+}
+
+/// The harts of a call. The legacy calls pass a pointer to a mask, which is taken
+/// as all the harts since there is only one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HartMask {
+    All,
+    /// a mask of the harts from `base`
+    Mask {
+        mask: u64,
+        base: u64,
+    },
+}
+
+impl HartMask {
+    fn new(mask: u64, base: u64) -> Self {
+        // a base of -1 is all the harts
+        if base == u64::MAX {
+            Self::All
+        } else {
+            Self::Mask { mask, base }
+        }
+    }
+
+    /// Check if a hart is in the mask
+    pub fn contains(&self, hart: u64) -> bool {
+        match *self {
+            Self::All => true,
+            Self::Mask { mask, base } => hart
+                .checked_sub(base)
+                .is_some_and(|bit| bit < 64 && mask & 1 << bit != 0),
+        }
+    }
+}
+
+/// A decoded call, with the convention of its return values
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Request {
+    pub call: Call,
+    /// the legacy calls only return a value in a0, the others an error in a0
+    /// and a value in a1
+    pub legacy: bool,
+}
+
+/// The answers of the base extension
+fn base(function: u64, args: &[u64; 6]) -> Result<u64, i64> {
+    match function {
+        0 => Ok(SPEC_VERSION),
+        1 => Ok(IMPL_ID),
+        // the version of the emulator, as major << 16 | minor
+        2 => Ok(
+            env!("CARGO_PKG_VERSION_MAJOR").parse::<u64>().unwrap_or(0) << 16
+                | env!("CARGO_PKG_VERSION_MINOR").parse::<u64>().unwrap_or(0),
+        ),
+        3 => Ok(EXTENSIONS.contains(&args[0]).into()),
+        // mvendorid, marchid and mimpid
+        4..=6 => Ok(0),
+        _ => Err(ERR_NOT_SUPPORTED),
+    }
+}
+
+/// Decode a call from the extension in a7, the function in a6 and the arguments
+/// in a0 to a5
+pub fn decode(extension: u64, function: u64, args: &[u64; 6]) -> Request {
+    let legacy = extension <= EXT_SHUTDOWN;
+    let harts = || HartMask::new(args[0], args[1]);
+
+    let call = match (extension, function) {
+        (EXT_SET_TIMER, _) | (EXT_TIME, 0) => Call::SetTimer(args[0]),
+        (EXT_CONSOLE_PUTCHAR, _) => Call::ConsolePutchar(args[0] as u8),
+        (EXT_CONSOLE_GETCHAR, _) => Call::ConsoleGetchar,
+        (EXT_CLEAR_IPI, _) => Call::ClearIpi,
+        (EXT_SEND_IPI, _) => Call::SendIpi(HartMask::All),
+        (EXT_REMOTE_FENCE_I, _) => Call::RemoteFenceI(HartMask::All),
+        (EXT_REMOTE_SFENCE_VMA, _) => Call::RemoteSfenceVma {
+            harts: HartMask::All,
+            start: args[1],
+            size: args[2],
+            asid: None,
+        },
+        (EXT_REMOTE_SFENCE_VMA_ASID, _) => Call::RemoteSfenceVma {
+            harts: HartMask::All,
+            start: args[1],
+            size: args[2],
+            asid: Some(args[3] as u16),
+        },
+        (EXT_SHUTDOWN, _) => Call::Reset {
+            kind: RESET_SHUTDOWN,
+            reason: 0,
+        },
+        (EXT_BASE, _) => Call::Return(base(function, args)),
+        (EXT_IPI, 0) => Call::SendIpi(harts()),
+        (EXT_RFENCE, 0) => Call::RemoteFenceI(harts()),
+        (EXT_RFENCE, 1) => Call::RemoteSfenceVma {
+            harts: harts(),
+            start: args[2],
+            size: args[3],
+            asid: None,
+        },
+        (EXT_RFENCE, 2) => Call::RemoteSfenceVma {
+            harts: harts(),
+            start: args[2],
+            size: args[3],
+            asid: Some(args[4] as u16),
+        },
+        (EXT_HSM, 0) => Call::HartStart {
+            hart: args[0],
+            start: args[1],
+            opaque: args[2],
+        },
+        (EXT_HSM, 1) => Call::HartStop,
+        (EXT_HSM, 2) => Call::HartStatus(args[0]),
+        (EXT_HSM, 3) => Call::HartSuspend {
+            kind: args[0] & 0xffff_ffff,
+            resume: args[1],
+            opaque: args[2],
+        },
+        (EXT_SRST, 0) => Call::Reset {
+            kind: args[0] & 0xffff_ffff,
+            reason: args[1] & 0xffff_ffff,
+        },
+        // the hypervisor fences and the unknown extensions
+        _ => Call::Return(Err(ERR_NOT_SUPPORTED)),
+    };
+
+    Request { call, legacy }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn base_extension() {
+        let call = |function, arg| decode(EXT_BASE, function, &[arg, 0, 0, 0, 0, 0]).call;
+
+        assert_eq!(call(0, 0), Call::Return(Ok(1 << 24)));
+        assert_eq!(call(3, EXT_HSM), Call::Return(Ok(1)));
+        assert_eq!(call(3, 0x4442_434e), Call::Return(Ok(0)));
+        assert_eq!(call(7, 0), Call::Return(Err(ERR_NOT_SUPPORTED)));
+        assert!(!decode(EXT_BASE, 0, &[0; 6]).legacy);
+    }
+
+    #[test]
+    fn legacy() {
+        let request = decode(EXT_CONSOLE_PUTCHAR, 0, &[0x141, 0, 0, 0, 0, 0]);
+
+        assert!(request.legacy);
+        assert_eq!(request.call, Call::ConsolePutchar(b'A'));
+        assert_eq!(
+            decode(EXT_SET_TIMER, 0, &[5, 0, 0, 0, 0, 0]).call,
+            decode(EXT_TIME, 0, &[5, 0, 0, 0, 0, 0]).call
+        );
+    }
+
+    #[test]
+    fn hart_mask() {
+        let request = decode(EXT_RFENCE, 2, &[0b10, 4, 0x1000, 0x2000, 7, 0]);
+
+        let Call::RemoteSfenceVma { harts, asid, .. } = request.call else {
+            panic!("{:?}", request);
+        };
+        assert_eq!(asid, Some(7));
+        assert!(harts.contains(5));
+        assert!(!harts.contains(0));
+        assert!(!harts.contains(4 + 64));
+
+        assert!(HartMask::new(0, u64::MAX).contains(0));
+        assert_eq!(
+            decode(EXT_RFENCE, 3, &[0; 6]).call,
+            Call::Return(Err(ERR_NOT_SUPPORTED))
+        );
+    }
+}
@@ -0,0 +1,542 @@
+use crate::clint::{Clock, CLINT_BASE, CLINT_SIZE, TIMEBASE_FREQUENCY};
+use crate::fdt::Fdt;
+use crate::machine::{self, Machine};
+use crate::plic::{Interrupt, NUM_SOURCES, PLIC_BASE, PLIC_SIZE};
+use crate::uart::{Serial, Uart, UART_BASE, UART_IRQ, UART_SIZE};
+use crate::virtio::{Disk, VirtioBlock, VIRTIO_BASE, VIRTIO_COUNT, VIRTIO_IRQ, VIRTIO_SIZE};
+use crate::vm::PAGE_SIZE;
+use std::cell::RefCell;
+use std::io;
+use std::path::PathBuf;
+use std::rc::Rc;
+
+/// The base address of the RAM in the `virt` machine layout
+pub const RAM_BASE: usize = 0x8000_0000;
+
+/// The default size of the RAM
+pub const RAM_SIZE: usize = 128 * 1024 * 1024;
+
+/// The offset of a kernel image in the RAM when it has no header, the one of
+/// 64 bit linux
+pub const KERNEL_OFFSET: usize = 0x20_0000;
+
+/// The room for the device tree at the end of the RAM, below it is the initrd
+pub const DTB_MAX_SIZE: usize = 0x20_0000;
+
+/// The header of a RISC-V linux `Image`, with the magic number at offset 56
+const IMAGE_HEADER_SIZE: usize = 64;
+const IMAGE_MAGIC: &[u8] = b"RSC\x05";
+
+/// The frequency of the UART input clock, the one of the `virt` machine
+const UART_CLOCK: u32 = 3_686_400;
+
+/// The phandles of the interrupt controllers
+const CPU_INTC_PHANDLE: u32 = 1;
+const PLIC_PHANDLE: u32 = 2;
+
+/// The interrupt codes in `interrupts-extended`
+const IRQ_M_SOFT: u32 = 3;
+const IRQ_M_TIMER: u32 = 7;
+const IRQ_S_EXT: u32 = 9;
+const IRQ_M_EXT: u32 = 11;
+
+#[derive(Debug)]
+pub enum Error {
+    Machine(machine::Error),
+    /// a host file of a device can't be opened
+    Io(PathBuf, io::Error),
+    /// more drives than virtio-mmio transports
+    TooManyDrives(usize),
+    /// an image doesn't fit in the RAM, with its size
+    NoRoom(usize),
+}
+
+/// A disk image for a virtio block device
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Drive {
+    pub path: PathBuf,
+    pub read_only: bool,
+    /// keep the writes in memory, and leave the image untouched
+    pub overlay: bool,
+}
+
+/// The platform of a machine in system mode, laid out like the QEMU `virt` machine
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub ram_size: usize,
+    pub clock: Clock,
+    pub serial: Serial,
+    pub uart_base: usize,
+    pub drives: Vec<Drive>,
+    /// the kernel command line
+    pub bootargs: Option<String>,
+    /// the physical range (start, end) of the initial ramdisk
+    pub initrd: Option<(usize, usize)>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            ram_size: RAM_SIZE,
+            clock: Clock::Instructions,
+            serial: Serial::Stdio,
+            uart_base: UART_BASE,
+            drives: Vec::new(),
+            bootargs: None,
+            initrd: None,
+        }
+    }
+}
+
+/// Add the RAM and the devices of the platform to a machine
+pub fn build(machine: &mut Machine, config: &Config) -> Result<(), Error> {
+    if config.drives.len() > VIRTIO_COUNT {
+        return Err(Error::TooManyDrives(config.drives.len()));
+    }
+
+    machine
+        .add_ram(RAM_BASE, config.ram_size)
+        .map_err(Error::Machine)?;
+    machine
+        .add_clint(CLINT_BASE, config.clock)
+        .map_err(Error::Machine)?;
+    let plic = machine.add_plic(PLIC_BASE).map_err(Error::Machine)?;
+
+    let mut uart = Uart::open(&config.serial).map_err(|err| {
+        let path = match &config.serial {
+            Serial::Stdio => PathBuf::from("stdio"),
+            Serial::Pipe(path) | Serial::File(path) => path.clone(),
+        };
+        Error::Io(path, err)
+    })?;
+    uart.set_interrupt(Interrupt::new(plic.clone(), UART_IRQ));
+    machine
+        .add_device(config.uart_base, UART_SIZE, Rc::new(RefCell::new(uart)))
+        .map_err(Error::Machine)?;
+
+    for (i, drive) in config.drives.iter().enumerate() {
+        let disk = Disk::open(&drive.path, drive.read_only, drive.overlay)
+            .map_err(|err| Error::Io(drive.path.clone(), err))?;
+
+        let mut block = VirtioBlock::new(disk);
+        block.set_interrupt(Interrupt::new(plic.clone(), VIRTIO_IRQ + i));
+        machine
+            .add_device(
+                VIRTIO_BASE + i * VIRTIO_SIZE,
+                VIRTIO_SIZE,
+                Rc::new(RefCell::new(block)),
+            )
+            .map_err(Error::Machine)?;
+    }
+
+    Ok(())
+}
+
+/// The `reg` property of a region, with 2 address and 2 size cells
+fn reg(fdt: &mut Fdt, base: usize, size: usize) {
+    fdt.property_u64s("reg", &[base as u64, size as u64]);
+}
+
+/// Describe the platform in a device tree blob, for a hart with the ISA string
+pub fn device_tree(config: &Config, isa: &str) -> Vec<u8> {
+    let mut fdt = Fdt::default();
+
+    fdt.begin_node("");
+    fdt.property_u32("#address-cells", 2);
+    fdt.property_u32("#size-cells", 2);
+    fdt.property_string("compatible", "riscv-virtio");
+    fdt.property_string("model", "risky,virt");
+
+    fdt.begin_node("chosen");
+    if let Some(bootargs) = &config.bootargs {
+        fdt.property_string("bootargs", bootargs);
+    }
+    if let Some((start, end)) = config.initrd {
+        fdt.property_u64("linux,initrd-start", start as u64);
+        fdt.property_u64("linux,initrd-end", end as u64);
+    }
+    fdt.property_string(
+        "stdout-path",
+        &format!("/soc/serial@{:x}", config.uart_base),
+    );
+    fdt.end_node();
+
+    fdt.begin_node(&format!("memory@{:x}", RAM_BASE));
+    fdt.property_string("device_type", "memory");
+    reg(&mut fdt, RAM_BASE, config.ram_size);
+    fdt.end_node();
+
+    fdt.begin_node("cpus");
+    fdt.property_u32("#address-cells", 1);
+    fdt.property_u32("#size-cells", 0);
+    fdt.property_u32("timebase-frequency", TIMEBASE_FREQUENCY as u32);
+
+    fdt.begin_node("cpu@0");
+    fdt.property_string("device_type", "cpu");
+    fdt.property_u32("reg", 0);
+    fdt.property_string("status", "okay");
+    fdt.property_string("compatible", "riscv");
+    fdt.property_string("riscv,isa", isa);
+    fdt.property_string("mmu-type", "riscv,sv57");
+
+    fdt.begin_node("interrupt-controller");
+    fdt.property_u32("#interrupt-cells", 1);
+    fdt.property_empty("interrupt-controller");
+    fdt.property_string("compatible", "riscv,cpu-intc");
+    fdt.property_u32("phandle", CPU_INTC_PHANDLE);
+    fdt.end_node();
+
+    fdt.end_node();
+    fdt.end_node();
+
+    fdt.begin_node("soc");
+    fdt.property_u32("#address-cells", 2);
+    fdt.property_u32("#size-cells", 2);
+    fdt.property_string("compatible", "simple-bus");
+    fdt.property_empty("ranges");
+
+    fdt.begin_node(&format!("clint@{:x}", CLINT_BASE));
+    fdt.property_strings("compatible", &["sifive,clint0", "riscv,clint0"]);
+    reg(&mut fdt, CLINT_BASE, CLINT_SIZE);
+    fdt.property_cells(
+        "interrupts-extended",
+        &[CPU_INTC_PHANDLE, IRQ_M_SOFT, CPU_INTC_PHANDLE, IRQ_M_TIMER],
+    );
+    fdt.end_node();
+
+    fdt.begin_node(&format!("plic@{:x}", PLIC_BASE));
+    fdt.property_strings("compatible", &["sifive,plic-1.0.0", "riscv,plic0"]);
+    reg(&mut fdt, PLIC_BASE, PLIC_SIZE);
+    fdt.property_u32("#address-cells", 0);
+    fdt.property_u32("#interrupt-cells", 1);
+    fdt.property_empty("interrupt-controller");
+    fdt.property_cells(
+        "interrupts-extended",
+        &[CPU_INTC_PHANDLE, IRQ_M_EXT, CPU_INTC_PHANDLE, IRQ_S_EXT],
+    );
+    fdt.property_u32("riscv,ndev", NUM_SOURCES as u32 - 1);
+    fdt.property_u32("phandle", PLIC_PHANDLE);
+    fdt.end_node();
+
+    fdt.begin_node(&format!("serial@{:x}", config.uart_base));
+    fdt.property_string("compatible", "ns16550a");
+    reg(&mut fdt, config.uart_base, UART_SIZE);
+    fdt.property_u32("clock-frequency", UART_CLOCK);
+    fdt.property_u32("interrupt-parent", PLIC_PHANDLE);
+    fdt.property_u32("interrupts", UART_IRQ as u32);
+    fdt.end_node();
+
+    for i in 0..config.drives.len() {
+        let base = VIRTIO_BASE + i * VIRTIO_SIZE;
+        fdt.begin_node(&format!("virtio_mmio@{:x}", base));
+        fdt.property_string("compatible", "virtio,mmio");
+        reg(&mut fdt, base, VIRTIO_SIZE);
+        fdt.property_u32("interrupt-parent", PLIC_PHANDLE);
+        fdt.property_u32("interrupts", (VIRTIO_IRQ + i) as u32);
+        fdt.end_node();
+    }
+
+    fdt.end_node();
+    fdt.end_node();
+
+    fdt.finish()
+}
+
+/// Copy the device tree to the last pages of the RAM, and pass it to the boot
+/// hart in a1 with its hart id in a0. Returns the address of the device tree
+pub fn place_device_tree(
+    machine: &mut Machine,
+    config: &Config,
+    dtb: &[u8],
+) -> Result<usize, Error> {
+    if dtb.len() > DTB_MAX_SIZE || dtb.len() > config.ram_size {
+        return Err(Error::NoRoom(dtb.len()));
+    }
+
+    let address = (RAM_BASE + config.ram_size - dtb.len()) / PAGE_SIZE * PAGE_SIZE;
+
+    machine.write_memory(address, dtb).map_err(Error::Machine)?;
+    machine.set_register(10, 0);
+    machine.set_register(11, address as u64);
+
+    Ok(address)
+}
+
+/// Load a raw supervisor image in the RAM, at the offset of its header when it
+/// has the one of a linux `Image` or at `KERNEL_OFFSET` without one, and start
+/// it there.
+/// Returns the range of the image, including its bss
+pub fn load_kernel(
+    machine: &mut Machine,
+    config: &Config,
+    image: &[u8],
+) -> Result<(usize, usize), Error> {
+    let field =
+        |offset: usize| u64::from_le_bytes(image[offset..offset + 8].try_into().unwrap()) as usize;
+
+    // the size in the header includes the bss
+    let (offset, size) = if image.len() >= IMAGE_HEADER_SIZE && &image[56..60] == IMAGE_MAGIC {
+        (field(8), field(16).max(image.len()))
+    } else {
+        (KERNEL_OFFSET, image.len())
+    };
+
+    let available = config.ram_size.saturating_sub(DTB_MAX_SIZE);
+    if offset.checked_add(size).is_none_or(|end| end > available) {
+        return Err(Error::NoRoom(size));
+    }
+
+    let address = RAM_BASE + offset;
+    machine
+        .write_memory(address, image)
+        .map_err(Error::Machine)?;
+    machine.set_pc(address as u64);
+
+    Ok((address, address + size))
+}
+
+/// Copy the initial ramdisk to the end of the RAM, below the room of the device
+/// tree and above the kernel that ends at `kernel_end`. Returns its range, for
+/// `Config::initrd`
+pub fn place_initrd(
+    machine: &mut Machine,
+    config: &Config,
+    initrd: &[u8],
+    kernel_end: usize,
+) -> Result<(usize, usize), Error> {
+    let start = (RAM_BASE + config.ram_size)
+        .checked_sub(DTB_MAX_SIZE + initrd.len())
+        .map(|start| start / PAGE_SIZE * PAGE_SIZE)
+        .filter(|&start| start >= RAM_BASE.max(kernel_end))
+        .ok_or(Error::NoRoom(initrd.len()))?;
+
+    machine
+        .write_memory(start, initrd)
+        .map_err(Error::Machine)?;
+
+    Ok((start, start + initrd.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::elf::{Elf, Endianness, Segment, Usize};
+
+    /// Find the offset of a string in the blob
+    fn find(blob: &[u8], needle: &[u8]) -> Option<usize> {
+        blob.windows(needle.len())
+            .position(|window| window == needle)
+    }
+
+    #[test]
+    fn devices() {
+        let config = Config {
+            uart_base: 0x1000_2000,
+            drives: vec![Drive {
+                path: "disk.img".into(),
+                read_only: false,
+                overlay: false,
+            }],
+            bootargs: Some("console=ttyS0".into()),
+            ..Default::default()
+        };
+
+        let blob = device_tree(&config, "rv64i_zicsr");
+
+        for needle in [
+            &b"memory@80000000\0"[..],
+            b"serial@10002000\0",
+            b"/soc/serial@10002000\0",
+            b"virtio_mmio@10001000\0",
+            b"console=ttyS0\0",
+            b"rv64i_zicsr\0",
+            b"sifive,plic-1.0.0\0riscv,plic0\0",
+        ] {
+            assert!(find(&blob, needle).is_some(), "{:?}", needle);
+        }
+
+        // the size of the memory follows its address
+        let memory = find(
+            &blob,
+            &[0, 0, 0, 0, 0x80, 0, 0, 0, 0, 0, 0, 0, 0x08, 0, 0, 0],
+        );
+        assert!(memory.is_some());
+
+        assert!(find(&blob, b"virtio_mmio@10002000").is_none());
+    }
+
+    #[test]
+    fn place() {
+        let elf = Elf {
+            endianness: Endianness::Little,
+            entry: Usize::U64(RAM_BASE as u64),
+            segments: vec![Segment {
+                start: RAM_BASE,
+                protection: 0b101.into(),
+                data: vec![0x13, 0, 0, 0],
+            }],
+            stack_protection: 0b110.into(),
+            relro: None,
+            notes: Vec::new(),
+            attributes: None,
+            line_table: None,
+            symbols: Vec::new(),
+        };
+        let mut machine = Machine::new_system(elf).unwrap();
+
+        let output = std::env::temp_dir().join(format!("risky-system-{}", std::process::id()));
+        let config = Config {
+            serial: Serial::File(output.clone()),
+            ..Default::default()
+        };
+        build(&mut machine, &config).unwrap();
+
+        let blob = device_tree(&config, &machine.isa());
+        let address = place_device_tree(&mut machine, &config, &blob).unwrap();
+
+        // the device tree is in the last page of the RAM, after the loaded code
+        assert_eq!(address, RAM_BASE + RAM_SIZE - PAGE_SIZE);
+        assert_eq!(machine.registers()[11], address as u64);
+        assert_eq!(machine.memory().read_u32(address).unwrap(), 0xedfe0dd0);
+        assert_eq!(machine.memory().read(RAM_BASE).unwrap(), 0x13);
+
+        // the RAM can be smaller than the device tree
+        let tiny = Config {
+            ram_size: 16,
+            ..Default::default()
+        };
+        assert!(matches!(
+            place_device_tree(&mut machine, &tiny, &blob),
+            Err(Error::NoRoom(..))
+        ));
+
+        std::fs::remove_file(output).unwrap();
+    }
+
+    #[test]
+    fn kernel_header() {
+        let mut image = vec![0; IMAGE_HEADER_SIZE];
+        image[8..16].copy_from_slice(&0x40_0000u64.to_le_bytes());
+        image[16..24].copy_from_slice(&0x1000u64.to_le_bytes());
+        image[56..60].copy_from_slice(IMAGE_MAGIC);
+
+        let config = Config::default();
+        let mut machine = Machine::new_bare();
+        machine.add_ram(RAM_BASE, config.ram_size).unwrap();
+
+        let (address, end) = load_kernel(&mut machine, &config, &image).unwrap();
+        assert_eq!(address, RAM_BASE + 0x40_0000);
+        assert_eq!(end, address + 0x1000);
+        assert_eq!(machine.pc(), address as u64);
+        assert_eq!(
+            machine.memory().read_u32(address + 56).unwrap(),
+            0x0543_5352
+        );
+
+        // the bss has to fit too
+        image[16..24].copy_from_slice(&(RAM_SIZE as u64).to_le_bytes());
+        assert!(matches!(
+            load_kernel(&mut machine, &config, &image),
+            Err(Error::NoRoom(RAM_SIZE))
+        ));
+
+        // the initial ramdisk goes above the kernel, or doesn't fit
+        let small = Config {
+            ram_size: 0x80_0000,
+            ..Default::default()
+        };
+        image[16..24].copy_from_slice(&0x1000u64.to_le_bytes());
+        let (_, end) = load_kernel(&mut machine, &small, &image).unwrap();
+        assert!(matches!(
+            place_initrd(&mut machine, &small, &[1; 0x20_0000], end),
+            Err(Error::NoRoom(0x20_0000))
+        ));
+        let (start, _) = place_initrd(&mut machine, &small, &[1; 0x10_0000], end).unwrap();
+        assert_eq!(start, RAM_BASE + 0x50_0000);
+    }
+
+    /// An entry of a newc cpio archive, the format of initramfs
+    fn newc(name: &str, data: &[u8]) -> Vec<u8> {
+        let fields = [
+            0,
+            0o100755,
+            0,
+            0,
+            1,
+            0,
+            data.len(),
+            0,
+            0,
+            0,
+            0,
+            name.len() + 1,
+            0,
+        ];
+
+        let mut entry = b"070701".to_vec();
+        for field in fields {
+            entry.extend(format!("{:08x}", field).as_bytes());
+        }
+        entry.extend(name.as_bytes());
+        entry.push(0);
+        entry.resize(entry.len().next_multiple_of(4), 0);
+        entry.extend(data);
+        entry.resize(entry.len().next_multiple_of(4), 0);
+
+        entry
+    }
+
+    #[test]
+    fn start_image() {
+        // a headerless image that prints with the legacy console and shuts down;
+        // addi a7, zero, 1; addi a0, zero, 'o'; ecall; addi a0, zero, 'k'; ecall;
+        // addi a7, zero, 8; ecall
+        let kernel: Vec<u8> = [
+            0x0010_0893u32,
+            0x06f0_0513,
+            0x0000_0073,
+            0x06b0_0513,
+            0x0000_0073,
+            0x0080_0893,
+            0x0000_0073,
+        ]
+        .iter()
+        .flat_map(|x| x.to_le_bytes())
+        .collect();
+        let mut initrd = newc("init", b"#!/bin/sh\n");
+        initrd.extend(newc("TRAILER!!!", b""));
+
+        let output = std::env::temp_dir().join(format!("risky-boot-{}", std::process::id()));
+        let mut config = Config {
+            serial: Serial::File(output.clone()),
+            bootargs: Some("console=ttyS0".into()),
+            ..Default::default()
+        };
+
+        let mut machine = Machine::new_bare();
+        build(&mut machine, &config).unwrap();
+
+        let (address, kernel_end) = load_kernel(&mut machine, &config, &kernel).unwrap();
+        assert_eq!(address, RAM_BASE + KERNEL_OFFSET);
+
+        let (start, end) = place_initrd(&mut machine, &config, &initrd, kernel_end).unwrap();
+        assert_eq!(start % PAGE_SIZE, 0);
+        assert_eq!(end - start, initrd.len());
+        assert!(end <= RAM_BASE + RAM_SIZE - DTB_MAX_SIZE);
+        config.initrd = Some((start, end));
+
+        let blob = device_tree(&config, &machine.isa());
+        assert!(find(&blob, &(start as u64).to_be_bytes()).is_some());
+        assert!(find(&blob, b"linux,initrd-end\0").is_some());
+        place_device_tree(&mut machine, &config, &blob).unwrap();
+
+        machine.start_supervisor();
+        machine.set_console(config.uart_base);
+        assert_eq!(machine.run().unwrap(), crate::machine::Stop::Exit(0));
+
+        assert_eq!(std::fs::read(&output).unwrap(), b"ok");
+        assert_eq!(machine.memory().read_u8(start).unwrap(), b'0');
+
+        std::fs::remove_file(output).unwrap();
+    }
+}
@@ -0,0 +1,49 @@
+/// The privilege modes, with their encoding in mstatus.MPP and the CSR addresses
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Privilege {
+    User = 0,
+    Supervisor = 1,
+    Machine = 3,
+}
+
+impl Privilege {
+    /// Decode a privilege, the reserved encoding 2 is `None`
+    pub fn from_bits(bits: u64) -> Option<Self> {
+        match bits {
+            0 => Some(Self::User),
+            1 => Some(Self::Supervisor),
+            3 => Some(Self::Machine),
+            _ => None,
+        }
+    }
+}
+
+/// The synchronous exceptions, with their exception codes in mcause and scause
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exception {
+    InstructionAddressMisaligned = 0,
+    InstructionAccessFault = 1,
+    IllegalInstruction = 2,
+    Breakpoint = 3,
+    LoadAddressMisaligned = 4,
+    LoadAccessFault = 5,
+    StoreAddressMisaligned = 6,
+    StoreAccessFault = 7,
+    EcallFromUser = 8,
+    EcallFromSupervisor = 9,
+    EcallFromMachine = 11,
+    InstructionPageFault = 12,
+    LoadPageFault = 13,
+    StorePageFault = 15,
+}
+
+impl Exception {
+    /// The environment call exception of a privilege
+    pub fn ecall(privilege: Privilege) -> Self {
+        match privilege {
+            Privilege::User => Self::EcallFromUser,
+            Privilege::Supervisor => Self::EcallFromSupervisor,
+            Privilege::Machine => Self::EcallFromMachine,
+        }
+    }
+}
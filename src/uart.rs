@@ -0,0 +1,510 @@
+use crate::plic::Interrupt;
+use crate::vm::{Device, VirtualMemory};
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{self, IsTerminal, Read, Write};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::OnceLock;
+
+/// The base address of the UART in the `virt` machine layout
+pub const UART_BASE: usize = 0x1000_0000;
+
+/// The size of the UART region
+pub const UART_SIZE: usize = 0x100;
+
+/// The PLIC source of the UART in the `virt` machine layout
+pub const UART_IRQ: usize = 10;
+
+/// The registers, some of them are the divisor latch while LCR.DLAB is set
+const RBR_THR_DLL: usize = 0;
+const IER_DLM: usize = 1;
+const IIR_FCR: usize = 2;
+const LCR: usize = 3;
+const MCR: usize = 4;
+const LSR: usize = 5;
+const MSR: usize = 6;
+const SCR: usize = 7;
+
+const IER_RDA: u8 = 1 << 0;
+const IER_THRE: u8 = 1 << 1;
+const IER_MASK: u8 = 0x0f;
+
+const IIR_NONE: u8 = 0x01;
+const IIR_THRE: u8 = 0x02;
+const IIR_RDA: u8 = 0x04;
+const IIR_FIFO: u8 = 0xc0;
+
+const FCR_ENABLE: u8 = 1 << 0;
+const FCR_CLEAR_RX: u8 = 1 << 1;
+
+const LCR_DLAB: u8 = 1 << 7;
+
+const LSR_DR: u8 = 1 << 0;
+const LSR_THRE: u8 = 1 << 5;
+const LSR_TEMT: u8 = 1 << 6;
+
+/// The modem is always ready, with DCD, DSR and CTS set
+const MSR_READY: u8 = 0xb0;
+
+const FIFO_SIZE: usize = 16;
+
+/// Ctrl-A, which starts the commands of the terminal like in QEMU
+const ESCAPE: u8 = 0x01;
+
+/// Where the input of a UART comes from, and its output goes to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Serial {
+    /// the host terminal, in raw mode
+    Stdio,
+    /// `<path>.in` and `<path>.out`, or `<path>` for both, like the pipes of QEMU
+    Pipe(PathBuf),
+    /// output only, to a file
+    File(PathBuf),
+}
+
+impl std::str::FromStr for Serial {
+    type Err = String;
+
+    /// Parse `stdio`, `pipe:<path>` or `file:<path>`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "stdio" => Ok(Self::Stdio),
+            Some(("pipe", path)) => Ok(Self::Pipe(path.into())),
+            Some(("file", path)) => Ok(Self::File(path.into())),
+            _ => Err(format!("unknown serial backend {s:?}")),
+        }
+    }
+}
+
+/// A NS16550A UART. Transmission is instant, and the input is received from
+/// a thread reading the host
+pub struct Uart {
+    input: Option<Receiver<u8>>,
+    output: Box<dyn Write>,
+    interrupt: Option<Interrupt>,
+    rx: VecDeque<u8>,
+    /// the transmitter empty interrupt, until IIR is read or THR written
+    thre_pending: bool,
+    ier: u8,
+    lcr: u8,
+    mcr: u8,
+    scr: u8,
+    divisor: u16,
+    fifo: bool,
+}
+
+impl std::fmt::Debug for Uart {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Uart")
+            .field("rx", &self.rx)
+            .field("ier", &self.ier)
+            .field("lcr", &self.lcr)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The terminal settings before `raw_terminal`
+static SAVED_TERMINAL: OnceLock<String> = OnceLock::new();
+
+/// Run stty on the terminal of stdin
+fn stty(args: &[&str]) -> Option<String> {
+    let output = Command::new("stty")
+        .args(args)
+        .stdin(Stdio::inherit())
+        .output()
+        .ok()?;
+
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).trim().to_owned())
+}
+
+/// Put the host terminal in raw mode, so every key goes to the guest. Ctrl-A x
+/// quits the emulator
+pub fn raw_terminal() {
+    if !io::stdin().is_terminal() || SAVED_TERMINAL.get().is_some() {
+        return;
+    }
+
+    if let Some(saved) = stty(&["-g"]) {
+        stty(&["raw", "-echo"]);
+        let _ = SAVED_TERMINAL.set(saved);
+    }
+}
+
+/// Restore the terminal settings changed by `raw_terminal`
+pub fn restore_terminal() {
+    if let Some(saved) = SAVED_TERMINAL.get() {
+        stty(&[saved]);
+    }
+}
+
+/// The bytes of the standard input, read on a thread for the devices that poll
+/// it without a UART
+pub fn stdin_reader() -> Receiver<u8> {
+    spawn_reader(|| Ok(io::stdin()), false)
+}
+
+/// Read the input on a thread, so the UART can poll it
+fn spawn_reader<R, F>(open: F, escape: bool) -> Receiver<u8>
+where
+    R: Read,
+    F: FnOnce() -> io::Result<R> + Send + 'static,
+{
+    let (sender, receiver) = mpsc::channel();
+
+    std::thread::spawn(move || {
+        let Ok(mut input) = open() else {
+            return;
+        };
+
+        let mut buffer = [0; 64];
+        let mut escaped = false;
+        loop {
+            let len = match input.read(&mut buffer) {
+                Ok(0) | Err(_) => return,
+                Ok(len) => len,
+            };
+
+            for &byte in &buffer[..len] {
+                // Ctrl-A x quits, Ctrl-A Ctrl-A sends a Ctrl-A
+                if escape && escaped {
+                    escaped = false;
+                    match byte {
+                        b'x' => {
+                            restore_terminal();
+                            std::process::exit(0);
+                        }
+                        ESCAPE => {}
+                        _ => {
+                            let _ = sender.send(ESCAPE);
+                        }
+                    }
+                } else if escape && byte == ESCAPE {
+                    escaped = true;
+                    continue;
+                }
+
+                if sender.send(byte).is_err() {
+                    return;
+                }
+            }
+        }
+    });
+
+    receiver
+}
+
+impl Uart {
+    pub fn new(input: Option<Receiver<u8>>, output: Box<dyn Write>) -> Self {
+        Self {
+            input,
+            output,
+            interrupt: None,
+            rx: VecDeque::new(),
+            thre_pending: false,
+            ier: 0,
+            lcr: 0,
+            mcr: 0,
+            scr: 0,
+            divisor: 0,
+            fifo: false,
+        }
+    }
+
+    /// Open the host side of a UART
+    pub fn open(serial: &Serial) -> io::Result<Self> {
+        match serial {
+            Serial::Stdio => Ok(Self::new(
+                Some(spawn_reader(|| Ok(io::stdin()), true)),
+                Box::new(io::stdout()),
+            )),
+            Serial::Pipe(path) => {
+                let with_extension = |extension: &str| {
+                    let mut path = path.clone().into_os_string();
+                    path.push(extension);
+                    PathBuf::from(path)
+                };
+
+                let (input, output) = if with_extension(".in").exists() {
+                    (with_extension(".in"), with_extension(".out"))
+                } else {
+                    (path.clone(), path.clone())
+                };
+
+                // opening a fifo blocks until the other side is opened
+                let output = OpenOptions::new().write(true).open(output)?;
+
+                Ok(Self::new(
+                    Some(spawn_reader(move || File::open(input), false)),
+                    Box::new(output),
+                ))
+            }
+            Serial::File(path) => Ok(Self::new(None, Box::new(File::create(path)?))),
+        }
+    }
+
+    /// Connect the interrupt of the UART
+    pub fn set_interrupt(&mut self, interrupt: Interrupt) {
+        self.interrupt = Some(interrupt);
+        self.update_interrupt();
+    }
+
+    /// The identification of the highest priority interrupt in IIR
+    fn pending_interrupt(&self) -> Option<u8> {
+        if self.ier & IER_RDA != 0 && !self.rx.is_empty() {
+            Some(IIR_RDA)
+        } else if self.ier & IER_THRE != 0 && self.thre_pending {
+            Some(IIR_THRE)
+        } else {
+            None
+        }
+    }
+
+    fn update_interrupt(&self) {
+        if let Some(interrupt) = &self.interrupt {
+            interrupt.set(self.pending_interrupt().is_some());
+        }
+    }
+
+    fn transmit(&mut self, byte: u8) {
+        // the guest can't see the errors of the host
+        let _ = self
+            .output
+            .write_all(&[byte])
+            .and_then(|_| self.output.flush());
+
+        self.thre_pending = true;
+    }
+
+    fn dlab(&self) -> bool {
+        self.lcr & LCR_DLAB != 0
+    }
+}
+
+impl Device for Uart {
+    fn read(&mut self, offset: usize, size: usize) -> Option<u64> {
+        if size != 1 {
+            return None;
+        }
+
+        let value = match offset {
+            RBR_THR_DLL if self.dlab() => self.divisor as u8,
+            RBR_THR_DLL => self.rx.pop_front().unwrap_or(0),
+            IER_DLM if self.dlab() => (self.divisor >> 8) as u8,
+            IER_DLM => self.ier,
+            IIR_FCR => {
+                let id = self.pending_interrupt();
+
+                // reading the transmitter empty interrupt acknowledges it
+                if id == Some(IIR_THRE) {
+                    self.thre_pending = false;
+                }
+
+                let fifo = if self.fifo { IIR_FIFO } else { 0 };
+                id.unwrap_or(IIR_NONE) | fifo
+            }
+            LCR => self.lcr,
+            MCR => self.mcr,
+            LSR => {
+                let ready = if self.rx.is_empty() { 0 } else { LSR_DR };
+                ready | LSR_THRE | LSR_TEMT
+            }
+            MSR => MSR_READY,
+            SCR => self.scr,
+            _ => 0,
+        };
+
+        self.update_interrupt();
+
+        Some(value.into())
+    }
+
+    fn write(&mut self, offset: usize, size: usize, value: u64) -> Option<()> {
+        if size != 1 {
+            return None;
+        }
+
+        let value = value as u8;
+
+        match offset {
+            RBR_THR_DLL if self.dlab() => self.divisor = self.divisor & 0xff00 | value as u16,
+            RBR_THR_DLL => self.transmit(value),
+            IER_DLM if self.dlab() => {
+                self.divisor = self.divisor & 0xff | (value as u16) << 8;
+            }
+            IER_DLM => {
+                // enabling the transmitter empty interrupt raises it right away
+                if value & IER_THRE != 0 && self.ier & IER_THRE == 0 {
+                    self.thre_pending = true;
+                }
+                self.ier = value & IER_MASK;
+            }
+            IIR_FCR => {
+                self.fifo = value & FCR_ENABLE != 0;
+                if value & FCR_CLEAR_RX != 0 {
+                    self.rx.clear();
+                }
+            }
+            LCR => self.lcr = value,
+            MCR => self.mcr = value,
+            SCR => self.scr = value,
+            _ => {}
+        }
+
+        self.update_interrupt();
+
+        Some(())
+    }
+
+    fn poll(&mut self, _memory: &mut VirtualMemory) {
+        let capacity = if self.fifo { FIFO_SIZE } else { 1 };
+
+        if let Some(input) = &self.input {
+            while self.rx.len() < capacity {
+                match input.try_recv() {
+                    Ok(byte) => self.rx.push_back(byte),
+                    Err(_) => break,
+                }
+            }
+        }
+
+        self.update_interrupt();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plic::Plic;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::sync::{Arc, Mutex};
+
+    /// An output that can be inspected after moving it into the UART
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn uart(input: &[u8]) -> (Uart, Shared) {
+        let (sender, receiver) = mpsc::channel();
+        for &byte in input {
+            sender.send(byte).unwrap();
+        }
+
+        let output = Shared::default();
+        (Uart::new(Some(receiver), Box::new(output.clone())), output)
+    }
+
+    #[test]
+    fn transmit_receive() {
+        let mut memory = VirtualMemory::default();
+        let (mut uart, output) = uart(b"hi");
+
+        for &byte in b"ok\n" {
+            uart.write(RBR_THR_DLL, 1, byte.into()).unwrap();
+        }
+        assert_eq!(*output.0.lock().unwrap(), b"ok\n");
+
+        // without the fifo, a byte is received at a time
+        uart.poll(&mut memory);
+        assert_eq!(
+            uart.read(LSR, 1),
+            Some((LSR_DR | LSR_THRE | LSR_TEMT).into())
+        );
+        assert_eq!(uart.read(RBR_THR_DLL, 1), Some(b'h'.into()));
+        assert_eq!(uart.read(LSR, 1).unwrap() as u8 & LSR_DR, 0);
+
+        uart.poll(&mut memory);
+        assert_eq!(uart.read(RBR_THR_DLL, 1), Some(b'i'.into()));
+
+        assert_eq!(uart.read(RBR_THR_DLL, 4), None);
+    }
+
+    #[test]
+    fn divisor_latch() {
+        let (mut uart, output) = uart(b"");
+
+        uart.write(LCR, 1, LCR_DLAB.into()).unwrap();
+        uart.write(RBR_THR_DLL, 1, 0x34).unwrap();
+        uart.write(IER_DLM, 1, 0x12).unwrap();
+        assert_eq!(uart.divisor, 0x1234);
+        assert_eq!(uart.ier, 0);
+
+        uart.write(LCR, 1, 0x03).unwrap();
+        assert_eq!(uart.read(RBR_THR_DLL, 1), Some(0));
+        assert!(output.0.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn interrupts() {
+        let mut memory = VirtualMemory::default();
+        let plic = Rc::new(RefCell::new(Plic::default()));
+        plic.borrow_mut().write(4 * UART_IRQ, 4, 1).unwrap();
+        plic.borrow_mut().write(0x2000, 4, 1 << UART_IRQ).unwrap();
+
+        let (mut uart, _) = uart(b"abc");
+        uart.set_interrupt(Interrupt::new(plic.clone(), UART_IRQ));
+        uart.write(IIR_FCR, 1, FCR_ENABLE.into()).unwrap();
+        assert_eq!(uart.read(IIR_FCR, 1), Some((IIR_NONE | IIR_FIFO).into()));
+
+        // the received data interrupt lasts until the fifo is empty
+        uart.write(IER_DLM, 1, IER_RDA.into()).unwrap();
+        uart.poll(&mut memory);
+        assert!(plic.borrow().interrupting(0));
+        assert_eq!(uart.read(IIR_FCR, 1), Some((IIR_RDA | IIR_FIFO).into()));
+        for &byte in b"abc" {
+            assert_eq!(uart.read(RBR_THR_DLL, 1), Some(byte.into()));
+        }
+        assert_eq!(uart.pending_interrupt(), None);
+
+        // the transmitter empty interrupt is acknowledged by reading IIR
+        uart.write(IER_DLM, 1, (IER_RDA | IER_THRE).into()).unwrap();
+        assert_eq!(uart.read(IIR_FCR, 1), Some((IIR_THRE | IIR_FIFO).into()));
+        assert_eq!(uart.read(IIR_FCR, 1), Some((IIR_NONE | IIR_FIFO).into()));
+    }
+
+    #[test]
+    fn serial() {
+        assert_eq!("stdio".parse(), Ok(Serial::Stdio));
+        assert_eq!("pipe:/tmp/a".parse(), Ok(Serial::Pipe("/tmp/a".into())));
+        assert_eq!("file:out".parse(), Ok(Serial::File("out".into())));
+        assert!("tcp:1234".parse::<Serial>().is_err());
+    }
+
+    #[test]
+    fn pipe() {
+        let mut memory = VirtualMemory::default();
+        let path = std::env::temp_dir().join(format!("risky-uart-{}", std::process::id()));
+        let with_extension = |extension: &str| path.with_extension(extension);
+        std::fs::write(with_extension("in"), b"x").unwrap();
+        std::fs::write(with_extension("out"), b"").unwrap();
+
+        let mut uart = Uart::open(&Serial::Pipe(path.clone())).unwrap();
+        uart.write(RBR_THR_DLL, 1, b'y'.into()).unwrap();
+        assert_eq!(std::fs::read(with_extension("out")).unwrap(), b"y");
+
+        // the input is read by a thread
+        while uart.rx.is_empty() {
+            uart.poll(&mut memory);
+            std::thread::yield_now();
+        }
+        assert_eq!(uart.read(RBR_THR_DLL, 1), Some(b'x'.into()));
+
+        std::fs::remove_file(with_extension("in")).unwrap();
+        std::fs::remove_file(with_extension("out")).unwrap();
+    }
+}
@@ -0,0 +1,788 @@
+use crate::plic::Interrupt;
+use crate::vm::{self, Device, VirtualMemory};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// The base address of the first virtio-mmio transport in the `virt` machine layout
+pub const VIRTIO_BASE: usize = 0x1000_1000;
+
+/// The size of a transport region, the next one follows it
+pub const VIRTIO_SIZE: usize = 0x1000;
+
+/// The PLIC source of the first transport, the next ones use the next sources
+pub const VIRTIO_IRQ: usize = 1;
+
+/// The number of transports in the `virt` machine layout
+pub const VIRTIO_COUNT: usize = 8;
+
+pub const SECTOR_SIZE: usize = 512;
+
+/// The registers of the virtio-mmio transport, version 2
+const MAGIC_VALUE: usize = 0x000;
+const VERSION: usize = 0x004;
+const DEVICE_ID: usize = 0x008;
+const VENDOR_ID: usize = 0x00c;
+const DEVICE_FEATURES: usize = 0x010;
+const DEVICE_FEATURES_SEL: usize = 0x014;
+const DRIVER_FEATURES: usize = 0x020;
+const DRIVER_FEATURES_SEL: usize = 0x024;
+const QUEUE_SEL: usize = 0x030;
+const QUEUE_NUM_MAX: usize = 0x034;
+const QUEUE_NUM: usize = 0x038;
+const QUEUE_READY: usize = 0x044;
+const QUEUE_NOTIFY: usize = 0x050;
+const INTERRUPT_STATUS: usize = 0x060;
+const INTERRUPT_ACK: usize = 0x064;
+const STATUS: usize = 0x070;
+const QUEUE_DESC_LOW: usize = 0x080;
+const QUEUE_DESC_HIGH: usize = 0x084;
+const QUEUE_DRIVER_LOW: usize = 0x090;
+const QUEUE_DRIVER_HIGH: usize = 0x094;
+const QUEUE_DEVICE_LOW: usize = 0x0a0;
+const QUEUE_DEVICE_HIGH: usize = 0x0a4;
+const CONFIG_GENERATION: usize = 0x0fc;
+const CONFIG: usize = 0x100;
+
+/// "virt" in little endian
+const MAGIC: u32 = 0x7472_6976;
+/// "QEMU", like the devices of the `virt` machine
+const VENDOR: u32 = 0x554d_4551;
+const BLOCK_DEVICE: u32 = 2;
+
+const QUEUE_SIZE: u16 = 256;
+
+const STATUS_FEATURES_OK: u32 = 8;
+const STATUS_NEEDS_RESET: u32 = 64;
+
+const INTERRUPT_USED_BUFFER: u32 = 1 << 0;
+const INTERRUPT_CONFIG_CHANGE: u32 = 1 << 1;
+
+const F_VERSION_1: u64 = 1 << 32;
+const BLK_F_RO: u64 = 1 << 5;
+const BLK_F_FLUSH: u64 = 1 << 9;
+
+const DESC_F_NEXT: u16 = 1;
+const DESC_F_WRITE: u16 = 2;
+const AVAIL_F_NO_INTERRUPT: u16 = 1;
+
+const BLK_T_IN: u32 = 0;
+const BLK_T_OUT: u32 = 1;
+const BLK_T_FLUSH: u32 = 4;
+const BLK_T_GET_ID: u32 = 8;
+
+const BLK_S_OK: u8 = 0;
+const BLK_S_IOERR: u8 = 1;
+const BLK_S_UNSUPP: u8 = 2;
+
+/// The length of the serial number returned by GET_ID
+const ID_LEN: usize = 20;
+
+/// The most data of a request, the lengths of the buffers come from the driver
+/// and longer requests are malformed
+const MAX_DATA: usize = 1 << 24;
+
+/// Why a queue can't be processed, the device then needs a reset
+#[derive(Debug)]
+pub enum Error {
+    Memory(vm::Error),
+    /// a descriptor chain longer than the queue, which loops
+    DescriptorLoop,
+    /// a request without a header or a status byte, or with too much data
+    MalformedRequest,
+}
+
+impl From<vm::Error> for Error {
+    fn from(err: vm::Error) -> Self {
+        Self::Memory(err)
+    }
+}
+
+/// A disk image file. With an overlay the written sectors are kept in memory,
+/// and the image is left untouched
+#[derive(Debug)]
+pub struct Disk {
+    file: File,
+    sectors: u64,
+    read_only: bool,
+    overlay: Option<HashMap<u64, Box<[u8; SECTOR_SIZE]>>>,
+}
+
+impl Disk {
+    pub fn open(path: impl AsRef<Path>, read_only: bool, overlay: bool) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(!read_only && !overlay)
+            .open(path)?;
+        let sectors = file.metadata()?.len() / SECTOR_SIZE as u64;
+
+        Ok(Self {
+            file,
+            sectors,
+            read_only,
+            overlay: overlay.then(HashMap::new),
+        })
+    }
+
+    /// The size in sectors, a partial last sector is not accessible
+    pub fn sectors(&self) -> u64 {
+        self.sectors
+    }
+
+    pub fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Check that an access is whole sectors inside the disk
+    fn check_range(&self, sector: u64, len: usize) -> io::Result<()> {
+        let count = (len / SECTOR_SIZE) as u64;
+
+        match sector.checked_add(count) {
+            Some(end) if end <= self.sectors && len.is_multiple_of(SECTOR_SIZE) => Ok(()),
+            _ => Err(io::ErrorKind::InvalidInput.into()),
+        }
+    }
+
+    pub fn read(&mut self, sector: u64, buf: &mut [u8]) -> io::Result<()> {
+        self.check_range(sector, buf.len())?;
+
+        self.file
+            .seek(SeekFrom::Start(sector * SECTOR_SIZE as u64))?;
+        self.file.read_exact(buf)?;
+
+        if let Some(overlay) = &self.overlay {
+            for (i, chunk) in buf.chunks_mut(SECTOR_SIZE).enumerate() {
+                if let Some(data) = overlay.get(&(sector + i as u64)) {
+                    chunk.copy_from_slice(&data[..]);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn write(&mut self, sector: u64, buf: &[u8]) -> io::Result<()> {
+        if self.read_only {
+            return Err(io::ErrorKind::PermissionDenied.into());
+        }
+
+        self.check_range(sector, buf.len())?;
+
+        match &mut self.overlay {
+            Some(overlay) => {
+                for (i, chunk) in buf.chunks(SECTOR_SIZE).enumerate() {
+                    let mut data = Box::new([0; SECTOR_SIZE]);
+                    data.copy_from_slice(chunk);
+                    overlay.insert(sector + i as u64, data);
+                }
+            }
+            None => {
+                self.file
+                    .seek(SeekFrom::Start(sector * SECTOR_SIZE as u64))?;
+                self.file.write_all(buf)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        if self.read_only || self.overlay.is_some() {
+            return Ok(());
+        }
+
+        self.file.sync_data()
+    }
+}
+
+/// A buffer of a descriptor chain
+#[derive(Debug, Clone, Copy)]
+struct Buffer {
+    addr: usize,
+    len: usize,
+    writable: bool,
+}
+
+/// A split virtqueue, with the guest physical addresses of its areas
+#[derive(Debug, Default)]
+struct Queue {
+    num: u16,
+    ready: bool,
+    desc: u64,
+    driver: u64,
+    device: u64,
+    /// the next entry of the available ring to process
+    last_avail: u16,
+}
+
+/// Set the low or high half of a 64 bit address
+fn set_half(value: &mut u64, high: bool, half: u32) {
+    *value = if high {
+        *value & 0xffff_ffff | (half as u64) << 32
+    } else {
+        *value & !0xffff_ffff | half as u64
+    };
+}
+
+fn read_u16(memory: &VirtualMemory, addr: u64) -> Result<u16, vm::Error> {
+    let mut buf = [0; 2];
+    memory.read_slice(addr as usize, &mut buf)?;
+    Ok(u16::from_le_bytes(buf))
+}
+
+/// A virtio block device on the virtio-mmio transport. The requests are handled
+/// between instructions after the driver notifies the queue
+#[derive(Debug)]
+pub struct VirtioBlock {
+    disk: Disk,
+    interrupt: Option<Interrupt>,
+    device_features_sel: u32,
+    driver_features: u64,
+    driver_features_sel: u32,
+    queue_sel: u32,
+    queue: Queue,
+    notified: bool,
+    interrupt_status: u32,
+    status: u32,
+}
+
+impl VirtioBlock {
+    pub fn new(disk: Disk) -> Self {
+        Self {
+            disk,
+            interrupt: None,
+            device_features_sel: 0,
+            driver_features: 0,
+            driver_features_sel: 0,
+            queue_sel: 0,
+            queue: Queue::default(),
+            notified: false,
+            interrupt_status: 0,
+            status: 0,
+        }
+    }
+
+    /// Connect the interrupt of the device
+    pub fn set_interrupt(&mut self, interrupt: Interrupt) {
+        self.interrupt = Some(interrupt);
+        self.update_interrupt();
+    }
+
+    fn features(&self) -> u64 {
+        let read_only = if self.disk.read_only() { BLK_F_RO } else { 0 };
+        F_VERSION_1 | BLK_F_FLUSH | read_only
+    }
+
+    fn update_interrupt(&self) {
+        if let Some(interrupt) = &self.interrupt {
+            interrupt.set(self.interrupt_status != 0);
+        }
+    }
+
+    /// Reset the transport and the queue, the disk is kept
+    fn reset(&mut self) {
+        self.device_features_sel = 0;
+        self.driver_features = 0;
+        self.driver_features_sel = 0;
+        self.queue_sel = 0;
+        self.queue = Queue::default();
+        self.notified = false;
+        self.interrupt_status = 0;
+        self.status = 0;
+
+        self.update_interrupt();
+    }
+
+    /// The configuration space, with the capacity in sectors
+    fn config(&self, offset: usize, size: usize) -> Option<u64> {
+        let capacity = self.disk.sectors().to_le_bytes();
+        let bytes = capacity.get(offset..offset + size)?;
+
+        let mut value = [0; 8];
+        value[..size].copy_from_slice(bytes);
+        Some(u64::from_le_bytes(value))
+    }
+
+    /// Follow the descriptor chain of a head
+    fn chain(&self, memory: &VirtualMemory, head: u16) -> Result<Vec<Buffer>, Error> {
+        let mut buffers = Vec::new();
+        let mut index = head;
+
+        // a chain can't be longer than the queue, which catches loops
+        for _ in 0..self.queue.num {
+            let mut desc = [0; 16];
+            let addr = self.queue.desc + 16 * index as u64;
+            memory.read_slice(addr as usize, &mut desc)?;
+
+            let flags = u16::from_le_bytes([desc[12], desc[13]]);
+            buffers.push(Buffer {
+                addr: u64::from_le_bytes(desc[0..8].try_into().unwrap()) as usize,
+                len: u32::from_le_bytes(desc[8..12].try_into().unwrap()) as usize,
+                writable: flags & DESC_F_WRITE != 0,
+            });
+
+            if flags & DESC_F_NEXT == 0 {
+                return Ok(buffers);
+            }
+            index = u16::from_le_bytes([desc[14], desc[15]]) % self.queue.num;
+        }
+
+        Err(Error::DescriptorLoop)
+    }
+
+    /// Handle a block request, and return the number of bytes written to the
+    /// buffers of the driver
+    fn request(&mut self, memory: &mut VirtualMemory, buffers: &[Buffer]) -> Result<u32, Error> {
+        // the header and the data of writes are in the readable buffers, the data
+        // of reads and the status byte are in the writable buffers. The status is
+        // the last byte of the last one, so it can't be empty
+        let (writable, readable): (Vec<&Buffer>, Vec<_>) =
+            buffers.iter().partition(|buffer| buffer.writable);
+        let readable_len: usize = readable.iter().map(|buffer| buffer.len).sum();
+        let capacity: usize = writable.iter().map(|buffer| buffer.len).sum();
+
+        let last = match writable.last() {
+            Some(last) if last.len != 0 => last,
+            _ => return Err(Error::MalformedRequest),
+        };
+        if !(16..=16 + MAX_DATA).contains(&readable_len) || capacity > MAX_DATA + 1 {
+            return Err(Error::MalformedRequest);
+        }
+
+        let mut request = Vec::with_capacity(readable_len);
+        for buffer in readable {
+            let start = request.len();
+            request.resize(start + buffer.len, 0);
+            memory.read_slice(buffer.addr, &mut request[start..])?;
+        }
+
+        let kind = u32::from_le_bytes(request[0..4].try_into().unwrap());
+        let sector = u64::from_le_bytes(request[8..16].try_into().unwrap());
+
+        let status = |result: io::Result<()>| match result {
+            Ok(()) => BLK_S_OK,
+            Err(_) => BLK_S_IOERR,
+        };
+
+        // the reads fill the writable buffers but the status byte
+        let (status, data) = match kind {
+            BLK_T_IN => match self.disk.check_range(sector, capacity - 1) {
+                Ok(()) => {
+                    let mut data = vec![0; capacity - 1];
+                    (status(self.disk.read(sector, &mut data)), data)
+                }
+                Err(_) => (BLK_S_IOERR, Vec::new()),
+            },
+            BLK_T_OUT => (status(self.disk.write(sector, &request[16..])), Vec::new()),
+            BLK_T_FLUSH => (status(self.disk.flush()), Vec::new()),
+            BLK_T_GET_ID => {
+                let mut id = b"risky".to_vec();
+                id.resize(ID_LEN, 0);
+                id.truncate(capacity - 1);
+                (BLK_S_OK, id)
+            }
+            _ => (BLK_S_UNSUPP, Vec::new()),
+        };
+
+        let mut rest = &data[..];
+        for buffer in &writable {
+            let len = buffer.len.min(rest.len());
+            memory.write_slice(buffer.addr, &rest[..len])?;
+            rest = &rest[len..];
+        }
+
+        memory.write_slice(last.addr + last.len - 1, &[status])?;
+
+        Ok(data.len() as u32 + 1)
+    }
+
+    /// Process the available buffers of the queue, and return the used ones
+    fn process(&mut self, memory: &mut VirtualMemory) -> Result<(), Error> {
+        let num = self.queue.num;
+        let avail_idx = read_u16(memory, self.queue.driver + 2)?;
+        let mut used_idx = read_u16(memory, self.queue.device + 2)?;
+
+        let mut used = false;
+        while self.queue.last_avail != avail_idx {
+            let slot = self.queue.driver + 4 + 2 * (self.queue.last_avail % num) as u64;
+            let head = read_u16(memory, slot)? % num;
+
+            let buffers = self.chain(memory, head)?;
+            let len = self.request(memory, &buffers)?;
+
+            let mut element = [0; 8];
+            element[0..4].copy_from_slice(&(head as u32).to_le_bytes());
+            element[4..8].copy_from_slice(&len.to_le_bytes());
+            let slot = self.queue.device + 4 + 8 * (used_idx % num) as u64;
+            memory.write_slice(slot as usize, &element)?;
+
+            used_idx = used_idx.wrapping_add(1);
+            memory.write_slice(self.queue.device as usize + 2, &used_idx.to_le_bytes())?;
+
+            self.queue.last_avail = self.queue.last_avail.wrapping_add(1);
+            used = true;
+        }
+
+        let flags = read_u16(memory, self.queue.driver)?;
+        if used && flags & AVAIL_F_NO_INTERRUPT == 0 {
+            self.interrupt_status |= INTERRUPT_USED_BUFFER;
+        }
+
+        Ok(())
+    }
+}
+
+impl Device for VirtioBlock {
+    fn read(&mut self, offset: usize, size: usize) -> Option<u64> {
+        if offset >= CONFIG {
+            return self.config(offset - CONFIG, size);
+        }
+
+        if size != 4 {
+            return None;
+        }
+
+        let selected = self.queue_sel == 0;
+        let queue = &self.queue;
+        let value = match offset {
+            MAGIC_VALUE => MAGIC,
+            VERSION => 2,
+            DEVICE_ID => BLOCK_DEVICE,
+            VENDOR_ID => VENDOR,
+            DEVICE_FEATURES => match self.device_features_sel {
+                0 => self.features() as u32,
+                1 => (self.features() >> 32) as u32,
+                _ => 0,
+            },
+            // there is a single request queue, the others don't exist
+            QUEUE_NUM_MAX if selected => QUEUE_SIZE.into(),
+            QUEUE_READY if selected => queue.ready.into(),
+            INTERRUPT_STATUS => self.interrupt_status,
+            STATUS => self.status,
+            QUEUE_DESC_LOW if selected => queue.desc as u32,
+            QUEUE_DESC_HIGH if selected => (queue.desc >> 32) as u32,
+            QUEUE_DRIVER_LOW if selected => queue.driver as u32,
+            QUEUE_DRIVER_HIGH if selected => (queue.driver >> 32) as u32,
+            QUEUE_DEVICE_LOW if selected => queue.device as u32,
+            QUEUE_DEVICE_HIGH if selected => (queue.device >> 32) as u32,
+            CONFIG_GENERATION => 0,
+            _ => 0,
+        };
+
+        Some(value.into())
+    }
+
+    fn write(&mut self, offset: usize, size: usize, value: u64) -> Option<()> {
+        if size != 4 {
+            return None;
+        }
+
+        let value = value as u32;
+        let selected = self.queue_sel == 0;
+        let queue = &mut self.queue;
+
+        match offset {
+            DEVICE_FEATURES_SEL => self.device_features_sel = value,
+            DRIVER_FEATURES => match self.driver_features_sel {
+                0 => set_half(&mut self.driver_features, false, value),
+                1 => set_half(&mut self.driver_features, true, value),
+                _ => {}
+            },
+            DRIVER_FEATURES_SEL => self.driver_features_sel = value,
+            QUEUE_SEL => self.queue_sel = value,
+            QUEUE_NUM if selected => queue.num = (value as u16).clamp(1, QUEUE_SIZE),
+            // the queue can't be used before its size is set
+            QUEUE_READY if selected => queue.ready = value & 1 != 0 && queue.num != 0,
+            QUEUE_DESC_LOW if selected => set_half(&mut queue.desc, false, value),
+            QUEUE_DESC_HIGH if selected => set_half(&mut queue.desc, true, value),
+            QUEUE_DRIVER_LOW if selected => set_half(&mut queue.driver, false, value),
+            QUEUE_DRIVER_HIGH if selected => set_half(&mut queue.driver, true, value),
+            QUEUE_DEVICE_LOW if selected => set_half(&mut queue.device, false, value),
+            QUEUE_DEVICE_HIGH if selected => set_half(&mut queue.device, true, value),
+            QUEUE_NOTIFY if value == 0 => self.notified = true,
+            INTERRUPT_ACK => {
+                self.interrupt_status &= !value;
+                self.update_interrupt();
+            }
+            STATUS if value == 0 => self.reset(),
+            STATUS => {
+                // the features can't be accepted without the version 1 interface,
+                // or with features the device doesn't offer
+                let accepted = self.driver_features & F_VERSION_1 != 0
+                    && self.driver_features & !self.features() == 0;
+                self.status = if accepted {
+                    value
+                } else {
+                    value & !STATUS_FEATURES_OK
+                };
+            }
+            _ => {}
+        }
+
+        Some(())
+    }
+
+    fn poll(&mut self, memory: &mut VirtualMemory) {
+        if !std::mem::take(&mut self.notified) || !self.queue.ready {
+            return;
+        }
+
+        // a broken queue needs a reset by the driver
+        if self.process(memory).is_err() {
+            self.status |= STATUS_NEEDS_RESET;
+            self.interrupt_status |= INTERRUPT_CONFIG_CHANGE;
+        }
+
+        self.update_interrupt();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::elf::Segment;
+
+    /// The queue areas and the buffers of the requests in guest memory
+    const DESC: usize = 0x1000;
+    const AVAIL: usize = 0x2000;
+    const USED: usize = 0x3000;
+    const HEADER: usize = 0x4000;
+    const DATA: usize = 0x5000;
+    const STATUS_BYTE: usize = 0x6000;
+
+    fn image(name: &str, sectors: usize) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("risky-{}-{}", name, std::process::id()));
+        let data: Vec<u8> = (0..sectors * SECTOR_SIZE)
+            .map(|i| (i / SECTOR_SIZE) as u8)
+            .collect();
+        std::fs::write(&path, data).unwrap();
+        path
+    }
+
+    /// The guest memory holding the queue and the buffers
+    fn memory() -> VirtualMemory {
+        let mut memory = VirtualMemory::default();
+        memory
+            .insert(Segment {
+                start: 0,
+                protection: 0b110.into(),
+                data: vec![0; 0x8000],
+            })
+            .unwrap();
+        memory
+    }
+
+    /// A block device with its queue set up by the driver
+    fn device(disk: Disk) -> (VirtioBlock, VirtualMemory) {
+        let memory = memory();
+
+        let mut block = VirtioBlock::new(disk);
+        assert_eq!(block.read(MAGIC_VALUE, 4), Some(MAGIC.into()));
+        assert_eq!(block.read(DEVICE_ID, 4), Some(2));
+
+        block.write(DRIVER_FEATURES_SEL, 4, 1).unwrap();
+        block.write(DRIVER_FEATURES, 4, 1).unwrap();
+        block.write(STATUS, 4, 0xb).unwrap();
+        assert_eq!(block.read(STATUS, 4), Some(0xb));
+
+        block.write(QUEUE_NUM, 4, 8).unwrap();
+        block.write(QUEUE_DESC_LOW, 4, DESC as u64).unwrap();
+        block.write(QUEUE_DRIVER_LOW, 4, AVAIL as u64).unwrap();
+        block.write(QUEUE_DEVICE_LOW, 4, USED as u64).unwrap();
+        block.write(QUEUE_READY, 4, 1).unwrap();
+
+        (block, memory)
+    }
+
+    /// Submit a request with a header, a data buffer and a status byte
+    fn submit(block: &mut VirtioBlock, memory: &mut VirtualMemory, kind: u32, sector: u64) {
+        submit_chain(block, memory, kind, sector, SECTOR_SIZE, 1);
+    }
+
+    /// Submit a request with buffers of the lengths given by the driver
+    fn submit_chain(
+        block: &mut VirtioBlock,
+        memory: &mut VirtualMemory,
+        kind: u32,
+        sector: u64,
+        data_len: usize,
+        status_len: usize,
+    ) {
+        let mut header = [0; 16];
+        header[0..4].copy_from_slice(&kind.to_le_bytes());
+        header[8..16].copy_from_slice(&sector.to_le_bytes());
+        memory.write_slice(HEADER, &header).unwrap();
+
+        let data_flags = if kind == BLK_T_IN { DESC_F_WRITE } else { 0 };
+        let descs = [
+            (HEADER, 16, DESC_F_NEXT, 1),
+            (DATA, data_len, data_flags | DESC_F_NEXT, 2),
+            (STATUS_BYTE, status_len, DESC_F_WRITE, 0),
+        ];
+        for (i, (addr, len, flags, next)) in descs.into_iter().enumerate() {
+            let mut desc = [0; 16];
+            desc[0..8].copy_from_slice(&(addr as u64).to_le_bytes());
+            desc[8..12].copy_from_slice(&(len as u32).to_le_bytes());
+            desc[12..14].copy_from_slice(&flags.to_le_bytes());
+            desc[14..16].copy_from_slice(&(next as u16).to_le_bytes());
+            memory.write_slice(DESC + 16 * i, &desc).unwrap();
+        }
+
+        let idx = read_u16(memory, AVAIL as u64 + 2).unwrap();
+        memory
+            .write_slice(AVAIL + 4 + 2 * (idx % 8) as usize, &0u16.to_le_bytes())
+            .unwrap();
+        memory
+            .write_slice(AVAIL + 2, &idx.wrapping_add(1).to_le_bytes())
+            .unwrap();
+
+        block.write(QUEUE_NOTIFY, 4, 0).unwrap();
+        block.poll(memory);
+    }
+
+    #[test]
+    fn read_write() {
+        let path = image("read-write", 4);
+        let (mut block, mut memory) = device(Disk::open(&path, false, false).unwrap());
+        assert_eq!(block.read(CONFIG, 8), Some(4));
+
+        submit(&mut block, &mut memory, BLK_T_IN, 2);
+        assert_eq!(memory.read(STATUS_BYTE).unwrap(), BLK_S_OK);
+        assert_eq!(memory.read(DATA + 10).unwrap(), 2);
+
+        // the used ring returns the head with the written length
+        assert_eq!(read_u16(&memory, USED as u64 + 2).unwrap(), 1);
+        assert_eq!(memory.read_u32(USED + 8).unwrap(), SECTOR_SIZE as u32 + 1);
+        assert_eq!(block.read(INTERRUPT_STATUS, 4), Some(1));
+        block.write(INTERRUPT_ACK, 4, 1).unwrap();
+        assert_eq!(block.read(INTERRUPT_STATUS, 4), Some(0));
+
+        memory.write_slice(DATA, &[0xaa; SECTOR_SIZE]).unwrap();
+        submit(&mut block, &mut memory, BLK_T_OUT, 1);
+        assert_eq!(memory.read(STATUS_BYTE).unwrap(), BLK_S_OK);
+        assert_eq!(memory.read_u32(USED + 16).unwrap(), 1);
+
+        let image = std::fs::read(&path).unwrap();
+        assert_eq!(image[SECTOR_SIZE..2 * SECTOR_SIZE], [0xaa; SECTOR_SIZE]);
+
+        // out of range
+        submit(&mut block, &mut memory, BLK_T_IN, 4);
+        assert_eq!(memory.read(STATUS_BYTE).unwrap(), BLK_S_IOERR);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn read_only() {
+        let path = image("read-only", 2);
+        let (mut block, mut memory) = device(Disk::open(&path, true, false).unwrap());
+
+        assert_eq!(block.read(DEVICE_FEATURES, 4), Some(BLK_F_RO | BLK_F_FLUSH));
+
+        submit(&mut block, &mut memory, BLK_T_OUT, 0);
+        assert_eq!(memory.read(STATUS_BYTE).unwrap(), BLK_S_IOERR);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn overlay() {
+        let path = image("overlay", 2);
+        let (mut block, mut memory) = device(Disk::open(&path, false, true).unwrap());
+
+        memory.write_slice(DATA, &[0x55; SECTOR_SIZE]).unwrap();
+        submit(&mut block, &mut memory, BLK_T_OUT, 1);
+        memory.write_slice(DATA, &[0; SECTOR_SIZE]).unwrap();
+        submit(&mut block, &mut memory, BLK_T_IN, 1);
+
+        // the write is only visible to the guest
+        assert_eq!(memory.read(DATA).unwrap(), 0x55);
+        assert_eq!(std::fs::read(&path).unwrap()[SECTOR_SIZE], 1);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn ready_without_size() {
+        let path = image("ready-without-size", 1);
+        let mut block = VirtioBlock::new(Disk::open(&path, false, false).unwrap());
+        let mut memory = memory();
+
+        block.write(QUEUE_DESC_LOW, 4, DESC as u64).unwrap();
+        block.write(QUEUE_DRIVER_LOW, 4, AVAIL as u64).unwrap();
+        block.write(QUEUE_DEVICE_LOW, 4, USED as u64).unwrap();
+        block.write(QUEUE_READY, 4, 1).unwrap();
+        assert_eq!(block.read(QUEUE_READY, 4), Some(0));
+
+        submit(&mut block, &mut memory, BLK_T_IN, 0);
+        assert_eq!(read_u16(&memory, USED as u64 + 2).unwrap(), 0);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn queue_sel() {
+        let path = image("queue-sel", 1);
+        let (mut block, _) = device(Disk::open(&path, false, false).unwrap());
+
+        block.write(QUEUE_SEL, 4, 1).unwrap();
+        block.write(QUEUE_DESC_LOW, 4, 0x1234).unwrap();
+        for offset in [QUEUE_NUM_MAX, QUEUE_READY, QUEUE_DESC_LOW, QUEUE_DEVICE_LOW] {
+            assert_eq!(block.read(offset, 4), Some(0));
+        }
+
+        block.write(QUEUE_SEL, 4, 0).unwrap();
+        assert_eq!(block.read(QUEUE_READY, 4), Some(1));
+        assert_eq!(block.read(QUEUE_DESC_LOW, 4), Some(DESC as u64));
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn malformed() {
+        let path = image("malformed", 4);
+
+        // lengths past the disk or the status byte fail before anything is
+        // allocated, an empty status buffer breaks the queue
+        let requests = [
+            (BLK_T_IN, u32::MAX as usize, 1),
+            (BLK_T_OUT, u32::MAX as usize, 1),
+            (BLK_T_IN, SECTOR_SIZE, 0),
+            (BLK_T_IN, MAX_DATA + 1, 1),
+        ];
+        for (kind, data_len, status_len) in requests {
+            let (mut block, mut memory) = device(Disk::open(&path, false, false).unwrap());
+            submit_chain(&mut block, &mut memory, kind, 0, data_len, status_len);
+
+            assert_eq!(
+                block.read(STATUS, 4),
+                Some((0xb | STATUS_NEEDS_RESET).into())
+            );
+            assert_eq!(read_u16(&memory, USED as u64 + 2).unwrap(), 0);
+        }
+
+        // a read longer than the disk is an error of the request
+        let (mut block, mut memory) = device(Disk::open(&path, false, false).unwrap());
+        submit_chain(&mut block, &mut memory, BLK_T_IN, 0, 8 * SECTOR_SIZE, 1);
+        assert_eq!(memory.read(STATUS_BYTE).unwrap(), BLK_S_IOERR);
+        assert_eq!(memory.read_u32(USED + 8).unwrap(), 1);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn features() {
+        let path = image("features", 1);
+        let mut block = VirtioBlock::new(Disk::open(&path, false, false).unwrap());
+
+        // the legacy interface is not supported
+        block.write(STATUS, 4, 0xb).unwrap();
+        assert_eq!(block.read(STATUS, 4), Some(0x3));
+
+        block.write(STATUS, 4, 0).unwrap();
+        assert_eq!(block.read(STATUS, 4), Some(0));
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -1,5 +1,17 @@
 use crate::elf::{Protection, Segment};
-use std::collections::BTreeMap;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Debug;
+use std::ops::RangeInclusive;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// The granularity of mappings and protection
+pub const PAGE_SIZE: usize = 4096;
+
+/// The default maximum size of the heap, every heap page is mapped when the
+/// break moves so an unlimited heap could exhaust the memory of the host
+pub const DEFAULT_HEAP_LIMIT: usize = 1 << 30;
 
 #[derive(Debug)]
 pub enum Error {
@@ -17,12 +29,241 @@ pub enum Error {
         required: Protection,
     },
     UnmappedAddress(usize),
+    NotPageAligned(usize),
+    Misaligned {
+        addr: usize,
+        size: usize,
+    },
+    /// an instruction fetch from an address that is not mapped executable
+    InstructionAccessFault(usize),
+    /// mappings can't be empty
+    EmptyMapping,
+    /// there is no free range of this length to map
+    NoFreeRange(usize),
+    /// a device doesn't support an access of this size at the address
+    DeviceAccess {
+        addr: usize,
+        size: usize,
+    },
+}
+
+/// A memory mapped device. The sized accesses to its region are routed to it,
+/// with the offset into the region and the access width in bytes (1, 2, 4 or 8).
+/// Values are little endian, like the registers of most peripherals
+pub trait Device: Debug {
+    /// Read a zero extended value, `None` if the access is not supported
+    fn read(&mut self, offset: usize, size: usize) -> Option<u64>;
+
+    /// Write the low `size` bytes of the value, `None` if the access is not supported
+    fn write(&mut self, offset: usize, size: usize, value: u64) -> Option<()>;
+
+    /// Handle the host events, like input, and the work that needs the memory,
+    /// like DMA. Called between instructions with the memory the device is mapped in
+    fn poll(&mut self, _memory: &mut VirtualMemory) {}
+}
+
+/// The accesses a watchpoint is hit by
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    Execute,
+}
+
+impl std::fmt::Display for WatchKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Read => "read",
+            Self::Write => "write",
+            Self::Execute => "execute",
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Watchpoint {
+    start: usize,
+    len: usize,
+    kind: WatchKind,
+}
+
+/// A shared handle to a device, so the machine can keep one to it
+pub type SharedDevice = Rc<RefCell<dyn Device>>;
+
+#[derive(Debug)]
+struct DeviceRegion {
+    start: usize,
+    len: usize,
+    device: SharedDevice,
+}
+
+impl DeviceRegion {
+    /// Check if the region overlaps the non empty range from start to the inclusive last
+    fn overlaps(&self, start: usize, last: usize) -> bool {
+        start < self.start + self.len && self.start <= last
+    }
+}
+
+/// What happens to accesses that are not naturally aligned
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MisalignedPolicy {
+    /// access the bytes at the unaligned address in one piece, checked like any
+    /// other access but without an alignment requirement
+    #[default]
+    Allow,
+    /// fail with `Error::Misaligned`, like hardware without misaligned access support
+    Trap,
+    /// split the access into byte accesses, like a trap handler emulating it,
+    /// so a failing access can be partially done
+    Emulate,
+}
+
+/// Flags of `VirtualMemory::map`, the subset of the mmap flags that is supported
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MapFlags {
+    /// map exactly at the hint, replacing the existing mappings, like MAP_FIXED
+    pub fixed: bool,
+}
+
+const READ: Protection = Protection {
+    r: true,
+    w: false,
+    x: false,
+};
+
+const WRITE: Protection = Protection {
+    r: false,
+    w: true,
+    x: false,
+};
+
+const EXECUTE: Protection = Protection {
+    r: false,
+    w: false,
+    x: true,
+};
+
+#[derive(Clone)]
+struct Page {
+    protection: Protection,
+    /// zero pages are not allocated until they are written
+    data: Option<Box<[u8; PAGE_SIZE]>>,
+}
+
+impl Page {
+    fn data_mut(&mut self) -> &mut [u8; PAGE_SIZE] {
+        self.data.get_or_insert_with(|| Box::new([0; PAGE_SIZE]))
+    }
+}
+
+/// Split the range (addr, len) at page boundaries into
+/// (page number, range in the page, range in the buffer) parts
+fn page_parts(
+    addr: usize,
+    len: usize,
+) -> impl Iterator<Item = (usize, std::ops::Range<usize>, std::ops::Range<usize>)> {
+    let mut done = 0;
+
+    std::iter::from_fn(move || {
+        if done == len {
+            return None;
+        }
+
+        let current = addr + done;
+        let offset = current % PAGE_SIZE;
+        let part = (PAGE_SIZE - offset).min(len - done);
+
+        let result = (
+            current / PAGE_SIZE,
+            offset..offset + part,
+            done..done + part,
+        );
+        done += part;
+
+        Some(result)
+    })
+}
+
+/// Generate little endian read and write functions for an integer type
+macro_rules! typed_access {
+    ($read:ident, $write:ident, $type:ty) => {
+        #[doc = concat!("Read a little endian `", stringify!($type), "`")]
+        pub fn $read(&self, addr: usize) -> Result<$type, Error> {
+            let mut buf = [0; std::mem::size_of::<$type>()];
+            self.read_sized(addr, &mut buf)?;
+            self.watch(addr, buf.len(), WatchKind::Read);
+            Ok(<$type>::from_le_bytes(buf))
+        }
+
+        #[doc = concat!("Write a little endian `", stringify!($type), "`")]
+        pub fn $write(&mut self, addr: usize, val: $type) -> Result<(), Error> {
+            let buf = val.to_le_bytes();
+            self.write_sized(addr, &buf)?;
+            self.watch(addr, buf.len(), WatchKind::Write);
+            Ok(())
+        }
+    };
+}
+
+/// Identifies snapshots, so a restore knows if the dirty pages are relative to it
+static NEXT_SNAPSHOT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// The mappings and program break of a `VirtualMemory`, saved by `snapshot`
+pub struct Snapshot {
+    id: u64,
+    pages: HashMap<usize, Page>,
+    brk_start: usize,
+    brk: usize,
 }
 
-/// A Segmented Virtual Memory implementation
-#[derive(Debug, Default)]
+/// A Paged Virtual Memory implementation
 pub struct VirtualMemory {
-    segments: BTreeMap<usize, Segment>,
+    /// mapped pages indexed by page number. Every mapped page has an entry, only
+    /// the data of the pages that were never written is left unallocated
+    pages: HashMap<usize, Page>,
+    misaligned: MisalignedPolicy,
+    /// `map` searches for free ranges below this address
+    map_base: usize,
+    /// the start of the heap and the current program break
+    brk_start: usize,
+    brk: usize,
+    /// the maximum size of the heap, like RLIMIT_DATA
+    heap_limit: Option<usize>,
+    /// memory mapped devices, they don't have pages
+    devices: Vec<DeviceRegion>,
+    watchpoints: Vec<Watchpoint>,
+    /// the watchpoints hit since they were last taken, as (address, kind)
+    watchpoint_hits: RefCell<Vec<(usize, WatchKind)>>,
+    /// the last snapshot taken or restored, and the pages changed since then
+    snapshot_id: Option<u64>,
+    dirty: HashSet<usize>,
+}
+
+impl Default for VirtualMemory {
+    fn default() -> Self {
+        Self {
+            pages: HashMap::new(),
+            misaligned: MisalignedPolicy::default(),
+            map_base: usize::MAX / PAGE_SIZE * PAGE_SIZE,
+            brk_start: 0,
+            brk: 0,
+            heap_limit: Some(DEFAULT_HEAP_LIMIT),
+            devices: Vec::new(),
+            watchpoints: Vec::new(),
+            watchpoint_hits: RefCell::new(Vec::new()),
+            snapshot_id: None,
+            dirty: HashSet::new(),
+        }
+    }
+}
+
+impl Debug for VirtualMemory {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("VirtualMemory")
+            .field("mappings", &self.mappings())
+            .field("devices", &self.devices)
+            .finish()
+    }
 }
 
 impl VirtualMemory {
@@ -36,6 +277,65 @@ impl VirtualMemory {
         Ok(vm)
     }
 
+    /// Get the sorted numbers of the mapped pages
+    fn page_numbers(&self) -> Vec<usize> {
+        let mut numbers: Vec<usize> = self.pages.keys().copied().collect();
+        numbers.sort_unstable();
+        numbers
+    }
+
+    /// Get the address ranges of the mappings without their data, consecutive pages
+    /// with the same protection are merged into a single range. The ranges are
+    /// inclusive, the last page ends at the top of the address space
+    fn mappings(&self) -> Vec<(RangeInclusive<usize>, Protection)> {
+        let mut mappings: Vec<(RangeInclusive<usize>, Protection)> = Vec::new();
+
+        for number in self.page_numbers() {
+            let protection = self.pages[&number].protection;
+            let start = number * PAGE_SIZE;
+            let end = start + (PAGE_SIZE - 1);
+
+            match mappings.last_mut() {
+                Some((last, last_protection))
+                    if *last.end() + 1 == start && *last_protection == protection =>
+                {
+                    *last = *last.start()..=end;
+                }
+                _ => mappings.push((start..=end, protection)),
+            }
+        }
+
+        mappings
+    }
+
+    /// Get the mappings as segments, consecutive pages with the same protection
+    /// are merged into a single segment
+    pub fn segments(&self) -> Vec<Segment> {
+        let mut segments: Vec<Segment> = Vec::new();
+
+        for number in self.page_numbers() {
+            let page = &self.pages[&number];
+            let start = number * PAGE_SIZE;
+            let data = page.data.as_deref().unwrap_or(&[0; PAGE_SIZE]);
+
+            match segments.last_mut() {
+                Some(last)
+                    if last.start + last.data.len() == start
+                        && last.protection == page.protection =>
+                {
+                    last.data.extend_from_slice(data);
+                }
+                _ => segments.push(Segment {
+                    start,
+                    protection: page.protection,
+                    data: data.to_vec(),
+                }),
+            }
+        }
+
+        segments
+    }
+
     fn check_protection(
         addr: usize,
         available: Protection,
@@ -52,365 +352,953 @@ impl VirtualMemory {
         }
     }
 
-    pub fn read(&self, addr: usize) -> Result<u8, Error> {
-        let segment = self.get_segment(addr)?;
-        let i = addr - segment.start;
-
-        Self::check_protection(
-            addr,
-            segment.protection,
-            Protection {
-                r: true,
-                w: false,
-                x: false,
-            },
-        )?;
+    /// Check that the range (addr, len) is mapped with the required protection
+    /// before accessing any part of it, so failing accesses don't have side effects.
+    /// The range can span multiple mappings, the errors point to the first byte
+    /// that is not mapped or doesn't have the required protection
+    fn check_range(&self, addr: usize, len: usize, required: Protection) -> Result<(), Error> {
+        // the range can't wrap around the end of the address space
+        if addr.checked_add(len).is_none() {
+            return Err(Error::SliceOutOfBounds { addr, len });
+        }
 
-        Ok(segment.data[i])
-    }
+        // devices only take sized accesses, not copies of their memory
+        if len != 0 {
+            let device = self
+                .devices
+                .iter()
+                .find(|region| region.overlaps(addr, addr + len - 1));
+            if let Some(region) = device {
+                return Err(Error::DeviceAccess {
+                    addr: addr.max(region.start),
+                    size: len,
+                });
+            }
+        }
 
-    pub fn read_slice(&self, addr: usize, buf: &mut [u8]) -> Result<(), Error> {
-        let segment = self.get_segment(addr)?;
-        let len = buf.len();
-        let i = addr - segment.start;
-
-        Self::check_protection(
-            addr,
-            segment.protection,
-            Protection {
-                r: true,
-                w: false,
-                x: false,
-            },
-        )?;
+        for (number, _, buf_range) in page_parts(addr, len) {
+            let part_addr = addr + buf_range.start;
 
-        if addr + len > segment.start + segment.data.len() {
-            return Err(Error::SliceOutOfBounds { addr, len });
-        }
+            let page = self
+                .pages
+                .get(&number)
+                .ok_or(Error::UnmappedAddress(part_addr))?;
 
-        buf.copy_from_slice(&segment.data[i..i + len]);
+            Self::check_protection(part_addr, page.protection, required)?;
+        }
 
         Ok(())
     }
 
-    pub fn write(&mut self, addr: usize, val: u8) -> Result<(), Error> {
-        let segment = self.get_mut_segment(addr)?;
-        let i = addr - segment.start;
-
-        Self::check_protection(
-            addr,
-            segment.protection,
-            Protection {
-                r: false,
-                w: true,
-                x: false,
-            },
-        )?;
+    /// Watch the accesses of `kind` to the range (start, len)
+    pub fn add_watchpoint(&mut self, start: usize, len: usize, kind: WatchKind) {
+        self.watchpoints.push(Watchpoint { start, len, kind });
+    }
 
-        segment.data[i] = val;
+    /// Remove a watchpoint added with the same arguments, false if there is none
+    pub fn remove_watchpoint(&mut self, start: usize, len: usize, kind: WatchKind) -> bool {
+        let watchpoint = Watchpoint { start, len, kind };
+        let count = self.watchpoints.len();
 
-        Ok(())
+        self.watchpoints.retain(|x| *x != watchpoint);
+
+        self.watchpoints.len() != count
     }
 
-    pub fn write_slice(&mut self, addr: usize, buf: &[u8]) -> Result<(), Error> {
-        let segment = self.get_mut_segment(addr)?;
-        let len = buf.len();
-        let i = addr - segment.start;
-
-        Self::check_protection(
-            addr,
-            segment.protection,
-            Protection {
-                r: false,
-                w: true,
-                x: false,
-            },
-        )?;
+    /// Get and clear the watchpoint hits in the order of the accesses, as the
+    /// accessed address and kind
+    pub fn take_watchpoint_hits(&mut self) -> Vec<(usize, WatchKind)> {
+        self.watchpoint_hits.take()
+    }
 
-        if addr + len > segment.start + segment.data.len() {
-            return Err(Error::SliceOutOfBounds { addr, len });
+    /// Record a hit for each watchpoint of `kind` the access to the range
+    /// (addr, len) touches. It is called once per access, after it succeeded
+    fn watch(&self, addr: usize, len: usize, kind: WatchKind) {
+        if self.watchpoints.is_empty() || len == 0 {
+            return;
         }
 
-        segment.data[i..i + len].copy_from_slice(buf);
+        let last = addr.saturating_add(len - 1);
 
-        Ok(())
+        let hits = self.watchpoints.iter().filter(|watchpoint| {
+            watchpoint.kind == kind
+                && watchpoint.len != 0
+                && addr <= watchpoint.start.saturating_add(watchpoint.len - 1)
+                && watchpoint.start <= last
+        });
+
+        self.watchpoint_hits
+            .borrow_mut()
+            .extend(hits.map(|watchpoint| (addr.max(watchpoint.start), kind)));
     }
 
-    /// get the sorted keys of segments that would overlap the segment (new, len)
-    fn get_overlapping(&self, new: usize, len: usize) -> Vec<usize> {
-        // IMPORTANT
-        // this assumes that there are no existing overlaps between segments
-        // that can only happen if a segment wasn't mapped with the `insert` function
+    pub fn read(&self, addr: usize) -> Result<u8, Error> {
+        if self.device_at(addr, 1)?.is_some() {
+            return self.read_u8(addr);
+        }
 
-        let mut overlapping = Vec::new();
+        let value = self.read_page_byte(addr)?;
+        self.watch(addr, 1, WatchKind::Read);
 
-        let end = new + len;
+        Ok(value)
+    }
 
-        // get the overlap from a previous segment
-        // | old |
-        //   | new |
-        let remaining_range = if let Ok(segment) = self.get_segment(new) {
-            overlapping.push(segment.start);
-            let segment_end = segment.start + segment.data.len();
+    /// Read a byte of a mapped page, without watching it
+    fn read_page_byte(&self, addr: usize) -> Result<u8, Error> {
+        let page = self.get_page(addr)?;
 
-            // prevent a negative range
-            if segment_end < end {
-                segment_end..end
-            } else {
-                end..end
+        Self::check_protection(addr, page.protection, READ)?;
+
+        Ok(page.data.as_ref().map_or(0, |data| data[addr % PAGE_SIZE]))
+    }
+
+    /// Copy mapped memory into the buffer, the range has to be checked first
+    fn copy_from_pages(&self, addr: usize, buf: &mut [u8]) {
+        for (number, page_range, buf_range) in page_parts(addr, buf.len()) {
+            match &self.pages[&number].data {
+                Some(data) => buf[buf_range].copy_from_slice(&data[page_range]),
+                None => buf[buf_range].fill(0),
             }
-        } else {
-            new..end
-        };
+        }
+    }
 
-        // get the overlas from segments starting further
-        //   | old | | old |
-        // |    new    |
-        overlapping.extend(self.segments.range(remaining_range).map(|(&i, _)| i));
+    pub fn read_slice(&self, addr: usize, buf: &mut [u8]) -> Result<(), Error> {
+        self.check_range(addr, buf.len(), READ)?;
+        self.watch(addr, buf.len(), WatchKind::Read);
+        self.copy_from_pages(addr, buf);
 
-        // make sure the results are sorted
-        debug_assert_eq!(overlapping, {
-            let mut clone = overlapping.clone();
-            clone.sort();
-            clone
-        });
+        Ok(())
+    }
 
-        overlapping
+    /// Read a little endian `u64` for the hart itself, like a page table walk.
+    /// The guest didn't ask for it, so it doesn't hit the watchpoints
+    pub fn read_u64_implicit(&self, addr: usize) -> Result<u64, Error> {
+        let mut buf = [0; 8];
+        self.read_sized(addr, &mut buf)?;
+        Ok(u64::from_le_bytes(buf))
     }
 
-    pub fn insert(&mut self, segment: Segment) -> Result<(), Error> {
-        // ignore zero sized segments
-        if segment.data.is_empty() {
-            return Ok(());
-        }
+    /// Write a little endian `u64` for the hart itself, like the accessed and
+    /// dirty bits of a page table entry, without hitting the watchpoints
+    pub fn write_u64_implicit(&mut self, addr: usize, val: u64) -> Result<(), Error> {
+        self.write_sized(addr, &val.to_le_bytes())
+    }
 
-        let overlapping = self.get_overlapping(segment.start, segment.data.len());
+    /// Fetch a little endian 32 bit instruction. Only the execute permission is
+    /// checked, and every failure is an `InstructionAccessFault` at the first byte
+    /// that can't be fetched, so it can be told apart from a faulting load
+    pub fn fetch_u32(&self, addr: usize) -> Result<u32, Error> {
+        let mut buf = [0; 4];
 
-        if overlapping.is_empty() {
-            self.segments.insert(segment.start, segment);
-            Ok(())
-        } else {
-            Err(Error::InsertOverlap {
-                overlapping,
-                new: segment.start,
-            })
+        self.check_range(addr, buf.len(), EXECUTE)
+            .map_err(|err| match err {
+                Error::UnmappedAddress(addr) | Error::Protection { addr, .. } => {
+                    Error::InstructionAccessFault(addr)
+                }
+                // wrapping around the address space
+                _ => Error::InstructionAccessFault(addr),
+            })?;
+        self.watch(addr, buf.len(), WatchKind::Execute);
+        self.copy_from_pages(addr, &mut buf);
+
+        Ok(u32::from_le_bytes(buf))
+    }
+
+    pub fn write(&mut self, addr: usize, val: u8) -> Result<(), Error> {
+        if self.device_at(addr, 1)?.is_some() {
+            return self.write_u8(addr, val);
         }
+
+        self.write_page_byte(addr, val)?;
+        self.watch(addr, 1, WatchKind::Write);
+
+        Ok(())
     }
 
-    fn resize_or_unmap_or_split_segment(&mut self, key: usize, del_start: usize, del_len: usize) {
-        let segment = self.segments.get(&key).unwrap();
-        let (orig_start, orig_len) = (segment.start, segment.data.len());
-        let (del_end, orig_end) = (del_start + del_len, orig_start + orig_len);
+    /// Write a byte of a mapped page, without watching it
+    fn write_page_byte(&mut self, addr: usize, val: u8) -> Result<(), Error> {
+        let page = self.get_mut_page(addr)?;
 
-        if del_start <= orig_start && del_end >= orig_end {
-            // if there is a total overlap just remove the segment
-            self.segments.remove(&key);
-        } else if del_start <= orig_start || del_end >= orig_end {
-            // if there is an overlap remove, resize, then reinsert
-            let mut segment = self.segments.remove(&key).unwrap();
+        Self::check_protection(addr, page.protection, WRITE)?;
 
-            let (keep_range, new_start) = if orig_start < del_start {
-                // |   old   |
-                // | keep |  del  |
-                (0..del_start - orig_start, orig_start)
-            } else {
-                //    |    old    |
-                // | del |  keep  |
-                (del_end - orig_start..orig_len, del_end)
-            };
+        page.data_mut()[addr % PAGE_SIZE] = val;
+
+        Ok(())
+    }
+
+    /// Copy the buffer into mapped memory, the range has to be checked first
+    fn copy_to_pages(&mut self, addr: usize, buf: &[u8]) {
+        for (number, page_range, buf_range) in page_parts(addr, buf.len()) {
+            self.mark_dirty(number);
+            let page = self.pages.get_mut(&number).unwrap();
+            page.data_mut()[page_range].copy_from_slice(&buf[buf_range]);
+        }
+    }
 
-            // keep slice of data
-            segment.data = segment.data.drain(keep_range).collect();
+    pub fn write_slice(&mut self, addr: usize, buf: &[u8]) -> Result<(), Error> {
+        self.check_range(addr, buf.len(), WRITE)?;
+        self.watch(addr, buf.len(), WatchKind::Write);
+        self.copy_to_pages(addr, buf);
 
-            // set start
-            segment.start = new_start;
+        Ok(())
+    }
 
-            // it's safe to not use the `vm::insert` function here, because the mappings
-            // didn't change since we unmapped the original one
-            self.segments.insert(segment.start, segment);
-        } else if del_start > orig_start && del_end < orig_end {
-            // if an inner slice needs to get unmapped: remove, split, reinsert
-            let segment = self.segments.remove(&key).unwrap();
+    pub fn misaligned_policy(&self) -> MisalignedPolicy {
+        self.misaligned
+    }
 
-            let head = Segment {
-                start: segment.start,
-                protection: segment.protection,
-                data: Vec::from(&segment.data[0..del_start - orig_start]),
-            };
+    pub fn set_misaligned_policy(&mut self, policy: MisalignedPolicy) {
+        self.misaligned = policy;
+    }
 
-            let tail = Segment {
-                start: del_end,
-                protection: segment.protection,
-                data: Vec::from(&segment.data[del_end - orig_start..]),
-            };
+    /// Get the device region the access (addr, size) is in. Accesses that are
+    /// partially in a region are not supported by the device
+    fn device_at(&self, addr: usize, size: usize) -> Result<Option<&DeviceRegion>, Error> {
+        let Some(region) = self
+            .devices
+            .iter()
+            .find(|region| region.overlaps(addr, addr.saturating_add(size - 1)))
+        else {
+            return Ok(None);
+        };
 
-            // it's safe to not use the `vm::insert` function here, because the mappings
-            // didn't change since we unmapped the original one
-            self.segments.insert(head.start, head);
-            self.segments.insert(tail.start, tail);
+        if addr < region.start || addr.saturating_add(size) > region.start + region.len {
+            return Err(Error::DeviceAccess { addr, size });
         }
+
+        Ok(Some(region))
     }
 
-    /// unmap a region defined by (start, len)
-    pub fn unmap(&mut self, start: usize, len: usize) -> Result<(), Error> {
-        let overlapping = self.get_overlapping(start, len);
+    /// Read a naturally sized value, following the misaligned access policy. The
+    /// callers watch the access, so it's a single hit even when it's emulated
+    fn read_sized(&self, addr: usize, buf: &mut [u8]) -> Result<(), Error> {
+        let size = buf.len();
+        if let Some(region) = self.device_at(addr, size)? {
+            let value = region
+                .device
+                .borrow_mut()
+                .read(addr - region.start, size)
+                .ok_or(Error::DeviceAccess { addr, size })?;
+            buf.copy_from_slice(&value.to_le_bytes()[..size]);
+            return Ok(());
+        }
 
-        match overlapping.len() {
-            0 => Ok(()),
-            1 => {
-                self.resize_or_unmap_or_split_segment(overlapping[0], start, len);
+        let unaligned = !addr.is_multiple_of(buf.len());
+
+        match self.misaligned {
+            MisalignedPolicy::Trap if unaligned => Err(Error::Misaligned {
+                addr,
+                size: buf.len(),
+            }),
+            MisalignedPolicy::Emulate if unaligned => {
+                for (i, byte) in buf.iter_mut().enumerate() {
+                    *byte = self.read_page_byte(addr.wrapping_add(i))?;
+                }
                 Ok(())
             }
             _ => {
-                self.resize_or_unmap_or_split_segment(overlapping[0], start, len);
-                self.resize_or_unmap_or_split_segment(
-                    overlapping[overlapping.len() - 1],
-                    start,
-                    len,
-                );
-
-                // the segments between the last and first overlap get entirely unmapped
-                for key in &overlapping[1..overlapping.len() - 1] {
-                    self.segments.remove(key).unwrap();
-                }
-
+                self.check_range(addr, buf.len(), READ)?;
+                self.copy_from_pages(addr, buf);
                 Ok(())
             }
         }
     }
 
-    /// Get the key of the segment containing the address
-    fn get_segment_key(&self, addr: usize) -> Option<usize> {
-        self.segments
-            .range(..=addr)
-            .last()
-            .and_then(|(&key, last)| {
-                // check if the last segment in long enough
-                if addr >= last.start && addr < last.start + last.data.len() {
-                    Some(key)
-                } else {
-                    None
+    /// Write a naturally sized value, following the misaligned access policy. The
+    /// callers watch the access, like for `read_sized`
+    fn write_sized(&mut self, addr: usize, buf: &[u8]) -> Result<(), Error> {
+        let size = buf.len();
+        if let Some(region) = self.device_at(addr, size)? {
+            let mut bytes = [0; 8];
+            bytes[..size].copy_from_slice(buf);
+            return region
+                .device
+                .borrow_mut()
+                .write(addr - region.start, size, u64::from_le_bytes(bytes))
+                .ok_or(Error::DeviceAccess { addr, size });
+        }
+
+        let unaligned = !addr.is_multiple_of(buf.len());
+
+        match self.misaligned {
+            MisalignedPolicy::Trap if unaligned => Err(Error::Misaligned {
+                addr,
+                size: buf.len(),
+            }),
+            MisalignedPolicy::Emulate if unaligned => {
+                for (i, &byte) in buf.iter().enumerate() {
+                    self.write_page_byte(addr.wrapping_add(i), byte)?;
                 }
-            })
+                Ok(())
+            }
+            _ => {
+                self.check_range(addr, buf.len(), WRITE)?;
+                self.copy_to_pages(addr, buf);
+                Ok(())
+            }
+        }
     }
 
-    /// get the segment which the address is in
-    fn get_segment(&self, addr: usize) -> Result<&Segment, Error> {
-        self.get_segment_key(addr)
-            .and_then(|key| self.segments.get(&key))
-            .ok_or(Error::UnmappedAddress(addr))
+    pub fn read_u8(&self, addr: usize) -> Result<u8, Error> {
+        let mut buf = [0];
+        self.read_sized(addr, &mut buf)?;
+        self.watch(addr, 1, WatchKind::Read);
+        Ok(buf[0])
     }
 
-    /// get the segment which the address is in
-    fn get_mut_segment(&mut self, addr: usize) -> Result<&mut Segment, Error> {
-        self.get_segment_key(addr)
-            .and_then(|key| self.segments.get_mut(&key))
-            .ok_or(Error::UnmappedAddress(addr))
+    pub fn write_u8(&mut self, addr: usize, val: u8) -> Result<(), Error> {
+        self.write_sized(addr, &[val])?;
+        self.watch(addr, 1, WatchKind::Write);
+        Ok(())
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    typed_access!(read_u16, write_u16, u16);
+    typed_access!(read_u32, write_u32, u32);
+    typed_access!(read_u64, write_u64, u64);
 
-    #[test]
-    fn last_possible() {
-        let segments = vec![
-            Segment {
-                start: 123,
-                protection: 0.into(),
-                data: vec![0; 10],
-            },
-            Segment {
-                start: 140,
-                protection: 0.into(),
-                data: vec![0; 10],
-            },
-        ]
-        .into_iter();
+    /// get the sorted addresses of mapped pages that would overlap the region (new, len)
+    fn get_overlapping(&self, new: usize, len: usize) -> Vec<usize> {
+        if len == 0 {
+            return Vec::new();
+        }
 
-        let vm = VirtualMemory::try_from_iter(segments).unwrap();
+        let first = new / PAGE_SIZE;
+        let last = (new + len - 1) / PAGE_SIZE;
 
-        assert!(matches!(
-            vm.get_segment(122),
-            Err(Error::UnmappedAddress(..))
-        ));
-        assert_eq!(vm.get_segment(123).unwrap().start, 123);
-        assert_eq!(vm.get_segment(123 + 1).unwrap().start, 123);
-        assert_eq!(vm.get_segment(123 + 9).unwrap().start, 123);
-        assert!(matches!(
-            vm.get_segment(123 + 10),
-            Err(Error::UnmappedAddress(..))
-        ));
-        assert_eq!(vm.get_segment(140).unwrap().start, 140);
-        assert_eq!(vm.get_segment(140 + 5).unwrap().start, 140);
-        assert_eq!(vm.get_segment(140 + 9).unwrap().start, 140);
-        assert!(matches!(
-            vm.get_segment(140 + 10),
-            Err(Error::UnmappedAddress(..))
-        ));
+        if last - first < self.pages.len() {
+            (first..=last)
+                .filter(|number| self.pages.contains_key(number))
+                .map(|number| number * PAGE_SIZE)
+                .collect()
+        } else {
+            // don't walk huge ranges page by page
+            self.page_numbers()
+                .into_iter()
+                .filter(|number| (first..=last).contains(number))
+                .map(|number| number * PAGE_SIZE)
+                .collect()
+        }
     }
 
-    #[test]
-    fn insert_empty() {
-        let mut vm = VirtualMemory::default();
+    /// get the sorted start addresses of the devices in the pages of the region (new, len)
+    fn get_overlapping_devices(&self, new: usize, len: usize) -> Vec<usize> {
+        if len == 0 {
+            return Vec::new();
+        }
 
-        vm.insert(Segment {
-            start: 0,
-            protection: 0.into(),
-            data: vec![],
-        })
-        .unwrap();
+        let start = new / PAGE_SIZE * PAGE_SIZE;
+        let last = (new + len - 1) | (PAGE_SIZE - 1);
 
-        assert!(vm.segments.is_empty());
+        let mut overlapping: Vec<usize> = self
+            .devices
+            .iter()
+            .filter(|region| region.overlaps(start, last))
+            .map(|region| region.start)
+            .collect();
+        overlapping.sort_unstable();
+        overlapping
     }
 
-    #[test]
-    fn insert() {
-        let mut vm = VirtualMemory::default();
+    /// Check that no page or device is mapped in the pages of the region (new, len)
+    fn is_free(&self, new: usize, len: usize) -> bool {
+        self.get_overlapping(new, len).is_empty()
+            && self.get_overlapping_devices(new, len).is_empty()
+    }
 
-        let segments = vec![
-            Segment {
-                start: 0,
-                protection: 0.into(),
-                data: vec![0; 10],
-            },
-            Segment {
-                start: 10,
-                protection: 0.into(),
-                data: vec![0; 10],
-            },
-            Segment {
-                start: 21,
-                protection: 0.into(),
-                data: vec![0; 10],
-            },
-        ]
-        .into_iter();
+    /// Copy the data of a segment into mapped pages, leaving zero pages unallocated
+    fn copy_segment_data(&mut self, segment: &Segment) {
+        for (number, page_range, buf_range) in page_parts(segment.start, segment.data.len()) {
+            let data = &segment.data[buf_range];
+            self.mark_dirty(number);
+            let page = self.pages.get_mut(&number).unwrap();
 
-        for segment in segments {
-            vm.insert(segment).unwrap();
+            if page.data.is_some() || data.iter().any(|&byte| byte != 0) {
+                page.data_mut()[page_range].copy_from_slice(data);
+            }
         }
-
-        assert_eq!(vm.segments.len(), 3);
     }
 
-    #[test]
-    fn insert_overlap_left() {
-        let mut vm = VirtualMemory::default();
+    /// Map the pages of a segment. The pages containing the segment are mapped
+    /// entirely, so the segment can't share a page with an existing mapping.
+    /// The data covers the whole segment, zeros included
+    pub fn insert(&mut self, segment: Segment) -> Result<(), Error> {
+        // ignore zero sized segments
+        if segment.data.is_empty() {
+            return Ok(());
+        }
 
-        vm.insert(Segment {
-            start: 0,
-            protection: 0.into(),
-            data: vec![0; 10],
-        })
-        .unwrap();
+        if segment.start.checked_add(segment.data.len()).is_none() {
+            return Err(Error::SliceOutOfBounds {
+                addr: segment.start,
+                len: segment.data.len(),
+            });
+        }
 
-        assert!(matches!(
-            vm.insert(Segment {
-                start: 9,
+        let mut overlapping = self.get_overlapping(segment.start, segment.data.len());
+        overlapping.extend(self.get_overlapping_devices(segment.start, segment.data.len()));
+
+        if !overlapping.is_empty() {
+            return Err(Error::InsertOverlap {
+                overlapping,
+                new: segment.start,
+            });
+        }
+
+        for (number, _, _) in page_parts(segment.start, segment.data.len()) {
+            self.insert_page(
+                number,
+                Page {
+                    protection: segment.protection,
+                    data: None,
+                },
+            );
+        }
+
+        self.copy_segment_data(&segment);
+
+        Ok(())
+    }
+
+    /// Map a device to the region (start, len). Like `insert` the region can't
+    /// overlap another device, or the pages of other mappings
+    pub fn insert_device(
+        &mut self,
+        start: usize,
+        len: usize,
+        device: SharedDevice,
+    ) -> Result<(), Error> {
+        if len == 0 {
+            return Ok(());
+        }
+
+        if start.checked_add(len).is_none() {
+            return Err(Error::SliceOutOfBounds { addr: start, len });
+        }
+
+        let mut overlapping = self.get_overlapping(start, len);
+        overlapping.extend(
+            self.devices
+                .iter()
+                .filter(|region| region.overlaps(start, start + len - 1))
+                .map(|region| region.start),
+        );
+
+        if !overlapping.is_empty() {
+            return Err(Error::InsertOverlap {
+                overlapping,
+                new: start,
+            });
+        }
+
+        self.devices.push(DeviceRegion { start, len, device });
+
+        Ok(())
+    }
+
+    /// Poll every mapped device
+    pub fn poll_devices(&mut self) {
+        for i in 0..self.devices.len() {
+            let device = self.devices[i].device.clone();
+            device.borrow_mut().poll(self);
+        }
+    }
+
+    /// Map the pages of a segment like `insert`, but pages that are already mapped
+    /// are shared, and get the union of the protections. This is for loading the
+    /// segments of an ELF, which are not always page aligned. A shared page that
+    /// ends up both writable and executable is reported on stderr. The segment
+    /// can't overlap a device
+    pub fn load(&mut self, segment: Segment) -> Result<(), Error> {
+        let len = segment.data.len();
+        if segment.start.checked_add(len).is_none() {
+            return Err(Error::SliceOutOfBounds {
+                addr: segment.start,
+                len,
+            });
+        }
+
+        let devices = self.get_overlapping_devices(segment.start, len);
+        if !devices.is_empty() {
+            return Err(Error::InsertOverlap {
+                overlapping: devices,
+                new: segment.start,
+            });
+        }
+
+        for (number, _, _) in page_parts(segment.start, segment.data.len()) {
+            self.mark_dirty(number);
+            self.pages
+                .entry(number)
+                .and_modify(|page| {
+                    let merged = page.protection | segment.protection;
+                    let wx = |protection: Protection| protection.w && protection.x;
+                    if wx(merged) && !wx(page.protection) && !wx(segment.protection) {
+                        eprintln!(
+                            "warning: page {:#x} is shared by writable and executable segments, \
+                             it is mapped rwx",
+                            number * PAGE_SIZE
+                        );
+                    }
+                    page.protection = merged;
+                })
+                .or_insert(Page {
+                    protection: segment.protection,
+                    data: None,
+                });
+        }
+
+        self.copy_segment_data(&segment);
+
+        Ok(())
+    }
+
+    /// Map the region (start, len) like RAM, start has to be page aligned. The pages
+    /// that are not mapped yet are zero pages, and every page gets the protection,
+    /// including the ones of segments loaded before
+    pub fn fill(&mut self, start: usize, len: usize, protection: Protection) -> Result<(), Error> {
+        if !start.is_multiple_of(PAGE_SIZE) {
+            return Err(Error::NotPageAligned(start));
+        }
+
+        if start.checked_add(len).is_none() {
+            return Err(Error::SliceOutOfBounds { addr: start, len });
+        }
+
+        let devices = self.get_overlapping_devices(start, len);
+        if !devices.is_empty() {
+            return Err(Error::InsertOverlap {
+                overlapping: devices,
+                new: start,
+            });
+        }
+
+        for (number, _, _) in page_parts(start, len) {
+            self.mark_dirty(number);
+            self.pages
+                .entry(number)
+                .or_insert(Page {
+                    protection,
+                    data: None,
+                })
+                .protection = protection;
+        }
+
+        Ok(())
+    }
+
+    pub fn map_base(&self) -> usize {
+        self.map_base
+    }
+
+    /// Set the address below which `map` places mappings, like `mmap_base` in linux
+    pub fn set_map_base(&mut self, base: usize) {
+        self.map_base = base / PAGE_SIZE * PAGE_SIZE;
+    }
+
+    /// Find the highest free range of `pages` pages below the map base
+    fn find_free_range(&self, pages: usize) -> Option<usize> {
+        // (first, end) page numbers of the mapped pages and the device regions
+        let mut used: Vec<(usize, usize)> = self
+            .pages
+            .keys()
+            .map(|&number| (number, number + 1))
+            .chain(self.devices.iter().map(|region| {
+                (
+                    region.start / PAGE_SIZE,
+                    (region.start + region.len).div_ceil(PAGE_SIZE),
+                )
+            }))
+            .collect();
+        used.sort_unstable();
+
+        // exclusive end page number of the free range
+        let mut end = self.map_base / PAGE_SIZE;
+
+        for (first, last) in used.into_iter().rev() {
+            if first >= end {
+                continue;
+            }
+
+            if last <= end && end - last >= pages {
+                break;
+            }
+
+            end = first;
+        }
+
+        end.checked_sub(pages).map(|start| start * PAGE_SIZE)
+    }
+
+    /// Map an anonymous zero filled region of `len` bytes rounded up to pages, and get
+    /// its address. Like mmap in linux, a free range at the page aligned `hint` is used
+    /// if there is one, otherwise the highest free range below the map base is used.
+    /// With `flags.fixed` the region is mapped exactly at `hint`, which has to be page
+    /// aligned, and the existing mappings in it are unmapped
+    pub fn map(
+        &mut self,
+        hint: usize,
+        len: usize,
+        protection: Protection,
+        flags: MapFlags,
+    ) -> Result<usize, Error> {
+        if len == 0 {
+            return Err(Error::EmptyMapping);
+        }
+
+        let size = len
+            .checked_next_multiple_of(PAGE_SIZE)
+            .ok_or(Error::SliceOutOfBounds { addr: hint, len })?;
+        let pages = size / PAGE_SIZE;
+
+        let start = if flags.fixed {
+            if hint.checked_add(size).is_none() {
+                return Err(Error::SliceOutOfBounds { addr: hint, len });
+            }
+
+            let devices = self.get_overlapping_devices(hint, size);
+            if !devices.is_empty() {
+                return Err(Error::InsertOverlap {
+                    overlapping: devices,
+                    new: hint,
+                });
+            }
+
+            self.unmap(hint, size)?;
+            hint
+        } else {
+            let hint = hint.checked_next_multiple_of(PAGE_SIZE).unwrap_or(0);
+
+            let free = hint != 0 && hint.checked_add(size).is_some() && self.is_free(hint, size);
+
+            if free {
+                hint
+            } else {
+                self.find_free_range(pages).ok_or(Error::NoFreeRange(len))?
+            }
+        };
+
+        for number in start / PAGE_SIZE..start / PAGE_SIZE + pages {
+            self.insert_page(
+                number,
+                Page {
+                    protection,
+                    data: None,
+                },
+            );
+        }
+
+        Ok(start)
+    }
+
+    /// unmap the pages in the region defined by (start, len), start has to be page aligned
+    pub fn unmap(&mut self, start: usize, len: usize) -> Result<(), Error> {
+        if !start.is_multiple_of(PAGE_SIZE) {
+            return Err(Error::NotPageAligned(start));
+        }
+
+        for addr in self.get_overlapping(start, len) {
+            self.mark_dirty(addr / PAGE_SIZE);
+            self.pages.remove(&(addr / PAGE_SIZE));
+        }
+
+        Ok(())
+    }
+
+    /// Start the heap at the page after `end`, which is usually the end of the
+    /// highest loaded segment. The program break is reset to the start
+    pub fn set_brk_start(&mut self, end: usize) {
+        self.brk_start = end.next_multiple_of(PAGE_SIZE);
+        self.brk = self.brk_start;
+    }
+
+    /// The current program break
+    pub fn current_brk(&self) -> usize {
+        self.brk
+    }
+
+    pub fn heap_limit(&self) -> Option<usize> {
+        self.heap_limit
+    }
+
+    /// Limit the size of the heap, `None` is unlimited. The default is
+    /// `DEFAULT_HEAP_LIMIT`
+    pub fn set_heap_limit(&mut self, limit: Option<usize>) {
+        self.heap_limit = limit;
+    }
+
+    /// Move the program break like the brk syscall of linux, and get the new break.
+    /// The heap pages are mapped read-write and zero filled when it grows, and
+    /// unmapped when it shrinks. When the break can't be moved, because it's below
+    /// the start of the heap, beyond the heap limit or the heap would overlap
+    /// another mapping, the current break is returned
+    pub fn brk(&mut self, brk: usize) -> usize {
+        if brk < self.brk_start {
+            return self.brk;
+        }
+
+        if let Some(limit) = self.heap_limit {
+            if brk - self.brk_start > limit {
+                return self.brk;
+            }
+        }
+
+        let Some(new_end) = brk.checked_next_multiple_of(PAGE_SIZE) else {
+            return self.brk;
+        };
+        let old_end = self.brk.next_multiple_of(PAGE_SIZE);
+
+        if new_end < old_end {
+            self.unmap(new_end, old_end - new_end).unwrap();
+        } else if new_end > old_end {
+            if !self.is_free(old_end, new_end - old_end) {
+                return self.brk;
+            }
+
+            let protection = READ | WRITE;
+            for number in old_end / PAGE_SIZE..new_end / PAGE_SIZE {
+                self.insert_page(
+                    number,
+                    Page {
+                        protection,
+                        data: None,
+                    },
+                );
+            }
+        }
+
+        self.brk = brk;
+        self.brk
+    }
+
+    /// Change the protection of the pages in the region defined by (start, len) like
+    /// mprotect, start has to be page aligned. All the pages have to be mapped,
+    /// otherwise nothing is changed
+    pub fn protect(
+        &mut self,
+        start: usize,
+        len: usize,
+        protection: Protection,
+    ) -> Result<(), Error> {
+        if !start.is_multiple_of(PAGE_SIZE) {
+            return Err(Error::NotPageAligned(start));
+        }
+
+        if start.checked_add(len).is_none() {
+            return Err(Error::SliceOutOfBounds { addr: start, len });
+        }
+
+        let numbers: Vec<usize> = page_parts(start, len)
+            .map(|(number, _, _)| number)
+            .collect();
+
+        if let Some(&number) = numbers
+            .iter()
+            .find(|number| !self.pages.contains_key(number))
+        {
+            return Err(Error::UnmappedAddress(number * PAGE_SIZE));
+        }
+
+        for number in numbers {
+            self.mark_dirty(number);
+            self.pages.get_mut(&number).unwrap().protection = protection;
+        }
+
+        Ok(())
+    }
+
+    /// Remember that a page changed since the last snapshot, if there is one
+    fn mark_dirty(&mut self, number: usize) {
+        if self.snapshot_id.is_some() {
+            self.dirty.insert(number);
+        }
+    }
+
+    fn insert_page(&mut self, number: usize, page: Page) {
+        self.mark_dirty(number);
+        self.pages.insert(number, page);
+    }
+
+    /// Save the mappings and the program break. The pages changed after the
+    /// snapshot are tracked, so restoring it only copies them back. Devices
+    /// keep their own state, and stay mapped
+    pub fn snapshot(&mut self) -> Snapshot {
+        let id = NEXT_SNAPSHOT_ID.fetch_add(1, Ordering::Relaxed);
+
+        self.snapshot_id = Some(id);
+        self.dirty.clear();
+
+        Snapshot {
+            id,
+            pages: self.pages.clone(),
+            brk_start: self.brk_start,
+            brk: self.brk,
+        }
+    }
+
+    /// Restore the mappings and the program break of a snapshot. Restoring the
+    /// last snapshot taken or restored only copies the pages changed since then,
+    /// other snapshots are copied entirely
+    pub fn restore(&mut self, snapshot: &Snapshot) {
+        if self.snapshot_id == Some(snapshot.id) {
+            for number in std::mem::take(&mut self.dirty) {
+                match snapshot.pages.get(&number) {
+                    Some(page) => self.pages.insert(number, page.clone()),
+                    None => self.pages.remove(&number),
+                };
+            }
+        } else {
+            self.pages = snapshot.pages.clone();
+            self.snapshot_id = Some(snapshot.id);
+            self.dirty.clear();
+        }
+
+        self.brk_start = snapshot.brk_start;
+        self.brk = snapshot.brk;
+    }
+
+    /// The number of pages changed since the last snapshot taken or restored
+    pub fn dirty_pages(&self) -> usize {
+        self.dirty.len()
+    }
+
+    /// get the page which the address is in
+    fn get_page(&self, addr: usize) -> Result<&Page, Error> {
+        self.pages
+            .get(&(addr / PAGE_SIZE))
+            .ok_or(Error::UnmappedAddress(addr))
+    }
+
+    /// get the page which the address is in, it's considered dirty
+    fn get_mut_page(&mut self, addr: usize) -> Result<&mut Page, Error> {
+        self.mark_dirty(addr / PAGE_SIZE);
+        self.pages
+            .get_mut(&(addr / PAGE_SIZE))
+            .ok_or(Error::UnmappedAddress(addr))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const P: usize = PAGE_SIZE;
+
+    #[test]
+    fn last_possible() {
+        let segments = vec![
+            Segment {
+                start: P,
                 protection: 0.into(),
-                data: vec![0; 10],
+                data: vec![0; 2 * P],
+            },
+            Segment {
+                start: 4 * P,
+                protection: 0.into(),
+                data: vec![0; P],
+            },
+        ]
+        .into_iter();
+
+        let vm = VirtualMemory::try_from_iter(segments).unwrap();
+
+        assert!(matches!(
+            vm.get_page(P - 1),
+            Err(Error::UnmappedAddress(..))
+        ));
+        assert!(vm.get_page(P).is_ok());
+        assert!(vm.get_page(P + 1).is_ok());
+        assert!(vm.get_page(3 * P - 1).is_ok());
+        assert!(matches!(
+            vm.get_page(3 * P),
+            Err(Error::UnmappedAddress(..))
+        ));
+        assert!(vm.get_page(4 * P).is_ok());
+        assert!(vm.get_page(4 * P + 5).is_ok());
+        assert!(vm.get_page(5 * P - 1).is_ok());
+        assert!(matches!(
+            vm.get_page(5 * P),
+            Err(Error::UnmappedAddress(..))
+        ));
+    }
+
+    #[test]
+    fn insert_empty() {
+        let mut vm = VirtualMemory::default();
+
+        vm.insert(Segment {
+            start: 0,
+            protection: 0.into(),
+            data: vec![],
+        })
+        .unwrap();
+
+        assert!(vm.pages.is_empty());
+    }
+
+    #[test]
+    fn insert() {
+        let mut vm = VirtualMemory::default();
+
+        let segments = vec![
+            Segment {
+                start: 0,
+                protection: 0.into(),
+                data: vec![0; 10 * P],
+            },
+            Segment {
+                start: 10 * P,
+                protection: 0.into(),
+                data: vec![0; 10 * P],
+            },
+            Segment {
+                start: 21 * P,
+                protection: 0.into(),
+                data: vec![0; 10 * P],
+            },
+        ]
+        .into_iter();
+
+        for segment in segments {
+            vm.insert(segment).unwrap();
+        }
+
+        assert_eq!(vm.pages.len(), 30);
+    }
+
+    #[test]
+    fn insert_sparse() {
+        let mut vm = VirtualMemory::default();
+
+        vm.insert(Segment {
+            start: 0,
+            protection: 0b110.into(),
+            data: vec![0; 1000 * P],
+        })
+        .unwrap();
+
+        vm.write(5 * P, 1).unwrap();
+
+        // only the written page is allocated
+        assert_eq!(vm.pages.len(), 1000);
+        assert_eq!(vm.pages.values().filter(|x| x.data.is_some()).count(), 1);
+    }
+
+    #[test]
+    fn insert_overlap_left() {
+        let mut vm = VirtualMemory::default();
+
+        vm.insert(Segment {
+            start: 0,
+            protection: 0.into(),
+            data: vec![0; 10 * P],
+        })
+        .unwrap();
+
+        assert!(matches!(
+            vm.insert(Segment {
+                start: 9 * P,
+                protection: 0.into(),
+                data: vec![0; 10 * P],
             }),
             Err(Error::InsertOverlap { .. })
         ));
@@ -421,9 +1309,9 @@ mod tests {
         let mut vm = VirtualMemory::default();
 
         vm.insert(Segment {
-            start: 9,
+            start: 9 * P,
             protection: 0.into(),
-            data: vec![0; 10],
+            data: vec![0; 10 * P],
         })
         .unwrap();
 
@@ -431,7 +1319,7 @@ mod tests {
             vm.insert(Segment {
                 start: 0,
                 protection: 0.into(),
-                data: vec![0; 10],
+                data: vec![0; 10 * P],
             }),
             Err(Error::InsertOverlap { .. })
         ));
@@ -442,9 +1330,9 @@ mod tests {
         let mut vm = VirtualMemory::default();
 
         vm.insert(Segment {
-            start: 1,
+            start: P,
             protection: 0.into(),
-            data: vec![0; 9],
+            data: vec![0; 9 * P],
         })
         .unwrap();
 
@@ -452,7 +1340,7 @@ mod tests {
             vm.insert(Segment {
                 start: 0,
                 protection: 0.into(),
-                data: vec![0; 10],
+                data: vec![0; 10 * P],
             }),
             Err(Error::InsertOverlap { .. })
         ));
@@ -465,15 +1353,15 @@ mod tests {
         vm.insert(Segment {
             start: 0,
             protection: 0.into(),
-            data: vec![0; 10],
+            data: vec![0; 10 * P],
         })
         .unwrap();
 
         assert!(matches!(
             vm.insert(Segment {
-                start: 1,
+                start: P,
                 protection: 0.into(),
-                data: vec![0; 9],
+                data: vec![0; 9 * P],
             }),
             Err(Error::InsertOverlap { .. })
         ));
@@ -487,12 +1375,12 @@ mod tests {
             Segment {
                 start: 0,
                 protection: 0.into(),
-                data: vec![0; 10],
+                data: vec![0; 10 * P],
             },
             Segment {
-                start: 20,
+                start: 20 * P,
                 protection: 0.into(),
-                data: vec![0; 10],
+                data: vec![0; 10 * P],
             },
         ]
         .into_iter();
@@ -503,22 +1391,188 @@ mod tests {
 
         assert!(matches!(
             vm.insert(Segment {
-                start: 9,
+                start: 9 * P,
                 protection: 0.into(),
-                data: vec![0; 11],
+                data: vec![0; 11 * P],
             }),
             Err(Error::InsertOverlap { .. })
         ));
     }
 
     #[test]
-    fn read() {
+    fn insert_overlap_same_page() {
         let mut vm = VirtualMemory::default();
 
         vm.insert(Segment {
-            start: 1234,
-            protection: 0b111.into(),
-            data: vec![111],
+            start: 0,
+            protection: 0.into(),
+            data: vec![0; 10],
+        })
+        .unwrap();
+
+        // the segments don't overlap, but their pages do
+        assert!(matches!(
+            vm.insert(Segment {
+                start: 10,
+                protection: 0.into(),
+                data: vec![0; 10],
+            }),
+            Err(Error::InsertOverlap { .. })
+        ));
+    }
+
+    #[test]
+    fn load_same_page() {
+        let mut vm = VirtualMemory::default();
+
+        vm.load(Segment {
+            start: P - 2,
+            protection: 0b101.into(),
+            data: vec![1, 2],
+        })
+        .unwrap();
+        vm.load(Segment {
+            start: P + 2,
+            protection: 0b110.into(),
+            data: vec![3, 4],
+        })
+        .unwrap();
+        vm.load(Segment {
+            start: 2 * P - 1,
+            protection: 0b100.into(),
+            data: vec![5, 6],
+        })
+        .unwrap();
+
+        let segments = vm.segments();
+        assert_eq!(segments.len(), 3);
+        assert_eq!(segments[0].protection, 0b101.into());
+        assert_eq!(segments[1].protection, 0b110.into());
+        assert_eq!(segments[2].protection, 0b100.into());
+
+        let mut buf = [0; 4];
+        vm.read_slice(P, &mut buf).unwrap();
+        assert_eq!(buf, [0, 0, 3, 4]);
+        assert_eq!(vm.read(2 * P - 1).unwrap(), 5);
+        assert_eq!(vm.read(2 * P).unwrap(), 6);
+    }
+
+    #[test]
+    fn load_shared_page() {
+        let mut vm = VirtualMemory::default();
+
+        vm.load(Segment {
+            start: 0,
+            protection: 0b101.into(),
+            data: vec![1, 2],
+        })
+        .unwrap();
+        vm.load(Segment {
+            start: 2,
+            protection: 0b110.into(),
+            data: vec![3, 4],
+        })
+        .unwrap();
+
+        // the shared page gets both protections, and keeps both contents
+        let segments = vm.segments();
+        assert_eq!(segments.len(), 1);
+        assert_eq!(segments[0].protection, 0b111.into());
+        assert_eq!(&segments[0].data[..5], &[1, 2, 3, 4, 0]);
+    }
+
+    #[test]
+    fn segments() {
+        let mut vm = VirtualMemory::default();
+
+        let segments = vec![
+            Segment {
+                start: 0,
+                protection: 0b100.into(),
+                data: vec![1; P],
+            },
+            Segment {
+                start: P,
+                protection: 0b100.into(),
+                data: vec![2; P],
+            },
+            Segment {
+                start: 2 * P,
+                protection: 0b110.into(),
+                data: vec![3; P],
+            },
+            Segment {
+                start: 4 * P,
+                protection: 0b110.into(),
+                data: vec![4; P],
+            },
+        ];
+
+        for segment in segments {
+            vm.insert(segment).unwrap();
+        }
+
+        let segments = vm.segments();
+
+        assert_eq!(segments.len(), 3);
+        assert_eq!(segments[0].start, 0);
+        assert_eq!(segments[0].data.len(), 2 * P);
+        assert_eq!(segments[0].data[P], 2);
+        assert_eq!(segments[1].start, 2 * P);
+        assert_eq!(segments[1].data.len(), P);
+        assert_eq!(segments[2].start, 4 * P);
+        assert_eq!(segments[2].data.len(), P);
+    }
+
+    #[test]
+    fn mappings() {
+        let mut vm = VirtualMemory::default();
+
+        for (start, protection) in [
+            (0, 0b100),
+            (P, 0b100),
+            (2 * P, 0b110),
+            (usize::MAX - 6, 0b110),
+        ] {
+            vm.insert(Segment {
+                start,
+                protection: protection.into(),
+                data: vec![1; 6],
+            })
+            .unwrap();
+        }
+
+        let mappings = vm.mappings();
+
+        assert_eq!(mappings.len(), 3);
+        assert_eq!(mappings[0], (0..=2 * P - 1, 0b100.into()));
+        assert_eq!(mappings[1], (2 * P..=3 * P - 1, 0b110.into()));
+        assert_eq!(mappings[2], (usize::MAX - P + 1..=usize::MAX, 0b110.into()));
+    }
+
+    #[test]
+    fn insert_wrapping() {
+        let mut vm = VirtualMemory::default();
+
+        assert!(matches!(
+            vm.insert(Segment {
+                start: usize::MAX - 6,
+                protection: 0b110.into(),
+                data: vec![1; 8],
+            }),
+            Err(Error::SliceOutOfBounds { .. })
+        ));
+        assert!(vm.mappings().is_empty());
+    }
+
+    #[test]
+    fn read() {
+        let mut vm = VirtualMemory::default();
+
+        vm.insert(Segment {
+            start: 1234,
+            protection: 0b111.into(),
+            data: vec![111],
         })
         .unwrap();
 
@@ -543,193 +1597,888 @@ mod tests {
     fn read_unmapped() {
         let vm = VirtualMemory::default();
 
-        assert!(matches!(vm.read(1234), Err(Error::UnmappedAddress(..))));
-    }
+        assert!(matches!(vm.read(1234), Err(Error::UnmappedAddress(..))));
+    }
+
+    #[test]
+    fn read_slice() {
+        let mut vm = VirtualMemory::default();
+
+        vm.insert(Segment {
+            start: 1234,
+            protection: 0b111.into(),
+            data: vec![1, 2, 3, 4, 5, 6],
+        })
+        .unwrap();
+
+        let mut buf = [0; 3];
+
+        vm.read_slice(1234 + 1, &mut buf).unwrap();
+
+        assert_eq!(buf, [2, 3, 4]);
+    }
+
+    #[test]
+    fn read_slice_across_pages() {
+        let mut vm = VirtualMemory::default();
+
+        vm.insert(Segment {
+            start: P - 3,
+            protection: 0b111.into(),
+            data: vec![1, 2, 3, 4, 5, 6],
+        })
+        .unwrap();
+
+        let mut buf = [0; 6];
+
+        vm.read_slice(P - 3, &mut buf).unwrap();
+
+        assert_eq!(buf, [1, 2, 3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn read_slice_protection() {
+        let mut vm = VirtualMemory::default();
+
+        vm.insert(Segment {
+            start: 1234,
+            protection: 0.into(),
+            data: vec![1, 2, 3, 4, 5, 6],
+        })
+        .unwrap();
+
+        let mut buf = [0; 3];
+
+        assert!(matches!(
+            vm.read_slice(1234 + 1, &mut buf),
+            Err(Error::Protection { .. })
+        ));
+    }
+
+    #[test]
+    fn read_slice_unmapped() {
+        let vm = VirtualMemory::default();
+
+        let mut buf = [0; 3];
+
+        assert!(matches!(
+            vm.read_slice(1234, &mut buf),
+            Err(Error::UnmappedAddress(..))
+        ));
+    }
+
+    #[test]
+    fn read_slice_oob() {
+        let mut vm = VirtualMemory::default();
+
+        vm.insert(Segment {
+            start: P - 6,
+            protection: 0b111.into(),
+            data: vec![1, 2, 3, 4, 5, 6],
+        })
+        .unwrap();
+
+        let mut buf = [0; 7];
+
+        assert!(matches!(
+            vm.read_slice(P - 6, &mut buf),
+            Err(Error::UnmappedAddress(P)),
+        ));
+    }
+
+    #[test]
+    fn read_slice_wrapping() {
+        let mut vm = VirtualMemory::default();
+
+        vm.insert(Segment {
+            start: usize::MAX - 6,
+            protection: 0b111.into(),
+            data: vec![1, 2, 3, 4, 5, 6],
+        })
+        .unwrap();
+
+        let mut buf = [0; 8];
+
+        assert!(matches!(
+            vm.read_slice(usize::MAX - 6, &mut buf),
+            Err(Error::SliceOutOfBounds { .. }),
+        ));
+    }
+
+    /// Map three adjacent pages: read-write, read-only and read-write, then a gap,
+    /// then another read-write page
+    fn adjacent() -> VirtualMemory {
+        let mut vm = VirtualMemory::default();
+
+        for (start, protection, fill) in [(0, 0b110, 1), (P, 0b100, 2), (2 * P, 0b110, 3)] {
+            vm.insert(Segment {
+                start,
+                protection: protection.into(),
+                data: vec![fill; P],
+            })
+            .unwrap();
+        }
+
+        vm.insert(Segment {
+            start: 4 * P,
+            protection: 0b110.into(),
+            data: vec![4; P],
+        })
+        .unwrap();
+
+        vm
+    }
+
+    #[test]
+    fn read_slice_across_segments() {
+        let vm = adjacent();
+
+        let mut buf = [0; P + 4];
+
+        vm.read_slice(P - 2, &mut buf).unwrap();
+
+        assert_eq!(&buf[..2], &[1, 1]);
+        assert!(buf[2..P + 2].iter().all(|&x| x == 2));
+        assert_eq!(&buf[P + 2..], &[3, 3]);
+    }
+
+    #[test]
+    fn read_slice_gap() {
+        let vm = adjacent();
+
+        let mut buf = [0; 2 * P];
+
+        assert!(matches!(
+            vm.read_slice(2 * P + 10, &mut buf),
+            Err(Error::UnmappedAddress(addr)) if addr == 3 * P
+        ));
+    }
+
+    #[test]
+    fn write() {
+        let mut vm = VirtualMemory::default();
+
+        vm.insert(Segment {
+            start: 1234,
+            protection: 0b111.into(),
+            data: vec![0],
+        })
+        .unwrap();
+
+        vm.write(1234, 1).unwrap();
+        assert!(matches!(vm.read(1234), Ok(1)));
+    }
+
+    #[test]
+    fn write_protection() {
+        let mut vm = VirtualMemory::default();
+
+        vm.insert(Segment {
+            start: 1234,
+            protection: 0b100.into(),
+            data: vec![0],
+        })
+        .unwrap();
+
+        assert!(matches!(vm.write(1234, 1), Err(Error::Protection { .. })));
+        assert!(matches!(vm.read(1234), Ok(0)));
+    }
+
+    #[test]
+    fn write_unmapped() {
+        let mut vm = VirtualMemory::default();
+
+        assert!(matches!(vm.write(1234, 1), Err(Error::UnmappedAddress(..))));
+    }
+
+    #[test]
+    fn write_slice() {
+        let mut vm = VirtualMemory::default();
+
+        vm.insert(Segment {
+            start: 1234,
+            protection: 0b111.into(),
+            data: vec![0; 6],
+        })
+        .unwrap();
+
+        let data = "asdasd".as_bytes();
+
+        vm.write_slice(1234, data).unwrap();
+
+        let mut buf = [0; 6];
+        vm.read_slice(1234, &mut buf).unwrap();
+
+        assert_eq!(buf, data);
+    }
+
+    #[test]
+    fn write_slice_across_pages() {
+        let mut vm = VirtualMemory::default();
+
+        vm.insert(Segment {
+            start: P - 3,
+            protection: 0b111.into(),
+            data: vec![0; 6],
+        })
+        .unwrap();
+
+        let data = "asdasd".as_bytes();
+
+        vm.write_slice(P - 3, data).unwrap();
+
+        let mut buf = [0; 6];
+        vm.read_slice(P - 3, &mut buf).unwrap();
+
+        assert_eq!(buf, data);
+    }
+
+    #[test]
+    fn write_slice_protection() {
+        let mut vm = VirtualMemory::default();
+
+        vm.insert(Segment {
+            start: 1234,
+            protection: 0b100.into(),
+            data: vec![0; 6],
+        })
+        .unwrap();
+
+        let data = "asdasd".as_bytes();
+
+        assert!(matches!(
+            vm.write_slice(1234, data),
+            Err(Error::Protection { .. })
+        ));
+
+        let mut buf = [0; 6];
+        vm.read_slice(1234, &mut buf).unwrap();
+
+        assert_eq!(buf, [0; 6]);
+    }
+
+    #[test]
+    fn write_slice_unmapped() {
+        let mut vm = VirtualMemory::default();
+
+        let data = "asdasd".as_bytes();
+
+        assert!(matches!(
+            vm.write_slice(1234, data),
+            Err(Error::UnmappedAddress { .. })
+        ));
+    }
+
+    #[test]
+    fn write_slice_oob() {
+        let mut vm = VirtualMemory::default();
+
+        vm.insert(Segment {
+            start: P - 6,
+            protection: 0b111.into(),
+            data: vec![0; 6],
+        })
+        .unwrap();
+
+        let data = &[1; 7];
+
+        assert!(matches!(
+            vm.write_slice(P - 6, data),
+            Err(Error::UnmappedAddress(P))
+        ));
+
+        let mut buf = [0; 6];
+        vm.read_slice(P - 6, &mut buf).unwrap();
+
+        assert_eq!(buf, [0; 6]);
+    }
+
+    #[test]
+    fn write_slice_across_segments() {
+        let mut vm = VirtualMemory::default();
+        vm.insert(numbered(0, 1)).unwrap();
+        vm.insert(numbered(P, 1)).unwrap();
+
+        vm.write_slice(P - 2, &[9, 9, 9, 9]).unwrap();
+
+        let mut buf = [0; 6];
+        vm.read_slice(P - 3, &mut buf).unwrap();
+
+        assert_eq!(buf, [1, 9, 9, 9, 9, 1]);
+    }
+
+    #[test]
+    fn write_slice_across_protection() {
+        let mut vm = adjacent();
+
+        // the second page is read-only, nothing gets written
+        assert!(matches!(
+            vm.write_slice(P - 2, &[9; 4]),
+            Err(Error::Protection { addr, .. }) if addr == P
+        ));
+
+        let mut buf = [0; 2];
+        vm.read_slice(P - 2, &mut buf).unwrap();
+
+        assert_eq!(buf, [1, 1]);
+    }
+
+    #[test]
+    fn typed() {
+        let mut vm = VirtualMemory::default();
+
+        vm.insert(Segment {
+            start: 0,
+            protection: 0b110.into(),
+            data: vec![0; 16],
+        })
+        .unwrap();
+
+        vm.write_u64(0, 0x0807_0605_0403_0201).unwrap();
+
+        assert_eq!(vm.read_u8(0).unwrap(), 0x01);
+        assert_eq!(vm.read_u16(2).unwrap(), 0x0403);
+        assert_eq!(vm.read_u32(4).unwrap(), 0x0807_0605);
+        assert_eq!(vm.read_u64(0).unwrap(), 0x0807_0605_0403_0201);
+
+        vm.write_u32(8, 0xdead_beef).unwrap();
+        vm.write_u16(12, 0xcafe).unwrap();
+        vm.write_u8(14, 0xff).unwrap();
+
+        assert_eq!(vm.read_u64(8).unwrap(), 0x00ff_cafe_dead_beef);
+    }
+
+    #[test]
+    fn misaligned_allow() {
+        let mut vm = VirtualMemory::default();
+
+        vm.insert(numbered(0, 2)).unwrap();
+
+        vm.write_u32(P - 1, 0x0403_0201).unwrap();
+
+        assert_eq!(vm.read_u16(P - 1).unwrap(), 0x0201);
+        assert_eq!(vm.read_u32(P - 1).unwrap(), 0x0403_0201);
+    }
+
+    #[test]
+    fn misaligned_trap() {
+        let mut vm = VirtualMemory::default();
+        vm.set_misaligned_policy(MisalignedPolicy::Trap);
+
+        vm.insert(numbered(0, 1)).unwrap();
+
+        assert!(vm.read_u32(4).is_ok());
+        assert!(matches!(
+            vm.read_u32(2),
+            Err(Error::Misaligned { addr: 2, size: 4 })
+        ));
+        assert!(matches!(
+            vm.write_u16(1, 0),
+            Err(Error::Misaligned { addr: 1, size: 2 })
+        ));
+        assert_eq!(vm.read_u8(1).unwrap(), 1);
+    }
+
+    #[test]
+    fn misaligned_emulate() {
+        let mut vm = VirtualMemory::default();
+        vm.set_misaligned_policy(MisalignedPolicy::Emulate);
+
+        vm.insert(numbered(0, 1)).unwrap();
+        vm.insert(Segment {
+            start: P,
+            protection: 0b100.into(),
+            data: vec![2; P],
+        })
+        .unwrap();
+
+        assert_eq!(vm.read_u32(P - 2).unwrap(), 0x0202_0101);
+
+        // the bytes before the read-only page are written, like a trap handler would
+        assert!(matches!(
+            vm.write_u32(P - 2, 0),
+            Err(Error::Protection { addr, .. }) if addr == P
+        ));
+        assert_eq!(vm.read_u32(P - 2).unwrap(), 0x0202_0000);
+    }
+
+    #[test]
+    fn map_top_down() {
+        let mut vm = VirtualMemory::default();
+        vm.set_map_base(100 * P);
+
+        let first = vm.map(0, 2 * P, 0b110.into(), MapFlags::default()).unwrap();
+        let second = vm.map(0, 1, 0b100.into(), MapFlags::default()).unwrap();
+
+        assert_eq!(first, 98 * P);
+        assert_eq!(second, 97 * P);
+
+        // anonymous mappings are zero filled
+        let mut buf = [1; 2 * P];
+        vm.read_slice(first, &mut buf).unwrap();
+        assert!(buf.iter().all(|&x| x == 0));
+
+        assert!(matches!(vm.write(second, 0), Err(Error::Protection { .. })));
+    }
+
+    #[test]
+    fn map_gap() {
+        let mut vm = VirtualMemory::default();
+        vm.set_map_base(100 * P);
+
+        vm.insert(numbered(97 * P, 3)).unwrap();
+        vm.insert(numbered(94 * P, 1)).unwrap();
+
+        // the gap between the two mappings is used when the range fits
+        let start = vm.map(0, 2 * P, 0b110.into(), MapFlags::default()).unwrap();
+        assert_eq!(start, 95 * P);
+
+        let start = vm.map(0, 2 * P, 0b110.into(), MapFlags::default()).unwrap();
+        assert_eq!(start, 92 * P);
+    }
+
+    #[test]
+    fn map_hint() {
+        let mut vm = VirtualMemory::default();
+        vm.set_map_base(100 * P);
+
+        // the hint is rounded up to a page
+        let start = vm
+            .map(10 * P - 1, P, 0b110.into(), MapFlags::default())
+            .unwrap();
+        assert_eq!(start, 10 * P);
+
+        // an occupied hint falls back to the search
+        let start = vm
+            .map(10 * P, P, 0b110.into(), MapFlags::default())
+            .unwrap();
+        assert_eq!(start, 99 * P);
+    }
+
+    #[test]
+    fn map_fixed() {
+        let mut vm = VirtualMemory::default();
+        let fixed = MapFlags { fixed: true };
+
+        vm.insert(numbered(10 * P, 3)).unwrap();
+
+        let start = vm.map(11 * P, P, 0b100.into(), fixed).unwrap();
+        assert_eq!(start, 11 * P);
+
+        // the pages around the replaced one are kept
+        assert_eq!(vm.read(10 * P).unwrap(), 1);
+        assert_eq!(vm.read(11 * P).unwrap(), 0);
+        assert_eq!(vm.read(12 * P).unwrap(), 3);
+        assert!(matches!(vm.write(11 * P, 0), Err(Error::Protection { .. })));
+
+        assert!(matches!(
+            vm.map(P + 1, P, 0b100.into(), fixed),
+            Err(Error::NotPageAligned(..))
+        ));
+    }
+
+    #[test]
+    fn map_errors() {
+        let mut vm = VirtualMemory::default();
+        vm.set_map_base(2 * P);
+
+        assert!(matches!(
+            vm.map(0, 0, 0b110.into(), MapFlags::default()),
+            Err(Error::EmptyMapping)
+        ));
+        assert!(matches!(
+            vm.map(0, 3 * P, 0b110.into(), MapFlags::default()),
+            Err(Error::NoFreeRange(..))
+        ));
+        assert!(matches!(
+            vm.map(
+                usize::MAX - P + 1,
+                2 * P,
+                0b110.into(),
+                MapFlags { fixed: true }
+            ),
+            Err(Error::SliceOutOfBounds { .. })
+        ));
+        // the length doesn't round up to a whole number of pages
+        assert!(matches!(
+            vm.map(0, usize::MAX, 0b110.into(), MapFlags::default()),
+            Err(Error::SliceOutOfBounds { .. })
+        ));
+    }
+
+    #[test]
+    fn protect() {
+        let mut vm = VirtualMemory::default();
+
+        vm.insert(numbered(10 * P, 3)).unwrap();
+
+        // the length is rounded up to a page
+        vm.protect(11 * P, 1, 0b100.into()).unwrap();
+
+        assert!(vm.write(10 * P, 0).is_ok());
+        assert!(matches!(vm.write(11 * P, 0), Err(Error::Protection { .. })));
+        assert!(vm.write(12 * P, 0).is_ok());
+
+        // the data is kept
+        assert_eq!(vm.read(11 * P).unwrap(), 2);
+
+        let segments = vm.segments();
+        assert_eq!(segments.len(), 3);
+        assert_eq!(segments[1].start, 11 * P);
+        assert_eq!(segments[1].data.len(), P);
+    }
+
+    #[test]
+    fn protect_unmapped() {
+        let mut vm = VirtualMemory::default();
+
+        vm.insert(numbered(10 * P, 1)).unwrap();
+        vm.insert(numbered(12 * P, 1)).unwrap();
+
+        assert!(matches!(
+            vm.protect(10 * P, 3 * P, 0b100.into()),
+            Err(Error::UnmappedAddress(addr)) if addr == 11 * P
+        ));
+        assert!(matches!(
+            vm.protect(10 * P + 1, P, 0b100.into()),
+            Err(Error::NotPageAligned(..))
+        ));
+
+        // nothing is changed when a page is missing
+        assert!(vm.write(10 * P, 0).is_ok());
+    }
+
+    #[test]
+    fn brk() {
+        let mut vm = VirtualMemory::default();
+
+        vm.insert(numbered(10 * P, 1)).unwrap();
+        vm.set_brk_start(11 * P - 100);
+
+        assert_eq!(vm.brk(0), 11 * P);
+        assert_eq!(vm.current_brk(), 11 * P);
+
+        assert_eq!(vm.brk(11 * P + 10), 11 * P + 10);
+        vm.write(11 * P + 9, 1).unwrap();
+
+        // the heap is not below its start
+        assert_eq!(vm.brk(10 * P), 11 * P + 10);
+
+        assert_eq!(vm.brk(13 * P + 1), 13 * P + 1);
+        vm.write(13 * P, 1).unwrap();
+        assert_eq!(vm.read(12 * P).unwrap(), 0);
+
+        // shrinking unmaps the pages that are not used anymore
+        assert_eq!(vm.brk(12 * P), 12 * P);
+        assert!(matches!(vm.read(12 * P), Err(Error::UnmappedAddress(..))));
+        assert_eq!(vm.read(11 * P + 9).unwrap(), 1);
+
+        // growing again gives zero pages
+        assert_eq!(vm.brk(14 * P), 14 * P);
+        assert_eq!(vm.read(13 * P).unwrap(), 0);
+    }
+
+    #[test]
+    fn brk_limits() {
+        let mut vm = VirtualMemory::default();
+
+        vm.set_brk_start(10 * P);
+        vm.set_heap_limit(Some(2 * P));
+        vm.insert(numbered(14 * P, 1)).unwrap();
+
+        assert_eq!(vm.brk(12 * P), 12 * P);
+        assert_eq!(vm.brk(12 * P + 1), 12 * P);
+
+        // the heap can't grow into other mappings
+        vm.set_heap_limit(None);
+        assert_eq!(vm.brk(15 * P), 12 * P);
+        assert_eq!(vm.brk(14 * P), 14 * P);
 
-    #[test]
-    fn read_slice() {
+        // the default limit stops a huge break before its pages are mapped
         let mut vm = VirtualMemory::default();
+        vm.set_brk_start(10 * P);
+        assert_eq!(vm.brk(usize::MAX / 2), 10 * P);
+        assert_eq!(
+            vm.brk(10 * P + DEFAULT_HEAP_LIMIT),
+            10 * P + DEFAULT_HEAP_LIMIT
+        );
+        assert_eq!(
+            vm.brk(10 * P + DEFAULT_HEAP_LIMIT + 1),
+            10 * P + DEFAULT_HEAP_LIMIT
+        );
+        assert_eq!(vm.brk(10 * P), 10 * P);
+    }
 
-        vm.insert(Segment {
-            start: 1234,
-            protection: 0b111.into(),
-            data: vec![1, 2, 3, 4, 5, 6],
-        })
-        .unwrap();
+    /// A device with a single 32 bit register, that counts the reads
+    #[derive(Debug, Default)]
+    struct Register {
+        value: u32,
+        reads: usize,
+    }
 
-        let mut buf = [0; 3];
+    impl Device for Register {
+        fn read(&mut self, offset: usize, size: usize) -> Option<u64> {
+            if offset != 0 || size != 4 {
+                return None;
+            }
 
-        vm.read_slice(1234 + 1, &mut buf).unwrap();
+            self.reads += 1;
+            Some(self.value.into())
+        }
 
-        assert_eq!(buf, [2, 3, 4]);
+        fn write(&mut self, offset: usize, size: usize, value: u64) -> Option<()> {
+            if offset != 0 || size != 4 {
+                return None;
+            }
+
+            self.value = value as u32;
+            Some(())
+        }
     }
 
     #[test]
-    fn read_slice_protection() {
+    fn device() {
         let mut vm = VirtualMemory::default();
+        let register = Rc::new(RefCell::new(Register::default()));
 
-        vm.insert(Segment {
-            start: 1234,
-            protection: 0.into(),
-            data: vec![1, 2, 3, 4, 5, 6],
-        })
-        .unwrap();
+        vm.insert(numbered(0, 1)).unwrap();
+        vm.insert_device(P + 8, 4, register.clone()).unwrap();
 
-        let mut buf = [0; 3];
+        vm.write_u32(P + 8, 0xdead_beef).unwrap();
+        assert_eq!(vm.read_u32(P + 8).unwrap(), 0xdead_beef);
+        assert_eq!(register.borrow().value, 0xdead_beef);
+        assert_eq!(register.borrow().reads, 1);
 
+        // the device decides which accesses are supported
         assert!(matches!(
-            vm.read_slice(1234 + 1, &mut buf),
-            Err(Error::Protection { .. })
+            vm.read_u8(P + 8),
+            Err(Error::DeviceAccess { size: 1, .. })
+        ));
+        assert!(matches!(
+            vm.read_u64(P + 8),
+            Err(Error::DeviceAccess { size: 8, .. })
         ));
-    }
 
-    #[test]
-    fn read_slice_unmapped() {
-        let vm = VirtualMemory::default();
+        // devices don't have pages
+        assert!(matches!(
+            vm.fetch_u32(P + 8),
+            Err(Error::InstructionAccessFault(..))
+        ));
+        assert_eq!(vm.segments().len(), 1);
 
-        let mut buf = [0; 3];
+        // the byte accessors go to the device, and it can't be copied from or to
+        assert!(matches!(
+            vm.write(P + 8, 0),
+            Err(Error::DeviceAccess { size: 1, .. })
+        ));
+        assert!(matches!(
+            vm.read_slice(P + 4, &mut [0; 8]),
+            Err(Error::DeviceAccess { addr, .. }) if addr == P + 8
+        ));
+        assert!(matches!(
+            vm.write_slice(P + 8, &[0; 4]),
+            Err(Error::DeviceAccess { .. })
+        ));
+        assert_eq!(register.borrow().value, 0xdead_beef);
 
         assert!(matches!(
-            vm.read_slice(1234, &mut buf),
-            Err(Error::UnmappedAddress(..))
+            vm.load(numbered(P, 1)),
+            Err(Error::InsertOverlap { overlapping, .. }) if overlapping == [P + 8]
         ));
     }
 
     #[test]
-    fn read_slice_oob() {
+    fn device_overlap() {
         let mut vm = VirtualMemory::default();
+        let register = || Rc::new(RefCell::new(Register::default()));
 
-        vm.insert(Segment {
-            start: 1234,
-            protection: 0b111.into(),
-            data: vec![1, 2, 3, 4, 5, 6],
-        })
-        .unwrap();
+        vm.insert(numbered(0, 1)).unwrap();
+        vm.insert_device(2 * P, 8, register()).unwrap();
 
-        let mut buf = [0; 7];
+        assert!(matches!(
+            vm.insert_device(P - 4, 8, register()),
+            Err(Error::InsertOverlap { new, overlapping }) if new == P - 4 && overlapping == [0]
+        ));
+        assert!(matches!(
+            vm.insert_device(2 * P + 4, 8, register()),
+            Err(Error::InsertOverlap { overlapping, .. }) if overlapping == [2 * P]
+        ));
 
+        // devices can share a page, but not with a segment
+        assert!(vm.insert_device(2 * P + 8, 8, register()).is_ok());
         assert!(matches!(
-            vm.read_slice(1234, &mut buf),
-            Err(Error::SliceOutOfBounds { .. }),
+            vm.insert(numbered(2 * P, 1)),
+            Err(Error::InsertOverlap { overlapping, .. }) if overlapping == [2 * P, 2 * P + 8]
+        ));
+
+        // or be mapped over
+        vm.set_map_base(3 * P);
+        assert_eq!(vm.map(0, P, 0b110.into(), MapFlags::default()).unwrap(), P);
+        assert!(matches!(
+            vm.map(2 * P, P, 0b110.into(), MapFlags { fixed: true }),
+            Err(Error::InsertOverlap { .. })
         ));
     }
 
     #[test]
-    fn write() {
+    fn snapshot() {
         let mut vm = VirtualMemory::default();
 
-        vm.insert(Segment {
-            start: 1234,
-            protection: 0b111.into(),
-            data: vec![0],
-        })
-        .unwrap();
-
-        vm.write(1234, 1).unwrap();
-        assert!(matches!(vm.read(1234), Ok(1)));
-    }
+        vm.insert(numbered(10 * P, 4)).unwrap();
+        vm.set_brk_start(20 * P);
 
-    #[test]
-    fn write_protection() {
-        let mut vm = VirtualMemory::default();
+        let snapshot = vm.snapshot();
 
-        vm.insert(Segment {
-            start: 1234,
-            protection: 0b100.into(),
-            data: vec![0],
-        })
-        .unwrap();
+        for _ in 0..3 {
+            vm.write(10 * P, 0).unwrap();
+            vm.write_slice(12 * P - 1, &[0, 0]).unwrap();
+            vm.unmap(13 * P, P).unwrap();
+            vm.brk(21 * P);
+            vm.write(20 * P, 1).unwrap();
 
-        assert!(matches!(vm.write(1234, 1), Err(Error::Protection { .. })));
-        assert!(matches!(vm.read(1234), Ok(0)));
-    }
+            assert_eq!(vm.dirty_pages(), 5);
 
-    #[test]
-    fn write_unmapped() {
-        let mut vm = VirtualMemory::default();
+            vm.restore(&snapshot);
 
-        assert!(matches!(vm.write(1234, 1), Err(Error::UnmappedAddress(..))));
+            assert_eq!(vm.dirty_pages(), 0);
+            assert_eq!(vm.read(10 * P).unwrap(), 1);
+            assert_eq!(vm.read(12 * P - 1).unwrap(), 2);
+            assert_eq!(vm.read(12 * P).unwrap(), 3);
+            assert_eq!(vm.read(13 * P).unwrap(), 4);
+            assert_eq!(vm.current_brk(), 20 * P);
+            assert!(matches!(vm.read(20 * P), Err(Error::UnmappedAddress(..))));
+        }
     }
 
     #[test]
-    fn write_slice() {
+    fn snapshot_other() {
         let mut vm = VirtualMemory::default();
 
-        vm.insert(Segment {
-            start: 1234,
-            protection: 0b111.into(),
-            data: vec![0; 6],
-        })
-        .unwrap();
+        vm.insert(numbered(0, 1)).unwrap();
+        let first = vm.snapshot();
 
-        let data = "asdasd".as_bytes();
+        vm.write(0, 5).unwrap();
+        let second = vm.snapshot();
 
-        vm.write_slice(1234, data).unwrap();
+        vm.write(0, 6).unwrap();
 
-        let mut buf = [0; 6];
-        vm.read_slice(1234, &mut buf).unwrap();
+        // the dirty pages are relative to the second snapshot
+        vm.restore(&first);
+        assert_eq!(vm.read(0).unwrap(), 1);
 
-        assert_eq!(buf, data);
+        vm.restore(&second);
+        assert_eq!(vm.read(0).unwrap(), 5);
     }
 
     #[test]
-    fn write_slice_protection() {
+    fn watchpoints() {
         let mut vm = VirtualMemory::default();
 
-        vm.insert(Segment {
-            start: 1234,
-            protection: 0b100.into(),
-            data: vec![0; 6],
-        })
-        .unwrap();
-
-        let data = "asdasd".as_bytes();
+        vm.insert(numbered(0, 2)).unwrap();
+        vm.add_watchpoint(P - 2, 4, WatchKind::Write);
+        vm.add_watchpoint(P, 1, WatchKind::Execute);
+
+        vm.read_u32(P - 4).unwrap();
+        vm.write_u16(P - 4, 0).unwrap();
+        assert_eq!(vm.take_watchpoint_hits(), []);
+
+        // the hit is the first watched byte of the access
+        vm.write_u32(P - 4, 0).unwrap();
+        assert_eq!(vm.take_watchpoint_hits(), [(P - 2, WatchKind::Write)]);
+        assert_eq!(vm.take_watchpoint_hits(), []);
+
+        vm.fetch_u32(P).unwrap();
+        vm.write(P + 1, 0).unwrap();
+        assert_eq!(
+            vm.take_watchpoint_hits(),
+            [(P, WatchKind::Execute), (P + 1, WatchKind::Write)]
+        );
+
+        // failed accesses don't hit
+        vm.protect(0, P, 0b100.into()).unwrap();
+        assert!(vm.write_slice(P - 2, &[0]).is_err());
+        assert_eq!(vm.take_watchpoint_hits(), []);
+
+        assert!(vm.remove_watchpoint(P - 2, 4, WatchKind::Write));
+        assert!(!vm.remove_watchpoint(P - 2, 4, WatchKind::Write));
+        vm.write(P + 1, 0).unwrap();
+        assert_eq!(vm.take_watchpoint_hits(), []);
+    }
 
-        assert!(matches!(
-            vm.write_slice(1234, data),
-            Err(Error::Protection { .. })
-        ));
+    #[test]
+    fn overlapping_watchpoints() {
+        let mut vm = VirtualMemory::default();
 
-        let mut buf = [0; 6];
-        vm.read_slice(1234, &mut buf).unwrap();
+        vm.insert(numbered(0, 1)).unwrap();
+        vm.add_watchpoint(8, 8, WatchKind::Read);
+        vm.add_watchpoint(12, 1, WatchKind::Read);
+        vm.add_watchpoint(8, 8, WatchKind::Write);
 
-        assert_eq!(buf, [0; 6]);
+        vm.read_u64(8).unwrap();
+        assert_eq!(
+            vm.take_watchpoint_hits(),
+            [(8, WatchKind::Read), (12, WatchKind::Read)]
+        );
     }
 
     #[test]
-    fn write_slice_unmapped() {
+    fn watch_emulated() {
         let mut vm = VirtualMemory::default();
 
-        let data = "asdasd".as_bytes();
-
-        assert!(matches!(
-            vm.write_slice(1234, data),
-            Err(Error::UnmappedAddress { .. })
-        ));
+        vm.insert(numbered(0, 1)).unwrap();
+        vm.set_misaligned_policy(MisalignedPolicy::Emulate);
+        vm.add_watchpoint(8, 8, WatchKind::Read);
+        vm.add_watchpoint(8, 8, WatchKind::Write);
+
+        // a single hit for the whole access, not one for each byte
+        vm.read_u32(6).unwrap();
+        vm.write_u64(10, 0).unwrap();
+        assert_eq!(
+            vm.take_watchpoint_hits(),
+            [(8, WatchKind::Read), (10, WatchKind::Write)]
+        );
+
+        // implicit accesses aren't watched
+        vm.read_u64_implicit(9).unwrap();
+        vm.write_u64_implicit(9, 0).unwrap();
+        assert_eq!(vm.take_watchpoint_hits(), []);
     }
 
     #[test]
-    fn write_slice_oob() {
+    fn fetch() {
         let mut vm = VirtualMemory::default();
 
         vm.insert(Segment {
-            start: 1234,
-            protection: 0b111.into(),
-            data: vec![0; 6],
+            start: 0,
+            protection: 0b001.into(),
+            data: vec![0x13, 0, 0, 0],
+        })
+        .unwrap();
+        vm.insert(Segment {
+            start: P,
+            protection: 0b110.into(),
+            data: vec![0; P],
         })
         .unwrap();
 
-        let data = &[1; 7];
+        // execute-only code can be fetched but not read
+        assert_eq!(vm.fetch_u32(0).unwrap(), 0x13);
+        assert!(matches!(vm.read_u32(0), Err(Error::Protection { .. })));
 
+        // data can be read but not fetched
+        assert!(vm.read_u32(P).is_ok());
         assert!(matches!(
-            vm.write_slice(1234, data),
-            Err(Error::SliceOutOfBounds { .. })
+            vm.fetch_u32(P),
+            Err(Error::InstructionAccessFault(addr)) if addr == P
+        ));
+        assert!(matches!(
+            vm.fetch_u32(P - 2),
+            Err(Error::InstructionAccessFault(addr)) if addr == P
+        ));
+        assert!(matches!(
+            vm.fetch_u32(2 * P),
+            Err(Error::InstructionAccessFault(addr)) if addr == 2 * P
         ));
-
-        let mut buf = [0; 6];
-        vm.read_slice(1234, &mut buf).unwrap();
-
-        assert_eq!(buf, [0; 6]);
     }
 
     #[test]
@@ -737,13 +2486,13 @@ mod tests {
         let mut vm = VirtualMemory::default();
 
         vm.insert(Segment {
-            start: 1234,
+            start: 1234 * P,
             protection: 0b111.into(),
-            data: vec![0; 6],
+            data: vec![0; 6 * P],
         })
         .unwrap();
 
-        let overlapping = vm.get_overlapping(0, 1234);
+        let overlapping = vm.get_overlapping(0, 1234 * P);
 
         assert_eq!(overlapping.len(), 0);
     }
@@ -753,16 +2502,16 @@ mod tests {
         let mut vm = VirtualMemory::default();
 
         vm.insert(Segment {
-            start: 1234,
+            start: 1234 * P,
             protection: 0b111.into(),
-            data: vec![0; 6],
+            data: vec![0; 6 * P],
         })
         .unwrap();
 
-        let overlapping = vm.get_overlapping(0, 1235);
+        let overlapping = vm.get_overlapping(0, 1235 * P);
         assert_eq!(overlapping.len(), 1);
 
-        let overlapping = vm.get_overlapping(1234 + 6 - 1, 10);
+        let overlapping = vm.get_overlapping((1234 + 6 - 1) * P, 10 * P);
         assert_eq!(overlapping.len(), 1);
     }
 
@@ -772,19 +2521,19 @@ mod tests {
 
         let segments = vec![
             Segment {
-                start: 10,
+                start: 10 * P,
                 protection: 0.into(),
-                data: vec![0; 3],
+                data: vec![0; P],
             },
             Segment {
-                start: 20,
+                start: 20 * P,
                 protection: 0.into(),
-                data: vec![0; 3],
+                data: vec![0; P],
             },
             Segment {
-                start: 30,
+                start: 30 * P,
                 protection: 0.into(),
-                data: vec![0; 3],
+                data: vec![0; P],
             },
         ]
         .into_iter();
@@ -793,38 +2542,44 @@ mod tests {
             vm.insert(segment).unwrap();
         }
 
-        let overlapping = vm.get_overlapping(12, 10);
-        assert_eq!(overlapping.len(), 2);
+        let overlapping = vm.get_overlapping(10 * P + 1, 11 * P);
+        assert_eq!(overlapping, [10 * P, 20 * P]);
 
-        let overlapping = vm.get_overlapping(0, 21);
-        assert_eq!(overlapping.len(), 2);
+        let overlapping = vm.get_overlapping(0, 21 * P);
+        assert_eq!(overlapping, [10 * P, 20 * P]);
 
-        let overlapping = vm.get_overlapping(22, 10);
-        assert_eq!(overlapping.len(), 2);
+        let overlapping = vm.get_overlapping(11 * P, 20 * P);
+        assert_eq!(overlapping, [20 * P, 30 * P]);
 
-        let overlapping = vm.get_overlapping(19, 100);
-        assert_eq!(overlapping.len(), 2);
+        let overlapping = vm.get_overlapping(19 * P, 100 * P);
+        assert_eq!(overlapping, [20 * P, 30 * P]);
 
-        let overlapping = vm.get_overlapping(20, 12);
-        assert_eq!(overlapping.len(), 2);
-
-        let overlapping = vm.get_overlapping(0, 100);
+        let overlapping = vm.get_overlapping(0, 100 * P);
         assert_eq!(overlapping.len(), 3);
 
-        let overlapping = vm.get_overlapping(10, 100);
+        let overlapping = vm.get_overlapping(10 * P, 100 * P);
         assert_eq!(overlapping.len(), 3);
 
-        let overlapping = vm.get_overlapping(12, 100);
+        let overlapping = vm.get_overlapping(10 * P + 1, 20 * P);
         assert_eq!(overlapping.len(), 3);
 
-        let overlapping = vm.get_overlapping(12, 32 - 12 + 1);
+        let overlapping = vm.get_overlapping(0, usize::MAX / 2);
         assert_eq!(overlapping.len(), 3);
     }
 
     #[test]
     fn unmap_none() {
         let mut vm = VirtualMemory::default();
-        assert!(vm.unmap(1234, 1234).is_ok());
+        assert!(vm.unmap(1234 * P, 1234).is_ok());
+    }
+
+    #[test]
+    fn unmap_not_aligned() {
+        let mut vm = VirtualMemory::default();
+        assert!(matches!(
+            vm.unmap(1234, 1234),
+            Err(Error::NotPageAligned(1234))
+        ));
     }
 
     #[test]
@@ -832,14 +2587,14 @@ mod tests {
         let mut vm = VirtualMemory::default();
 
         vm.insert(Segment {
-            start: 1234,
+            start: 1234 * P,
             protection: 0b111.into(),
-            data: vec![0; 6],
+            data: vec![0; 6 * P],
         })
         .unwrap();
 
-        assert!(vm.unmap(1234, 6).is_ok());
-        assert_eq!(vm.segments.len(), 0);
+        assert!(vm.unmap(1234 * P, 6 * P).is_ok());
+        assert_eq!(vm.pages.len(), 0);
     }
 
     #[test]
@@ -847,199 +2602,161 @@ mod tests {
         let mut vm = VirtualMemory::default();
 
         vm.insert(Segment {
-            start: 1234,
+            start: 1234 * P,
             protection: 0b111.into(),
-            data: vec![0; 6],
+            data: vec![0; 6 * P],
         })
         .unwrap();
 
-        assert!(vm.unmap(0, 10000).is_ok());
-        assert_eq!(vm.segments.len(), 0);
+        assert!(vm.unmap(0, 10000 * P).is_ok());
+        assert_eq!(vm.pages.len(), 0);
     }
 
     #[test]
-    fn unmap_head() {
+    fn fill() {
         let mut vm = VirtualMemory::default();
 
-        vm.insert(Segment {
-            start: 1234,
-            protection: 0b111.into(),
-            data: vec![1, 2, 3, 4, 5, 6],
+        vm.load(Segment {
+            protection: 0b101.into(),
+            ..numbered(11 * P, 1)
         })
         .unwrap();
+        vm.fill(10 * P, 3 * P, 0b111.into()).unwrap();
+
+        // the loaded data is kept, and the other pages are zero
+        assert_eq!(vm.read(11 * P).unwrap(), 1);
+        assert_eq!(vm.read(12 * P).unwrap(), 0);
+        vm.write(11 * P, 5).unwrap();
+        assert_eq!(vm.pages[&10].data, None);
+
+        assert!(matches!(
+            vm.fill(10 * P + 1, P, 0b111.into()),
+            Err(Error::NotPageAligned(..))
+        ));
+    }
+
+    /// Create a segment, every page is filled with its index starting from 1
+    fn numbered(start: usize, pages: u8) -> Segment {
+        Segment {
+            start,
+            protection: 0b111.into(),
+            data: (1..=pages).flat_map(|x| [x; P]).collect(),
+        }
+    }
+
+    #[test]
+    fn unmap_head() {
+        let mut vm = VirtualMemory::default();
+
+        vm.insert(numbered(1234 * P, 6)).unwrap();
 
-        assert!(vm.unmap(0, 1234 + 5).is_ok());
-        assert_eq!(vm.segments.len(), 1);
-        let (&key, segment) = vm.segments.first_key_value().unwrap();
-        assert_eq!(key, 1234 + 5);
-        assert_eq!(segment.start, 1234 + 5);
-        assert_eq!(segment.data, &[6]);
+        assert!(vm.unmap(0, (1234 + 5) * P).is_ok());
+
+        let segments = vm.segments();
+        assert_eq!(segments.len(), 1);
+        assert_eq!(segments[0].start, (1234 + 5) * P);
+        assert_eq!(segments[0].data, [6; P]);
     }
 
     #[test]
     fn unmap_tail() {
         let mut vm = VirtualMemory::default();
 
-        vm.insert(Segment {
-            start: 1234,
-            protection: 0b111.into(),
-            data: vec![1, 2, 3, 4, 5, 6],
-        })
-        .unwrap();
+        vm.insert(numbered(1234 * P, 6)).unwrap();
+
+        assert!(vm.unmap((1234 + 5) * P, 1000 * P).is_ok());
 
-        assert!(vm.unmap(1234 + 5, 1000).is_ok());
-        assert_eq!(vm.segments.len(), 1);
-        let (&key, segment) = vm.segments.first_key_value().unwrap();
-        assert_eq!(key, 1234);
-        assert_eq!(segment.start, 1234);
-        assert_eq!(segment.data, &[1, 2, 3, 4, 5]);
+        let segments = vm.segments();
+        assert_eq!(segments.len(), 1);
+        assert_eq!(segments[0].start, 1234 * P);
+        assert_eq!(segments[0].data, numbered(0, 5).data);
     }
 
     #[test]
     fn unmap_inside() {
         let mut vm = VirtualMemory::default();
 
-        vm.insert(Segment {
-            start: 1234,
-            protection: 0b111.into(),
-            data: vec![1, 2, 3, 4, 5, 6],
-        })
-        .unwrap();
+        vm.insert(numbered(1234 * P, 6)).unwrap();
 
-        assert!(vm.unmap(1234 + 2, 2).is_ok());
-        assert_eq!(vm.segments.len(), 2);
-        let mut it = vm.segments.iter();
+        assert!(vm.unmap((1234 + 2) * P, 2 * P).is_ok());
 
-        let (&key, segment) = it.next().unwrap();
-        assert_eq!(key, 1234);
-        assert_eq!(segment.start, 1234);
-        assert_eq!(segment.data, &[1, 2]);
+        let segments = vm.segments();
+        assert_eq!(segments.len(), 2);
 
-        let (&key, segment) = it.next().unwrap();
-        assert_eq!(key, 1234 + 4);
-        assert_eq!(segment.start, 1234 + 4);
-        assert_eq!(segment.data, &[5, 6]);
+        assert_eq!(segments[0].start, 1234 * P);
+        assert_eq!(segments[0].data, numbered(0, 2).data);
+
+        assert_eq!(segments[1].start, (1234 + 4) * P);
+        assert_eq!(segments[1].data[0], 5);
+        assert_eq!(segments[1].data[P], 6);
     }
 
     #[test]
-    fn unmap_multiple_exact() {
+    fn unmap_partial_page() {
         let mut vm = VirtualMemory::default();
 
-        let segments = vec![
-            Segment {
-                start: 10,
-                protection: 0.into(),
-                data: vec![0; 3],
-            },
-            Segment {
-                start: 20,
-                protection: 0.into(),
-                data: vec![0; 3],
-            },
-            Segment {
-                start: 30,
-                protection: 0.into(),
-                data: vec![0; 3],
-            },
-        ]
-        .into_iter();
+        vm.insert(numbered(0, 2)).unwrap();
 
-        for segment in segments {
-            vm.insert(segment).unwrap();
+        // the length is rounded up to whole pages
+        assert!(vm.unmap(0, 1).is_ok());
+
+        let segments = vm.segments();
+        assert_eq!(segments.len(), 1);
+        assert_eq!(segments[0].start, P);
+    }
+
+    #[test]
+    fn unmap_multiple_exact() {
+        let mut vm = VirtualMemory::default();
+
+        for start in [10, 20, 30] {
+            vm.insert(numbered(start * P, 3)).unwrap();
         }
 
-        assert!(vm.unmap(10, 23).is_ok());
-        assert_eq!(vm.segments.len(), 0);
+        assert!(vm.unmap(10 * P, 23 * P).is_ok());
+        assert_eq!(vm.pages.len(), 0);
     }
 
     #[test]
     fn unmap_multiple_head() {
         let mut vm = VirtualMemory::default();
 
-        let segments = vec![
-            Segment {
-                start: 10,
-                protection: 0.into(),
-                data: vec![1, 2, 3],
-            },
-            Segment {
-                start: 20,
-                protection: 0.into(),
-                data: vec![4, 5, 6],
-            },
-            Segment {
-                start: 30,
-                protection: 0.into(),
-                data: vec![7, 8, 9],
-            },
-        ]
-        .into_iter();
-
-        for segment in segments {
-            vm.insert(segment).unwrap();
+        for start in [10, 20, 30] {
+            vm.insert(numbered(start * P, 3)).unwrap();
         }
 
-        assert!(vm.unmap(0, 22).is_ok());
-        assert_eq!(vm.segments.len(), 2);
+        assert!(vm.unmap(0, 22 * P).is_ok());
 
-        let mut it = vm.segments.iter();
+        let segments = vm.segments();
+        assert_eq!(segments.len(), 2);
 
-        let (&key, segment) = it.next().unwrap();
-        assert_eq!(key, 22);
-        assert_eq!(segment.start, 22);
-        assert_eq!(segment.data, &[6]);
+        assert_eq!(segments[0].start, 22 * P);
+        assert_eq!(segments[0].data, [3; P]);
 
-        let (&key, segment) = it.next().unwrap();
-        assert_eq!(key, 30);
-        assert_eq!(segment.start, 30);
-        assert_eq!(segment.data, &[7, 8, 9]);
+        assert_eq!(segments[1].start, 30 * P);
+        assert_eq!(segments[1].data, numbered(0, 3).data);
     }
 
     #[test]
     fn unmap_multiple_tail() {
         let mut vm = VirtualMemory::default();
 
-        let segments = vec![
-            Segment {
-                start: 10,
-                protection: 0.into(),
-                data: vec![1, 2, 3],
-            },
-            Segment {
-                start: 20,
-                protection: 0.into(),
-                data: vec![4, 5, 6],
-            },
-            Segment {
-                start: 30,
-                protection: 0.into(),
-                data: vec![7, 8, 9],
-            },
-        ]
-        .into_iter();
-
-        for segment in segments {
-            vm.insert(segment).unwrap();
+        for start in [10, 20, 30] {
+            vm.insert(numbered(start * P, 3)).unwrap();
         }
 
-        assert!(vm.unmap(0, 12).is_ok());
-        assert_eq!(vm.segments.len(), 3);
+        assert!(vm.unmap(0, 12 * P).is_ok());
 
-        let mut it = vm.segments.iter();
+        let segments = vm.segments();
+        assert_eq!(segments.len(), 3);
 
-        let (&key, segment) = it.next().unwrap();
-        assert_eq!(key, 12);
-        assert_eq!(segment.start, 12);
-        assert_eq!(segment.data, &[3]);
+        assert_eq!(segments[0].start, 12 * P);
+        assert_eq!(segments[0].data, [3; P]);
 
-        let (&key, segment) = it.next().unwrap();
-        assert_eq!(key, 20);
-        assert_eq!(segment.start, 20);
-        assert_eq!(segment.data, &[4, 5, 6]);
+        assert_eq!(segments[1].start, 20 * P);
+        assert_eq!(segments[1].data, numbered(0, 3).data);
 
-        let (&key, segment) = it.next().unwrap();
-        assert_eq!(key, 30);
-        assert_eq!(segment.start, 30);
-        assert_eq!(segment.data, &[7, 8, 9]);
+        assert_eq!(segments[2].start, 30 * P);
+        assert_eq!(segments[2].data, numbered(0, 3).data);
     }
 }